@@ -0,0 +1,112 @@
+use std::{
+    io,
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// how long a connection waits for the tunnel to come up behind the listener
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(30);
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+// the listening socket systemd hands to the client started by a .socket unit, so the tunnel is only
+// dialed once a first local connection arrives. connections are passed on to the local port of the
+// tunnel, which listens behind it
+pub struct Activation {
+    listener: TcpListener,
+    first: TcpStream,
+    state: Arc<Mutex<(usize, Instant)>>, // connections open, and when the last one closed
+}
+
+impl Activation {
+    // blocks until the first connection
+    pub fn wait() -> Result<Self, String> {
+        let listener = inherited_listener()?;
+        let (first, _) = listener
+            .accept()
+            .map_err(|err| format!("failed to accept on the systemd socket: {}", err))?;
+        Ok(Activation {
+            listener,
+            first,
+            state: Arc::new(Mutex::new((0, Instant::now()))),
+        })
+    }
+
+    // passes the first connection and the next ones on to upstream, on their own threads
+    pub fn start(&self, upstream: u16) -> io::Result<()> {
+        let listener = self.listener.try_clone()?;
+        let first = self.first.try_clone()?;
+        let state = self.state.clone();
+        thread::spawn(move || {
+            let connections = Some(Ok(first)).into_iter().chain(listener.incoming());
+            for stream in connections.flatten() {
+                let state = state.clone();
+                state.lock().unwrap().0 += 1;
+                thread::spawn(move || {
+                    let _ = forward(stream, upstream);
+                    let mut state = state.lock().unwrap();
+                    *state = (state.0 - 1, Instant::now());
+                });
+            }
+        });
+        Ok(())
+    }
+
+    // for how long no connection has been open, none while one is
+    pub fn idle_for(&self) -> Option<Duration> {
+        let (open, since) = *self.state.lock().unwrap();
+        (open == 0).then(|| since.elapsed())
+    }
+}
+
+fn forward(client: TcpStream, upstream: u16) -> io::Result<()> {
+    let started = Instant::now();
+    let upstream = loop {
+        match TcpStream::connect(("127.0.0.1", upstream)) {
+            Ok(upstream) => break upstream,
+            Err(_) if started.elapsed() < TUNNEL_TIMEOUT => thread::sleep(RETRY_INTERVAL),
+            Err(err) => return Err(err),
+        }
+    };
+    let (client_reader, upstream_reader) = (client.try_clone()?, upstream.try_clone()?);
+    let sent = thread::spawn(move || copy(client_reader, upstream));
+    copy(upstream_reader, client);
+    let _ = sent.join();
+    Ok(())
+}
+
+// copies until the source closes, then closes the destination's side
+fn copy(mut from: TcpStream, mut to: TcpStream) {
+    let _ = io::copy(&mut from, &mut to);
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+// the first socket systemd passed (fd 3), when it was passed to this process
+#[cfg(unix)]
+fn inherited_listener() -> Result<TcpListener, String> {
+    use std::{env, os::unix::io::FromRawFd};
+    const LISTEN_FDS_START: i32 = 3;
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    let fds = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<u32>().ok());
+    if pid != Some(std::process::id()) || fds.unwrap_or(0) == 0 {
+        return Err(
+            "no socket was passed by systemd, start the client from a .socket unit".to_string(),
+        );
+    }
+    // not passed on to ssh and the other programs the client runs
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    unsafe {
+        libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC);
+        Ok(TcpListener::from_raw_fd(LISTEN_FDS_START))
+    }
+}
+
+#[cfg(not(unix))]
+fn inherited_listener() -> Result<TcpListener, String> {
+    Err("socket activation needs systemd".to_string())
+}
@@ -0,0 +1,117 @@
+use crate::{http, stats::TunnelStats};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+
+pub const TOKEN_ENV: &str = "KENSA_ADMIN_TOKEN";
+
+// a client connected to the server, as GET /admin/clients lists it
+#[derive(Deserialize, Debug)]
+pub struct AdminClient {
+    pub uuid: String,
+    pub client_type: String,
+    pub label: Option<String>,
+    pub address: Option<String>,
+    pub account: Option<String>,
+    pub tunnels: u32,
+}
+
+// an open tunnel, as GET /admin/tunnels lists it
+#[derive(Deserialize, Debug)]
+pub struct AdminTunnel {
+    pub id: String,
+    pub sender: String,
+    pub label: Option<String>,
+    pub receiver: String,
+    pub port: u16,
+    pub transport: String,
+    pub opened_at: String,
+    pub traffic: u64, // through the server, 0 for direct tunnels
+    pub stats: AdminTunnelStats,
+}
+
+// the last stats each side of the tunnel reported
+#[derive(Deserialize, Debug)]
+pub struct AdminTunnelStats {
+    pub sender: Option<TunnelStats>,
+    pub receiver: Option<TunnelStats>,
+}
+
+// what POST /admin/cleanup removed
+#[derive(Deserialize, Debug)]
+pub struct CleanupReport {
+    pub authorized_keys: Vec<String>, // file names
+    pub users: Vec<String>,
+    pub sshd: Vec<u32>, // pids
+    pub embedded_tunnels: Vec<String>,
+}
+
+// the http admin api of a server, served next to its websocket and enabled by its ADMIN_TOKEN
+pub struct Admin {
+    server_url: String,
+    token: String,
+}
+
+impl Admin {
+    pub fn new(server_url: &str, token: String) -> Result<Self, String> {
+        Url::parse(server_url).map_err(|err| err.to_string())?;
+        Ok(Admin {
+            server_url: server_url.to_string(),
+            token,
+        })
+    }
+
+    pub fn clients(&self) -> Result<Vec<AdminClient>, String> {
+        let clients = self.call("GET", "/admin/clients")?;
+        serde_json::from_value(clients).map_err(|err| format!("invalid clients: {}", err))
+    }
+
+    pub fn tunnels(&self) -> Result<Vec<AdminTunnel>, String> {
+        let tunnels = self.call("GET", "/admin/tunnels")?;
+        serde_json::from_value(tunnels).map_err(|err| format!("invalid tunnels: {}", err))
+    }
+
+    pub fn bans(&self) -> Result<Vec<String>, String> {
+        let bans = self.call("GET", "/admin/bans")?;
+        serde_json::from_value(bans).map_err(|err| format!("invalid bans: {}", err))
+    }
+
+    // disconnects the client, it can connect again right away unless it is banned
+    pub fn kick(&self, uuid: &str) -> Result<(), String> {
+        self.call("POST", &format!("/admin/clients/{}/kick", encode(uuid)))
+            .map(|_| ())
+    }
+
+    // the target is a client uuid or an ip address
+    pub fn set_banned(&self, target: &str, banned: bool) -> Result<(), String> {
+        let method = if banned { "PUT" } else { "DELETE" };
+        self.call(method, &format!("/admin/bans/{}", encode(target)))
+            .map(|_| ())
+    }
+
+    // removes what crashed tunnels left on the server, it also does it on its own periodically
+    pub fn cleanup(&self) -> Result<CleanupReport, String> {
+        let report = self.call("POST", "/admin/cleanup")?;
+        serde_json::from_value(report).map_err(|err| format!("invalid cleanup report: {}", err))
+    }
+
+    fn call(&self, method: &str, path: &str) -> Result<Value, String> {
+        let url = http::server_endpoint(&self.server_url, path)?;
+        let (status, body) = http::authorized(method, url.as_str(), &self.token)?;
+        match (status, body["error"].as_str()) {
+            (200, _) => Ok(body),
+            (401, _) => Err("the server refused the admin token".to_string()),
+            // what the server answers to every path when it has no token
+            (404, Some("not found")) => Err(
+                "the server has no admin api, it is enabled by setting its ADMIN_TOKEN".to_string(),
+            ),
+            (_, Some(error)) => Err(error.to_string()),
+            (status, None) => Err(format!("the server answered {}", status)),
+        }
+    }
+}
+
+fn encode(segment: &str) -> String {
+    utf8_percent_encode(segment, NON_ALPHANUMERIC).to_string()
+}
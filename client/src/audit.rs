@@ -0,0 +1,42 @@
+use crate::history::{format_timestamp, now};
+use std::{fs, io::Write, path::PathBuf};
+
+// every connection request a host got and what became of it, one line each in audit.log:
+//
+//   2024-05-17 14:03 UTC  accepted  port 8080  manual  4b0c... (alice)
+//
+// appended in clear even when the other files are encrypted, so it can be read with any tool
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        AuditLog { path }
+    }
+
+    // decision is how it was decided: manual, policy, script, accept window, auto-accept or invite.
+    // failing to write the log never stops the host
+    pub fn record(&self, receiver: &str, port: u16, decision: &str, accepted: bool) {
+        let line = format!(
+            "{}  {:<8}  port {:<5}  {:<13}  {}\n",
+            format_timestamp(now()),
+            if accepted { "accepted" } else { "denied" },
+            port,
+            decision,
+            receiver
+        );
+        let written = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(err) = written {
+            eprintln!(
+                "failed to write the audit log {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use crate::format_bytes;
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+// the name bench --server gives its port, bench looks it up on the host
+pub const PORT_NAME: &str = "bench";
+const CHUNK_SIZE: usize = 64 * 1024;
+const PINGS: u32 = 50;
+const PING_INTERVAL: Duration = Duration::from_millis(20);
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// what a connection to the bench server is for, its first byte
+const UPLOAD: u8 = b'U'; // the server counts what it reads until the client closes, then sends the count
+const DOWNLOAD: u8 = b'D'; // the server writes for the milliseconds given as a u64, then closes
+const PING: u8 = b'P'; // the server echoes 8 byte messages
+
+// the server of bench --server, measured against by the bench command through the tunnel
+pub struct BenchServer;
+
+impl BenchServer {
+    // listens on a free port of localhost on its own threads, returns the port
+    pub fn start() -> io::Result<u16> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        let port = listener.local_addr()?.port();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                thread::spawn(move || {
+                    let _ = serve(stream);
+                });
+            }
+        });
+        Ok(port)
+    }
+}
+
+fn serve(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut mode = [0];
+    stream.read_exact(&mut mode)?;
+    match mode[0] {
+        UPLOAD => {
+            let received = io::copy(&mut stream, &mut io::sink())?;
+            stream.write_all(&received.to_be_bytes())
+        }
+        DOWNLOAD => {
+            let mut millis = [0; 8];
+            stream.read_exact(&mut millis)?;
+            let duration = Duration::from_millis(u64::from_be_bytes(millis));
+            let started = Instant::now();
+            let chunk = vec![0; CHUNK_SIZE];
+            while started.elapsed() < duration {
+                stream.write_all(&chunk)?;
+            }
+            stream.shutdown(Shutdown::Write)
+        }
+        PING => {
+            let mut message = [0; 8];
+            loop {
+                match stream.read_exact(&mut message) {
+                    Ok(()) => stream.write_all(&message)?,
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        _ => Err(io::Error::new(ErrorKind::InvalidData, "unknown bench mode")),
+    }
+}
+
+// measures the latency, then the throughput each way for the duration, through the tunnel
+// listening on the local port, waiting for it to come up
+pub fn run(port: u16, duration: Duration) -> Result<(), String> {
+    let connect = |mode: u8| -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+        stream.set_nodelay(true)?;
+        stream.write_all(&[mode])?;
+        Ok(stream)
+    };
+    let failed = |err: io::Error| format!("the benchmark failed: {}", err);
+    let ping = loop {
+        match connect(PING) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(RETRY_INTERVAL),
+        }
+    };
+    latency(ping).map_err(failed)?;
+    upload(connect(UPLOAD).map_err(failed)?, duration).map_err(failed)?;
+    download(connect(DOWNLOAD).map_err(failed)?, duration).map_err(failed)?;
+    Ok(())
+}
+
+fn latency(mut stream: TcpStream) -> io::Result<()> {
+    let mut rtts = Vec::new();
+    let mut echo = [0; 8];
+    for n in 0..PINGS {
+        let sent = Instant::now();
+        stream.write_all(&u64::from(n).to_be_bytes())?;
+        stream.read_exact(&mut echo)?;
+        rtts.push(sent.elapsed().as_secs_f64() * 1000.0);
+        thread::sleep(PING_INTERVAL);
+    }
+    let min = rtts.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = rtts.iter().cloned().fold(0.0, f64::max);
+    let average = rtts.iter().sum::<f64>() / rtts.len() as f64;
+    // how much the round trip time moves from one ping to the next
+    let jitter =
+        rtts.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f64>() / (rtts.len() - 1) as f64;
+    println!(
+        "latency: {:.2} ms average ({:.2} min, {:.2} max), jitter {:.2} ms over {} pings",
+        average, min, max, jitter, PINGS
+    );
+    Ok(())
+}
+
+fn upload(mut stream: TcpStream, duration: Duration) -> io::Result<()> {
+    let chunk = vec![0; CHUNK_SIZE];
+    let started = Instant::now();
+    let mut report = Report::new("upload");
+    while started.elapsed() < duration {
+        stream.write_all(&chunk)?;
+        report.add(chunk.len());
+    }
+    stream.shutdown(Shutdown::Write)?;
+    // what went through is what the server read, not what is still buffered on the way
+    let mut received = [0; 8];
+    stream.read_exact(&mut received)?;
+    report.finish(u64::from_be_bytes(received), started.elapsed());
+    Ok(())
+}
+
+fn download(mut stream: TcpStream, duration: Duration) -> io::Result<()> {
+    stream.write_all(&(duration.as_millis() as u64).to_be_bytes())?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let started = Instant::now();
+    let mut report = Report::new("download");
+    let mut received = 0;
+    loop {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        received += n as u64;
+        report.add(n);
+    }
+    report.finish(received, started.elapsed());
+    Ok(())
+}
+
+// the throughput of a direction, printed every second like iperf does
+struct Report {
+    direction: &'static str,
+    since: Instant,
+    bytes: u64, // since the last line
+    elapsed: Duration,
+}
+
+impl Report {
+    fn new(direction: &'static str) -> Self {
+        Report {
+            direction,
+            since: Instant::now(),
+            bytes: 0,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    fn add(&mut self, n: usize) {
+        self.bytes += n as u64;
+        let interval = self.since.elapsed();
+        if interval < REPORT_INTERVAL {
+            return;
+        }
+        println!(
+            "{:>8} {:>3}-{}s  {}",
+            self.direction,
+            self.elapsed.as_secs(),
+            (self.elapsed + interval).as_secs(),
+            rate(self.bytes, interval)
+        );
+        self.elapsed += interval;
+        self.since = Instant::now();
+        self.bytes = 0;
+    }
+
+    fn finish(&self, total: u64, elapsed: Duration) {
+        println!(
+            "{}: {} in {:.1}s, {}",
+            self.direction,
+            format_bytes(total),
+            elapsed.as_secs_f64(),
+            rate(total, elapsed)
+        );
+    }
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> String {
+    let per_second = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    format!(
+        "{:.1} Mbit/s ({}/s)",
+        per_second * 8.0 / 1_000_000.0,
+        format_bytes(per_second as u64)
+    )
+}
@@ -0,0 +1,172 @@
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// raw ip packets, without a link layer
+const LINKTYPE_RAW: u32 = 101;
+// the most data put in one packet, so its ip length fits
+const MAX_SEGMENT: usize = 60_000;
+const SYN: u8 = 0x02;
+const FIN: u8 = 0x01;
+const PSH_ACK: u8 = 0x18;
+const ACK: u8 = 0x10;
+
+// the proxy of connect --capture, in front of the tunnel: what goes through it is written to a pcap
+// file as tcp packets between the local client and the host's port, readable with wireshark.
+// the packets are made up from the data, the tunnel itself is not captured
+pub struct Capture {
+    file: Mutex<File>,
+}
+
+impl Capture {
+    pub fn create(path: &Path) -> io::Result<Arc<Self>> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes()); // magic, microseconds
+        header.extend_from_slice(&2u16.to_le_bytes()); // version 2.4
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes()); // utc
+        header.extend_from_slice(&0u32.to_le_bytes()); // timestamp accuracy
+        header.extend_from_slice(&65_535u32.to_le_bytes()); // snapshot length
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Arc::new(Capture {
+            file: Mutex::new(file),
+        }))
+    }
+
+    // listens on the port on its own threads and passes the connections on to upstream,
+    // port is the port of the host the packets are addressed to
+    pub fn start(self: Arc<Self>, listen: u16, upstream: u16, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], listen)))?;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let capture = self.clone();
+                thread::spawn(move || {
+                    let _ = capture.forward(stream, upstream, port);
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn forward(self: Arc<Self>, client: TcpStream, upstream: u16, port: u16) -> io::Result<()> {
+        let SocketAddr::V4(client_address) = client.peer_addr()? else {
+            return Ok(());
+        };
+        let upstream = TcpStream::connect(("127.0.0.1", upstream))?;
+        let flow = Arc::new(Mutex::new(Flow {
+            capture: self,
+            client: client_address,
+            server: SocketAddrV4::new([127, 0, 0, 1].into(), port),
+            next_seq: [1, 1],
+        }));
+        {
+            let mut flow = flow.lock().unwrap();
+            flow.packet(true, SYN, &[]);
+            flow.packet(false, SYN | ACK, &[]);
+            flow.packet(true, ACK, &[]);
+        }
+        let (client_reader, upstream_reader) = (client.try_clone()?, upstream.try_clone()?);
+        let sent = {
+            let flow = flow.clone();
+            thread::spawn(move || copy(client_reader, upstream, &flow, true))
+        };
+        copy(upstream_reader, client, &flow, false);
+        let _ = sent.join();
+        Ok(())
+    }
+}
+
+// one connection through the capture, the client and server sides in this order
+struct Flow {
+    capture: Arc<Capture>,
+    client: SocketAddrV4,
+    server: SocketAddrV4,
+    next_seq: [u32; 2],
+}
+
+impl Flow {
+    fn packet(&mut self, from_client: bool, flags: u8, payload: &[u8]) {
+        let (side, other) = if from_client { (0, 1) } else { (1, 0) };
+        let (source, destination) = if from_client {
+            (self.client, self.server)
+        } else {
+            (self.server, self.client)
+        };
+        let seq = self.next_seq[side];
+        // syn and fin count as one byte
+        let used = payload.len() as u32 + u32::from(flags & (SYN | FIN) != 0);
+        self.next_seq[side] = seq.wrapping_add(used);
+        let ack = if flags & ACK != 0 {
+            self.next_seq[other]
+        } else {
+            0
+        };
+
+        let total_length = (20 + 20 + payload.len()) as u16;
+        let mut ip = Vec::with_capacity(total_length as usize);
+        ip.extend_from_slice(&[0x45, 0]);
+        ip.extend_from_slice(&total_length.to_be_bytes());
+        ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]); // don't fragment, ttl 64, tcp
+        ip.extend_from_slice(&source.ip().octets());
+        ip.extend_from_slice(&destination.ip().octets());
+        let checksum = ip_checksum(&ip);
+        ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        ip.extend_from_slice(&source.port().to_be_bytes());
+        ip.extend_from_slice(&destination.port().to_be_bytes());
+        ip.extend_from_slice(&seq.to_be_bytes());
+        ip.extend_from_slice(&ack.to_be_bytes());
+        ip.extend_from_slice(&[0x50, flags]); // 20 bytes header
+        ip.extend_from_slice(&65_535u16.to_be_bytes()); // window
+        ip.extend_from_slice(&[0, 0, 0, 0]); // checksum left out, urgent pointer
+        ip.extend_from_slice(payload);
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + ip.len());
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(ip.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(ip.len() as u32).to_le_bytes());
+        record.extend_from_slice(&ip);
+        // a failed write loses the packet, not the connection
+        let _ = self.capture.file.lock().unwrap().write_all(&record);
+    }
+}
+
+// copies until the source closes, then closes the destination's side
+fn copy(mut from: TcpStream, mut to: TcpStream, flow: &Mutex<Flow>, from_client: bool) {
+    let mut buffer = [0; MAX_SEGMENT];
+    loop {
+        let n = match from.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        if to.write_all(&buffer[..n]).is_err() {
+            break;
+        }
+        flow.lock()
+            .unwrap()
+            .packet(from_client, PSH_ACK, &buffer[..n]);
+    }
+    flow.lock().unwrap().packet(from_client, FIN | ACK, &[]);
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+fn ip_checksum(header: &[u8]) -> u16 {
+    let sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    let folded = (sum & 0xffff) + (sum >> 16);
+    !(((folded & 0xffff) + (folded >> 16)) as u16)
+}
@@ -0,0 +1,135 @@
+#[cfg(feature = "interactive")]
+use dialoguer::theme::{ColorfulTheme, Theme};
+#[cfg(unix)]
+use std::{fs::File, os::fd::AsFd};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// how often the reader looks whether something was typed, or whether the terminal is back
+const STDIN_POLL: Duration = Duration::from_millis(200);
+
+// reads what the user types on its own thread so the main loop keeps polling the server,
+// prompts go through it too since only one reader can own stdin
+pub struct Console {
+    lines: Receiver<String>,
+    // until when ssh reads the terminal, see hand_over
+    handed_over: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        let (sender, lines) = mpsc::channel();
+        let handed_over = Arc::new(Mutex::new(None));
+        let reader_handed_over = Arc::clone(&handed_over);
+        thread::spawn(move || {
+            let Some(mut stdin) = stdin_reader() else {
+                return;
+            };
+            loop {
+                let until = *reader_handed_over.lock().unwrap();
+                if until.is_some_and(|until| Instant::now() < until) {
+                    thread::sleep(STDIN_POLL);
+                    continue;
+                }
+                // only reads what was typed, a blocked read would take the next line from ssh
+                if stdin.buffer().is_empty() && !stdin_ready() {
+                    continue;
+                }
+                let mut line = String::new();
+                match stdin.read_line(&mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                let line = line.strip_suffix('\n').unwrap_or(&line);
+                let line = line.strip_suffix('\r').unwrap_or(line);
+                if sender.send(line.to_string()).is_err() {
+                    break;
+                }
+            }
+        });
+        Console { lines, handed_over }
+    }
+
+    // leaves the terminal to ssh for a while, to ask for the pin of a security key.
+    // given back sooner with take_back once ssh logged in
+    pub fn hand_over(&self, duration: Duration) {
+        *self.handed_over.lock().unwrap() = Some(Instant::now() + duration);
+    }
+
+    pub fn take_back(&self) {
+        *self.handed_over.lock().unwrap() = None;
+    }
+
+    // the next command typed, if any
+    pub fn poll(&self) -> Option<String> {
+        self.lines.try_recv().ok()
+    }
+
+    // blocks until the question is answered, the default is taken once stdin is closed
+    pub fn confirm(&self, prompt: &str, default: bool) -> bool {
+        #[cfg(feature = "interactive")]
+        let text = {
+            let mut text = String::new();
+            let _ =
+                ColorfulTheme::default().format_confirm_prompt(&mut text, prompt, Some(default));
+            text
+        };
+        #[cfg(not(feature = "interactive"))]
+        let text = format!("{} [{}]", prompt, if default { "Y/n" } else { "y/N" });
+        // a question needs the terminal, even while ssh logs in
+        self.take_back();
+        // lines typed before the question showed up are not answers to it
+        while self.lines.try_recv().is_ok() {}
+        loop {
+            print!("{} ", text);
+            let _ = io::stdout().flush();
+            let Ok(line) = self.lines.recv() else {
+                println!();
+                return default;
+            };
+            match line.trim().to_lowercase().as_str() {
+                "" => return default,
+                "y" | "yes" => return true,
+                "n" | "no" => return false,
+                _ => {}
+            }
+        }
+    }
+}
+
+// stdin with a buffer of its own, what std's one holds is not seen by stdin_ready
+#[cfg(unix)]
+fn stdin_reader() -> Option<BufReader<File>> {
+    let fd = io::stdin().as_fd().try_clone_to_owned().ok()?;
+    Some(BufReader::new(File::from(fd)))
+}
+
+#[cfg(not(unix))]
+fn stdin_reader() -> Option<BufReader<io::Stdin>> {
+    Some(BufReader::new(io::stdin()))
+}
+
+// whether a line can be read without blocking, waiting STDIN_POLL at most
+#[cfg(unix)]
+fn stdin_ready() -> bool {
+    let mut stdin = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // on errors the read tells what is wrong with stdin
+    unsafe { libc::poll(&mut stdin, 1, STDIN_POLL.as_millis() as libc::c_int) != 0 }
+}
+
+// elsewhere the read blocks, and ssh has to share the terminal
+#[cfg(not(unix))]
+fn stdin_ready() -> bool {
+    true
+}
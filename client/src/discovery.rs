@@ -0,0 +1,214 @@
+use crate::mdns::{header, read_name, read_u16, write_name};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::OnceLock,
+    time::Duration,
+};
+
+// --server-url auto:<domain> finds the server in the records of _kensa._tcp.<domain>:
+//   SRV <priority> <weight> <port> <host>   the websocket, picked like any SRV record
+//   TXT "tls=0"                             ws:// instead of wss://
+//   TXT "path=/portfwd"                     the path of the server behind a proxy
+//   TXT "ssh=<host>[:<port>]"               where the tunnels' ssh goes, the websocket's host by default.
+//                                           the port replaces the one the server announces, for
+//                                           servers with a single sshd behind a port mapping
+const SERVICE: &str = "_kensa._tcp";
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const TIMEOUT: Duration = Duration::from_secs(3);
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+// the ssh endpoint of the records, set once when the server url is parsed
+static SSH_ENDPOINT: OnceLock<(String, Option<u16>)> = OnceLock::new();
+
+pub fn ssh_host() -> Option<&'static str> {
+    SSH_ENDPOINT.get().map(|(host, _)| host.as_str())
+}
+
+pub fn ssh_port(announced: u16) -> u16 {
+    SSH_ENDPOINT
+        .get()
+        .and_then(|(_, port)| *port)
+        .unwrap_or(announced)
+}
+
+// the websocket url of the domain's server
+pub fn discover(domain: &str) -> Result<String, String> {
+    let name = format!("{}.{}", SERVICE, domain.trim_end_matches('.'));
+    let nameserver = nameserver()?;
+    let packet = query(nameserver, &name, TYPE_SRV)?;
+    let mut servers = Vec::new();
+    for (offset, _) in answers(&packet, TYPE_SRV) {
+        let (Some(priority), Some(weight), Some(port), Some((host, _))) = (
+            read_u16(&packet, offset),
+            read_u16(&packet, offset + 2),
+            read_u16(&packet, offset + 4),
+            read_name(&packet, offset + 6),
+        ) else {
+            continue;
+        };
+        // a host of "." says the service is not offered
+        if !host.is_empty() {
+            servers.push((priority, weight, port, host));
+        }
+    }
+    let Some((port, host)) = pick(servers) else {
+        return Err(format!("no SRV record for {}", name));
+    };
+
+    // the txt records are optional
+    let mut tls = true;
+    let mut path = String::new();
+    let packet = query(nameserver, &name, TYPE_TXT).unwrap_or_default();
+    for (offset, len) in answers(&packet, TYPE_TXT) {
+        for entry in txt_strings(&packet[offset..offset + len]) {
+            match entry.split_once('=') {
+                Some(("tls", value)) => tls = value != "0",
+                Some(("path", value)) => path = format!("/{}", value.trim_matches('/')),
+                Some(("ssh", value)) => {
+                    let endpoint = match value.rsplit_once(':') {
+                        Some((host, port)) if port.parse::<u16>().is_ok() => {
+                            (host.to_string(), port.parse().ok())
+                        }
+                        _ => (value.to_string(), None),
+                    };
+                    let _ = SSH_ENDPOINT.set(endpoint);
+                }
+                _ => {}
+            }
+        }
+    }
+    let url = format!(
+        "{}://{}:{}{}",
+        if tls { "wss" } else { "ws" },
+        host,
+        port,
+        path
+    );
+    eprintln!("found the server of {} at {}", domain, url);
+    Ok(url)
+}
+
+// the first nameserver of the system
+fn nameserver() -> Result<SocketAddr, String> {
+    let config = fs::read_to_string(RESOLV_CONF)
+        .map_err(|err| format!("failed to read {}: {}", RESOLV_CONF, err))?;
+    config
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .find_map(|address| address.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or(format!("no nameserver in {}", RESOLV_CONF))
+}
+
+fn query(nameserver: SocketAddr, name: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    let failed = |err: &dyn std::fmt::Display| format!("the lookup of {} failed: {}", name, err);
+    let local: IpAddr = match nameserver {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((local, 0)).map_err(|err| failed(&err))?;
+    socket
+        .set_read_timeout(Some(TIMEOUT))
+        .map_err(|err| failed(&err))?;
+    let id = random_u32() as u16;
+    // recursion desired
+    let mut packet = header(id, 0x0100, 1, 0);
+    write_name(&mut packet, name);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    socket
+        .send_to(&packet, nameserver)
+        .map_err(|err| failed(&err))?;
+
+    let mut buf = [0; 4096];
+    loop {
+        let (len, source) = socket.recv_from(&mut buf).map_err(|err| failed(&err))?;
+        let answer = &buf[..len];
+        if source != nameserver || read_u16(answer, 0) != Some(id) {
+            continue;
+        }
+        let flags = read_u16(answer, 2).unwrap_or_default();
+        return match flags & 0x000f {
+            0 => Ok(answer.to_vec()),
+            3 => Err(failed(&"no such name")),
+            rcode => Err(failed(&format!("error {}", rcode))),
+        };
+    }
+}
+
+// the offset and length of the data of each answer of the type
+fn answers(packet: &[u8], rtype: u16) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    let mut offset = 12;
+    for _ in 0..read_u16(packet, 4).unwrap_or_default() {
+        let Some((_, after)) = read_name(packet, offset) else {
+            return found;
+        };
+        offset = after + 4;
+    }
+    for _ in 0..read_u16(packet, 6).unwrap_or_default() {
+        let Some((_, after)) = read_name(packet, offset) else {
+            break;
+        };
+        let (Some(answer_type), Some(rdlength)) =
+            (read_u16(packet, after), read_u16(packet, after + 8))
+        else {
+            break;
+        };
+        let rdlength = rdlength as usize;
+        if after + 10 + rdlength > packet.len() {
+            break;
+        }
+        if answer_type == rtype {
+            found.push((after + 10, rdlength));
+        }
+        offset = after + 10 + rdlength;
+    }
+    found
+}
+
+// the length prefixed strings of a txt record
+fn txt_strings(mut data: &[u8]) -> Vec<String> {
+    let mut strings = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let Some(string) = rest.get(..len as usize) else {
+            break;
+        };
+        strings.push(String::from_utf8_lossy(string).into_owned());
+        data = &rest[len as usize..];
+    }
+    strings
+}
+
+// the lowest priority wins, its servers are picked at random in proportion of their weight
+fn pick(servers: Vec<(u16, u16, u16, String)>) -> Option<(u16, String)> {
+    let best = servers.iter().map(|(priority, ..)| *priority).min()?;
+    let servers: Vec<_> = servers
+        .into_iter()
+        .filter(|(priority, ..)| *priority == best)
+        .collect();
+    let total: u32 = servers.iter().map(|(_, weight, ..)| *weight as u32).sum();
+    let mut roll = if total == 0 { 0 } else { random_u32() % total };
+    for (_, weight, port, host) in &servers {
+        if roll < *weight as u32 {
+            return Some((*port, host.clone()));
+        }
+        roll -= *weight as u32;
+    }
+    servers
+        .into_iter()
+        .next()
+        .map(|(_, _, port, host)| (port, host))
+}
+
+fn random_u32() -> u32 {
+    let mut bytes = [0; 4];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("failed to generate random bytes");
+    u32::from_be_bytes(bytes)
+}
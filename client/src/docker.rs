@@ -0,0 +1,227 @@
+use crate::{http, privileged_port};
+use std::{
+    collections::{BTreeMap, HashSet},
+    env,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+// containers labeled kensa.expose=PORT[,PORT...] are hosted by host --docker-labels
+const EXPOSE_LABEL: &str = "kensa.expose";
+const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+// where the host reaches the port of a container, asked to the docker api on DOCKER_HOST
+// (unix:// or tcp://) or its default socket: the port it is published on when it is, the address of
+// the container on its network otherwise, which only the machine running docker can reach.
+// resolved once, a container recreated with another address needs the host restarted
+pub fn resolve(container: &str, port: u16) -> Result<SocketAddr, String> {
+    let (status, info) = api(&format!("/containers/{}/json", container))?;
+    match status {
+        200 => {}
+        404 => return Err(format!("there is no docker container named {}", container)),
+        _ => {
+            let message = info["message"].as_str().unwrap_or("unknown error");
+            return Err(format!(
+                "docker refused to describe {}: {}",
+                container, message
+            ));
+        }
+    }
+    if info["State"]["Running"].as_bool() != Some(true) {
+        return Err(format!("the docker container {} is not running", container));
+    }
+
+    let settings = &info["NetworkSettings"];
+    let published = settings["Ports"][format!("{}/tcp", port)]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|binding| {
+            let host_port = binding["HostPort"].as_str()?.parse().ok()?;
+            Some((binding["HostIp"].as_str().unwrap_or_default(), host_port))
+        });
+    reachable(published, &settings["Networks"], port).ok_or_else(|| {
+        format!(
+            "port {} of the docker container {} is not published and the container has no address",
+            port, container
+        )
+    })
+}
+
+// the running containers carrying the expose label, scanned every few seconds so ports are hosted
+// as their containers start and stop
+#[derive(Default)]
+pub struct LabelWatch {
+    allow_privileged: bool, // system ports are skipped otherwise
+    exposed: BTreeMap<u16, (String, SocketAddr)>, // by port, the container and where it is reached
+    last_scan: Option<Instant>,
+    failing: bool,           // the last scan failed, the error was already printed
+    warned: HashSet<String>, // containers whose label was already found invalid
+}
+
+impl LabelWatch {
+    pub fn new(allow_privileged: bool) -> Self {
+        LabelWatch {
+            allow_privileged,
+            ..Default::default()
+        }
+    }
+
+    // where each exposed port is reached, only when it changed since the last call
+    pub fn poll(&mut self) -> Option<BTreeMap<u16, SocketAddr>> {
+        if self
+            .last_scan
+            .is_some_and(|at| at.elapsed() < SCAN_INTERVAL)
+        {
+            return None;
+        }
+        self.last_scan = Some(Instant::now());
+        let exposed = match self.scan() {
+            Ok(exposed) => {
+                self.failing = false;
+                exposed
+            }
+            Err(err) => {
+                if !self.failing {
+                    eprintln!("failed to list the docker containers: {}", err);
+                    self.failing = true;
+                }
+                return None;
+            }
+        };
+        if exposed == self.exposed {
+            return None;
+        }
+        for (port, (container, _)) in &self.exposed {
+            if !exposed.contains_key(port) {
+                println!(
+                    "port {} of the container {} is no longer hosted",
+                    port, container
+                );
+            }
+        }
+        for (port, (container, target)) in &exposed {
+            if self.exposed.get(port) != Some(&(container.clone(), *target)) {
+                println!(
+                    "hosting port {} of the container {} on {}",
+                    port, container, target
+                );
+            }
+        }
+        self.exposed = exposed;
+        Some(
+            self.exposed
+                .iter()
+                .map(|(port, (_, target))| (*port, *target))
+                .collect(),
+        )
+    }
+
+    fn scan(&mut self) -> Result<BTreeMap<u16, (String, SocketAddr)>, String> {
+        // filters={"label":["kensa.expose"]}
+        let (status, containers) = api(&format!(
+            "/containers/json?filters=%7B%22label%22%3A%5B%22{}%22%5D%7D",
+            EXPOSE_LABEL
+        ))?;
+        if status != 200 {
+            let message = containers["message"].as_str().unwrap_or("unknown error");
+            return Err(message.to_string());
+        }
+        let mut exposed = BTreeMap::new();
+        for container in containers.as_array().into_iter().flatten() {
+            let name = container["Names"][0]
+                .as_str()
+                .unwrap_or_default()
+                .trim_start_matches('/')
+                .to_string();
+            let label = container["Labels"][EXPOSE_LABEL]
+                .as_str()
+                .unwrap_or_default();
+            for port in label.split(',').map(str::trim) {
+                let Ok(port) = port.parse::<u16>() else {
+                    if self.warned.insert(name.clone()) {
+                        eprintln!(
+                            "the container {} has an invalid {} label \"{}\", expected ports separated by commas",
+                            name, EXPOSE_LABEL, label
+                        );
+                    }
+                    continue;
+                };
+                if privileged_port(port) && !self.allow_privileged {
+                    if self.warned.insert(name.clone()) {
+                        eprintln!(
+                            "the container {} exposes the system port {}, which needs --allow-privileged-ports",
+                            name, port
+                        );
+                    }
+                    continue;
+                }
+                let published = container["Ports"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter(|binding| {
+                        binding["Type"] == "tcp"
+                            && binding["PrivatePort"].as_u64() == Some(port.into())
+                    })
+                    .filter_map(|binding| {
+                        let host_port = binding["PublicPort"].as_u64()?.try_into().ok()?;
+                        Some((binding["IP"].as_str().unwrap_or_default(), host_port))
+                    });
+                let networks = &container["NetworkSettings"]["Networks"];
+                let Some(target) = reachable(published, networks, port) else {
+                    continue;
+                };
+                // the first container claiming a port gets it
+                exposed.entry(port).or_insert((name.clone(), target));
+            }
+        }
+        Ok(exposed)
+    }
+}
+
+fn api(path: &str) -> Result<(u16, serde_json::Value), String> {
+    match env::var("DOCKER_HOST") {
+        Ok(host) if host.starts_with("tcp://") => {
+            http::get_json(&format!("http://{}{}", &host["tcp://".len()..], path))
+        }
+        Ok(host) => get_unix(host.strip_prefix("unix://").unwrap_or(&host), path),
+        Err(_) => get_unix(DEFAULT_SOCKET, path),
+    }
+}
+
+// a published binding (ip, port) when there is one, every interface meaning localhost,
+// the address of the container on one of its networks otherwise
+fn reachable<'a>(
+    mut published: impl Iterator<Item = (&'a str, u16)>,
+    networks: &serde_json::Value,
+    port: u16,
+) -> Option<SocketAddr> {
+    let published = published.find_map(|(ip, host_port)| {
+        let ip: IpAddr = match ip {
+            "" | "0.0.0.0" | "::" => [127, 0, 0, 1].into(),
+            ip => ip.parse().ok()?,
+        };
+        Some(SocketAddr::new(ip, host_port))
+    });
+    published.or_else(|| {
+        networks
+            .as_object()
+            .into_iter()
+            .flat_map(|networks| networks.values())
+            .filter_map(|network| network["IPAddress"].as_str()?.parse::<IpAddr>().ok())
+            .map(|ip| SocketAddr::new(ip, port))
+            .next()
+    })
+}
+
+#[cfg(unix)]
+fn get_unix(socket: &str, path: &str) -> Result<(u16, serde_json::Value), String> {
+    http::get_json_unix(socket.as_ref(), path)
+}
+
+#[cfg(not(unix))]
+fn get_unix(_socket: &str, _path: &str) -> Result<(u16, serde_json::Value), String> {
+    Err("set DOCKER_HOST to the tcp:// address of the docker api".to_string())
+}
@@ -0,0 +1,52 @@
+use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const FILE: &str = "favorites.json";
+
+// a connection saved under a name, connect <name> expands it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Favorite {
+    pub server_url: String,
+    pub target: String,
+    pub port: u16,
+    pub local_port: u16,
+}
+
+// kept in favorites.json in the data folder, sorted by name
+pub struct Favorites {
+    storage: Storage,
+    entries: BTreeMap<String, Favorite>,
+}
+
+impl Favorites {
+    pub fn load(storage: &Storage) -> Self {
+        let entries = storage
+            .read(FILE)
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        Favorites {
+            storage: storage.clone(),
+            entries,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Favorite)> {
+        self.entries.iter()
+    }
+
+    // returns the favorite it replaced, if any
+    pub fn insert(&mut self, name: String, favorite: Favorite) -> Option<Favorite> {
+        self.entries.insert(name, favorite)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Favorite> {
+        self.entries.remove(name)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let json =
+            serde_json::to_string_pretty(&self.entries).expect("failed to stringify favorites");
+        self.storage.write(FILE, &json)
+    }
+}
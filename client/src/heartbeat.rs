@@ -0,0 +1,64 @@
+use crate::{socket_send, Socket, WSMessage};
+use std::time::{Duration, Instant};
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+// the control channel is considered dead when a heartbeat isn't acknowledged within this delay
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+
+// sends heartbeats to the server and checks they get acknowledged,
+// a connection can look open long after a middlebox or the server dropped it
+pub struct Heartbeat {
+    enabled: bool, // servers from before heartbeats reject them
+    last_sent: Instant,
+    last_tick: Instant,
+    pending_since: Option<Instant>, // oldest heartbeat not acknowledged yet
+    rtt: Option<Duration>,          // measured with the last acknowledged heartbeat
+}
+
+impl Heartbeat {
+    pub fn new(enabled: bool) -> Self {
+        let now = Instant::now();
+        Heartbeat {
+            enabled,
+            last_sent: now,
+            last_tick: now,
+            pending_since: None,
+            rtt: None,
+        }
+    }
+
+    // returns false when the server stopped answering
+    pub fn tick(&mut self, socket: &mut Socket) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let now = Instant::now();
+        // the loop was blocked (confirmation prompt, hole punching, ...), the ack may be waiting unread
+        if now - self.last_tick > HEARTBEAT_INTERVAL {
+            self.pending_since = None;
+        }
+        self.last_tick = now;
+
+        if let Some(pending_since) = self.pending_since {
+            if now - pending_since > HEARTBEAT_TIMEOUT {
+                return false;
+            }
+        }
+        if now - self.last_sent >= HEARTBEAT_INTERVAL {
+            socket_send(socket, WSMessage::Heartbeat {});
+            self.last_sent = now;
+            self.pending_since.get_or_insert(now);
+        }
+        true
+    }
+
+    pub fn ack(&mut self) {
+        if self.pending_since.take().is_some() {
+            self.rtt = Some(self.last_sent.elapsed());
+        }
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+}
@@ -0,0 +1,97 @@
+use crate::{storage::Storage, ErrorCode};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the oldest connections are forgotten past this
+const MAX_ENTRIES: usize = 1000;
+const FILE: &str = "history.jsonl";
+
+// a connection attempt made with the connect command, one json object per line of history.jsonl
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Entry {
+    pub at: u64, // unix timestamp
+    pub server_url: String,
+    pub target: String,
+    pub port: u16,
+    pub local_port: u16,
+    pub error: Option<ErrorCode>, // why the connection failed, none when the host accepted it
+    // options of the connection, replayed by connect --last
+    #[serde(default)]
+    pub relay: bool,
+    #[serde(default)]
+    pub p2p: bool,
+    #[serde(default)]
+    pub quic: bool,
+    #[serde(default)]
+    pub wireguard: bool,
+    #[serde(default)]
+    pub add_port: Vec<(u16, u16)>,
+}
+
+pub struct History {
+    storage: Storage,
+}
+
+impl History {
+    pub fn new(storage: &Storage) -> Self {
+        History {
+            storage: storage.clone(),
+        }
+    }
+
+    // oldest first, lines which can't be read are skipped
+    pub fn load(&self) -> Vec<Entry> {
+        self.storage
+            .read(FILE)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()
+    }
+
+    // failing to write the history never stops a connection,
+    // the whole file is written again since an encrypted one can't be appended to
+    pub fn record(&self, entry: Entry) {
+        let mut entries = self.load();
+        entries.push(entry);
+        let skip = entries.len().saturating_sub(MAX_ENTRIES);
+        let content: String = entries
+            .iter()
+            .skip(skip)
+            .filter_map(|e| serde_json::to_string(e).ok())
+            .map(|e| e + "\n")
+            .collect();
+        let _ = self.storage.write(FILE, &content);
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// "2024-05-17 14:03 UTC"
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+    // days since the epoch to a civil date, from Howard Hinnant's date algorithms
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
@@ -0,0 +1,159 @@
+use crate::tls;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::Mutex,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
+use url::{form_urlencoded, Url};
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+// the basic authentication a proxy in front of the server asks for, from the server url
+// or --server-auth, sent with every request to the server
+static SERVER_AUTH: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_server_auth(user: &str, password: &str) {
+    let credentials = BASE64_STANDARD.encode(format!("{}:{}", user, password));
+    *SERVER_AUTH.lock().unwrap() = Some(format!("Basic {}", credentials));
+}
+
+// the value of the authorization header of the requests to the server
+pub fn server_auth() -> Option<String> {
+    SERVER_AUTH.lock().unwrap().clone()
+}
+
+// a bare http/1.0 client for the few json apis the client talks to (the server, identity providers),
+// 1.0 so the answer is never chunked and ends with the connection
+pub fn get_json(url: &str) -> Result<(u16, serde_json::Value), String> {
+    request("GET", url, None, Vec::new())
+}
+
+// the same for the server's own endpoints, through the proxy in front of it
+pub fn get_server_json(url: &str) -> Result<(u16, serde_json::Value), String> {
+    let headers = server_auth()
+        .map(|auth| vec![format!("Authorization: {}", auth)])
+        .unwrap_or_default();
+    request("GET", url, None, headers)
+}
+
+pub fn post_form(url: &str, form: &[(&str, &str)]) -> Result<(u16, serde_json::Value), String> {
+    let body = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(form)
+        .finish();
+    request("POST", url, Some(body), Vec::new())
+}
+
+// a request carrying a bearer token, like the ones of the server's admin api.
+// the token moves to X-Admin-Token when the proxy takes the authorization header
+pub fn authorized(
+    method: &str,
+    url: &str,
+    token: &str,
+) -> Result<(u16, serde_json::Value), String> {
+    let headers = match server_auth() {
+        Some(auth) => vec![
+            format!("Authorization: {}", auth),
+            format!("X-Admin-Token: {}", token),
+        ],
+        None => vec![format!("Authorization: Bearer {}", token)],
+    };
+    request(method, url, None, headers)
+}
+
+// the http url of a path of the server, which may be reached under a path like
+// wss://tools.example.com/portfwd/ behind a proxy
+pub fn server_endpoint(server_url: &str, path: &str) -> Result<Url, String> {
+    let mut url = Url::parse(server_url).map_err(|err| err.to_string())?;
+    let _ = url.set_scheme(if url.scheme() == "wss" {
+        "https"
+    } else {
+        "http"
+    });
+    let base = url.path().trim_end_matches('/').to_string();
+    url.set_path(&format!("{}{}", base, path));
+    url.set_query(None);
+    Ok(url)
+}
+
+fn request(
+    method: &str,
+    url: &str,
+    form: Option<String>,
+    headers: Vec<String>,
+) -> Result<(u16, serde_json::Value), String> {
+    let url = Url::parse(url).map_err(|err| format!("invalid url {}: {}", url, err))?;
+    let host = url.host_str().ok_or(format!("invalid url {}", url))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or(format!("invalid url {}", url))?;
+    let path = &url[url::Position::BeforePath..url::Position::AfterQuery];
+
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n",
+        method, path, host
+    );
+    for header in headers {
+        request.push_str(&header);
+        request.push_str("\r\n");
+    }
+    if let Some(form) = &form {
+        request.push_str("Content-Type: application/x-www-form-urlencoded\r\n");
+        request.push_str(&format!("Content-Length: {}\r\n", form.len()));
+    }
+    request.push_str("\r\n");
+    request.push_str(form.as_deref().unwrap_or_default());
+
+    let failed = |err: &dyn std::fmt::Display| format!("request to {} failed: {}", url, err);
+    let stream = TcpStream::connect((host, port)).map_err(|err| failed(&err))?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .map_err(|err| failed(&err))?;
+    let response = if url.scheme() == "https" {
+        let stream = tls::wrap(host, stream).map_err(|err| failed(&err))?;
+        exchange(stream, &request)
+    } else {
+        exchange(stream, &request)
+    }
+    .map_err(|err| failed(&err))?;
+    parse(&response).map_err(|err| failed(&err))
+}
+
+// the same as get_json for an api served on a unix socket, like docker's
+#[cfg(unix)]
+pub fn get_json_unix(socket: &Path, path: &str) -> Result<(u16, serde_json::Value), String> {
+    let failed =
+        |err: &dyn std::fmt::Display| format!("request to {} failed: {}", socket.display(), err);
+    let stream = UnixStream::connect(socket).map_err(|err| failed(&err))?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .map_err(|err| failed(&err))?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: localhost\r\nAccept: application/json\r\n\r\n",
+        path
+    );
+    let response = exchange(stream, &request).map_err(|err| failed(&err))?;
+    parse(&response).map_err(|err| failed(&err))
+}
+
+fn parse(response: &[u8]) -> Result<(u16, serde_json::Value), &'static str> {
+    let response = String::from_utf8_lossy(response);
+    let (head, body) = response.split_once("\r\n\r\n").ok_or("truncated answer")?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or("invalid answer")?;
+    let json = serde_json::from_str(body).unwrap_or(serde_json::Value::Null);
+    Ok((status, json))
+}
+
+fn exchange(mut stream: impl Read + Write, request: &str) -> io::Result<Vec<u8>> {
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(response)
+}
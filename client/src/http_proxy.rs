@@ -0,0 +1,342 @@
+use crate::{
+    inspector::{Exchange, Inspector},
+    tls::Acceptor,
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+// how long the proxy waits on one side of a connection before checking the other
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// a request whose headers don't fit in this is refused
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+// the proxy of connect --http, in front of the tunnel: every request has its headers rewritten
+// as if it had been made on the host (Host: localhost:PORT) with where it came from in X-Forwarded-*.
+// connections are closed after each request so every request goes through the rewriting
+#[derive(Clone)]
+pub struct HttpProxy {
+    upstream: u16,    // local port of the tunnel
+    origin_port: u16, // port of the service on the host
+    tls: Option<Arc<Acceptor>>,
+    credentials: Option<[u8; 32]>, // hash of the USER:PASSWORD requests must give
+    inspector: Arc<Inspector>,
+}
+
+impl HttpProxy {
+    pub fn new(upstream: u16, origin_port: u16) -> Self {
+        HttpProxy {
+            upstream,
+            origin_port,
+            tls: None,
+            credentials: None,
+            inspector: Arc::new(Inspector::default()),
+        }
+    }
+
+    // serves the requests that went through the proxy on the port, see Inspector
+    pub fn inspect(&self, port: u16) -> io::Result<()> {
+        self.inspector.clone().start(port, self.upstream)
+    }
+
+    // requests must give these credentials (USER:PASSWORD) with basic authentication
+    pub fn with_auth(mut self, credentials: &str) -> Self {
+        self.credentials = Some(Sha256::digest(credentials).into());
+        self
+    }
+
+    // serves https with the certificate, the service on the host still gets plain http
+    pub fn with_tls(mut self, acceptor: Acceptor) -> Self {
+        self.tls = Some(Arc::new(acceptor));
+        self
+    }
+
+    // listens on the port on its own threads, the tunnel may come up later
+    pub fn start(self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port)))?;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let proxy = self.clone();
+                thread::spawn(move || {
+                    let _ = proxy.serve(stream);
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn serve(&self, client: TcpStream) -> io::Result<()> {
+        let raw = client.try_clone()?;
+        match &self.tls {
+            Some(acceptor) => match acceptor.accept(client) {
+                Ok(client) => self.forward(client, &raw),
+                // browsers try and give up on a certificate they don't trust
+                Err(_) => Ok(()),
+            },
+            None => self.forward(client, &raw),
+        }
+    }
+
+    // raw is the tcp stream under the client's, for its timeout
+    fn forward(&self, mut client: impl Read + Write, raw: &TcpStream) -> io::Result<()> {
+        let peer = raw.peer_addr()?;
+        let (head, body) = match read_head(&mut client)? {
+            Some(read) => read,
+            None => return Ok(()),
+        };
+        let Some(request) = Request::parse(&head) else {
+            return respond(&mut client, "400 Bad Request", "");
+        };
+        if !self.authorized(&request) {
+            return respond(
+                &mut client,
+                "401 Unauthorized",
+                "WWW-Authenticate: Basic realm=\"kensa-port-forwarder\"\r\n",
+            );
+        }
+        let head = self.rewrite(request, peer);
+        exchange(self.upstream, &self.inspector, client, raw, head, &body)
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let Some(expected) = &self.credentials else {
+            return true;
+        };
+        let given = request
+            .header("authorization")
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+            .and_then(|(_, encoded)| BASE64_STANDARD.decode(encoded.trim()).ok());
+        // the hashes are compared so the time it takes tells nothing of the password
+        given.is_some_and(|given| Sha256::digest(given).as_slice() == expected)
+    }
+
+    fn rewrite(&self, mut request: Request, peer: SocketAddr) -> String {
+        let host = request.header("host").map(str::to_string);
+        let forwarded_for = match request.header("x-forwarded-for") {
+            Some(chain) => format!("{}, {}", chain, peer.ip()),
+            None => peer.ip().to_string(),
+        };
+        // websockets and the like keep the connection once upgraded
+        let upgrade = request.header("upgrade").is_some();
+
+        let mut replaced = vec![
+            "host",
+            "x-forwarded-for",
+            "x-forwarded-host",
+            "x-forwarded-proto",
+        ];
+        // the password of the tunnel is not the service's business
+        if self.credentials.is_some() {
+            replaced.push("authorization");
+        }
+        let headers = &mut request.headers;
+        headers.retain(|(name, _)| {
+            let name = name.to_ascii_lowercase();
+            !replaced.contains(&name.as_str())
+                && (upgrade || (name != "connection" && name != "keep-alive"))
+        });
+        headers.push((
+            "Host".to_string(),
+            format!("localhost:{}", self.origin_port),
+        ));
+        headers.push(("X-Forwarded-For".to_string(), forwarded_for));
+        if let Some(host) = host {
+            headers.push(("X-Forwarded-Host".to_string(), host));
+        }
+        let proto = if self.tls.is_some() { "https" } else { "http" };
+        headers.push(("X-Forwarded-Proto".to_string(), proto.to_string()));
+        if !upgrade {
+            headers.push(("Connection".to_string(), "close".to_string()));
+        }
+
+        let mut rewritten = format!("{}\r\n", request.line);
+        for (name, value) in &request.headers {
+            rewritten.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        rewritten.push_str("\r\n");
+        rewritten
+    }
+}
+
+pub struct Request {
+    pub line: String, // like GET /index.html HTTP/1.1
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    // none when the head is not a request
+    pub fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let line = lines.next()?;
+        if line.split(' ').count() != 3 {
+            return None;
+        }
+        let mut headers = Vec::new();
+        for header in lines.filter(|header| !header.is_empty()) {
+            let (name, value) = header.split_once(':')?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        Some(Request {
+            line: line.to_string(),
+            headers,
+        })
+    }
+
+    fn header(&self, wanted: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// the head of the request, without its blank line, and the part of the body read along with it.
+// none when the client closed the connection before sending a request
+pub fn read_head(stream: &mut impl Read) -> io::Result<Option<(String, Vec<u8>)>> {
+    let mut read = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        if let Some(end) = read.windows(4).position(|w| w == b"\r\n\r\n") {
+            let body = read.split_off(end + 4);
+            read.truncate(end);
+            return Ok(Some((String::from_utf8_lossy(&read).into_owned(), body)));
+        }
+        if read.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "request too large"));
+        }
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        read.extend_from_slice(&buffer[..n]);
+    }
+}
+
+// headers are whole lines, ending with \r\n
+pub fn respond(stream: &mut impl Write, status: &str, headers: &str) -> io::Result<()> {
+    stream.write_all(
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+            status, headers
+        )
+        .as_bytes(),
+    )
+}
+
+// sends the request to the service on the tunnel and its answer back, for the inspector to keep.
+// raw is the tcp stream under the client's, for its timeout
+pub fn exchange(
+    upstream: u16,
+    inspector: &Inspector,
+    mut client: impl Read + Write,
+    raw: &TcpStream,
+    head: String,
+    body: &[u8],
+) -> io::Result<()> {
+    let mut exchange = Exchange::new(head, body);
+    let Ok(mut upstream) = TcpStream::connect(("127.0.0.1", upstream)) else {
+        exchange.set_status(502);
+        inspector.record(exchange);
+        return respond(&mut client, "502 Bad Gateway", "");
+    };
+    upstream.write_all(exchange.head().as_bytes())?;
+    upstream.write_all(body)?;
+    raw.set_read_timeout(Some(POLL_INTERVAL))?;
+    upstream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let piped = pipe(client, upstream, &mut exchange);
+    inspector.record(exchange);
+    piped
+}
+
+// copies both ways until the service closes its side, which completes the answer.
+// the streams must have a read timeout
+fn pipe(
+    mut client: impl Read + Write,
+    mut upstream: impl Read + Write,
+    exchange: &mut Exchange,
+) -> io::Result<()> {
+    let mut client_open = true;
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        if client_open {
+            match copy_some(&mut client, &mut upstream, &mut buffer)? {
+                Some(n) => exchange.request_data(&buffer[..n]),
+                None => client_open = false,
+            }
+        }
+        match copy_some(&mut upstream, &mut client, &mut buffer)? {
+            Some(n) => exchange.response_data(&buffer[..n]),
+            None => return Ok(()),
+        }
+    }
+}
+
+// how much was copied, none once the source is closed
+fn copy_some(
+    from: &mut impl Read,
+    to: &mut impl Write,
+    buffer: &mut [u8],
+) -> io::Result<Option<usize>> {
+    match from.read(buffer) {
+        Ok(0) => Ok(None),
+        Ok(n) => {
+            to.write_all(&buffer[..n])?;
+            to.flush()?;
+            Ok(Some(n))
+        }
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            Ok(Some(0))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// the certificate and (pkcs8) key of the pem files, or a self-signed certificate for localhost
+// kept in the data folder so browsers only have to be told to trust it once
+pub fn load_identity(
+    files: Option<(PathBuf, PathBuf)>,
+    data_dir: &Path,
+) -> Result<Acceptor, String> {
+    let (cert_file, key_file) = match files {
+        Some(files) => files,
+        None => {
+            let files = (
+                data_dir.join("http_cert.pem"),
+                data_dir.join("http_key.pem"),
+            );
+            if !files.0.exists() || !files.1.exists() {
+                generate_certificate(&files.0, &files.1)?;
+            }
+            files
+        }
+    };
+    let read = |file: &Path| {
+        fs::read(file).map_err(|err| format!("failed to read {}: {}", file.display(), err))
+    };
+    Acceptor::new(&read(&cert_file)?, &read(&key_file)?)
+        .map_err(|err| format!("invalid certificate or key: {}", err))
+}
+
+fn generate_certificate(cert_file: &Path, key_file: &Path) -> Result<(), String> {
+    let names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    let certified = rcgen::generate_simple_self_signed(names).map_err(|err| err.to_string())?;
+    let write = |file: &Path, pem: String| {
+        fs::write(file, pem).map_err(|err| format!("failed to write {}: {}", file.display(), err))
+    };
+    write(cert_file, certified.cert.pem())?;
+    write(key_file, certified.key_pair.serialize_pem())?;
+    println!(
+        "generated a self-signed certificate for localhost in {}",
+        cert_file.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use crate::{tls, Socket};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+use url::Url;
+
+// what the client saw of a server on first contact, later connections must see the same (trust on first use)
+pub struct ServerIdentity {
+    pins_path: PathBuf,        // tls certificate fingerprints by server, as json
+    known_hosts_path: PathBuf, // sshd host keys, as an openssh known_hosts file
+    server: String,            // host:port of the server
+}
+
+impl ServerIdentity {
+    pub fn new(data_dir: &Path, server_url: &str) -> Self {
+        let url = Url::parse(server_url).expect("failed to parse server url");
+        let server = format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+        ServerIdentity {
+            pins_path: data_dir.join("tls_pins.json"),
+            known_hosts_path: data_dir.join("known_hosts"),
+            server,
+        }
+    }
+
+    // records the certificate of the server the first time, refuses a different one afterwards
+    pub fn check_tls(&self, socket: &Socket, accept_new: bool) -> Result<(), String> {
+        let Some(certificate) = tls::peer_certificate(socket.stream()) else {
+            return Ok(()); // ws:// has nothing to pin
+        };
+        let certificate = certificate.ok_or("the server did not present a certificate")?;
+        let fingerprint: String = Sha256::digest(certificate)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let mut pins: BTreeMap<String, String> = fs::read_to_string(&self.pins_path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+        match pins.get(&self.server) {
+            Some(pinned) if *pinned == fingerprint => return Ok(()),
+            Some(pinned) if !accept_new => {
+                return Err(format!(
+                    "WARNING: THE IDENTITY OF THE SERVER {} HAS CHANGED\n\
+                     someone could be intercepting the connection, or the server changed its certificate\n\
+                     pinned sha256 fingerprint: {}\n\
+                     presented sha256 fingerprint: {}\n\
+                     run again with --accept-new-identity once you checked the new one is legitimate",
+                    self.server, pinned, fingerprint
+                ));
+            }
+            Some(_) => println!("accepting the new identity of {}", self.server),
+            None => println!(
+                "first connection to {}, its certificate is pinned (sha256 {})",
+                self.server, fingerprint
+            ),
+        }
+        pins.insert(self.server.clone(), fingerprint);
+        let json = serde_json::to_string_pretty(&pins).expect("failed to stringify pins");
+        fs::write(&self.pins_path, json)
+            .map_err(|err| format!("failed to write {}: {}", self.pins_path.display(), err))
+    }
+
+    // ssh records the host key of the server's sshd on first use and refuses a different one,
+    // every tunnel port of the server shares the same entry
+    pub fn ssh_options(&self) -> Vec<String> {
+        vec![
+            "-o".to_string(),
+            format!("UserKnownHostsFile={}", self.known_hosts_path.display()),
+            "-o".to_string(),
+            "StrictHostKeyChecking=accept-new".to_string(),
+            "-o".to_string(),
+            format!("HostKeyAlias={}", self.ssh_alias()),
+        ]
+    }
+
+    // forgets the recorded sshd host key, for --accept-new-identity
+    pub fn forget_ssh_key(&self) {
+        if self.known_hosts_path.exists() {
+            let _ = process::Command::new("ssh-keygen")
+                .arg("-R")
+                .arg(self.ssh_alias())
+                .arg("-f")
+                .arg(&self.known_hosts_path)
+                .output();
+        }
+    }
+
+    fn ssh_alias(&self) -> String {
+        format!("kensa-{}", self.server.replace(':', "-"))
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    history::now,
+    http_proxy::{self, Request},
+};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+// requests kept for the inspector, the oldest are forgotten
+const MAX_EXCHANGES: usize = 100;
+// request bodies are kept up to this for replaying, longer ones can't be replayed
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+// a request that went through the http proxy and how the service answered it
+#[derive(Clone, Serialize)]
+pub struct Exchange {
+    id: u64,
+    at: u64, // unix timestamp
+    method: String,
+    path: String,
+    status: Option<u16>, // none when the service closed without answering
+    duration_ms: u64,
+    #[serde(skip)]
+    head: String, // as sent to the service
+    #[serde(skip)]
+    body: Vec<u8>,
+    #[serde(skip)]
+    truncated: bool, // the body was too long to keep whole
+    #[serde(skip)]
+    response: Vec<u8>, // start of the answer, until the status line is known
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl Exchange {
+    pub fn new(head: String, body: &[u8]) -> Self {
+        let mut words = head.split(' ');
+        let method = words.next().unwrap_or_default().to_string();
+        let path = words.next().unwrap_or_default().to_string();
+        let mut exchange = Exchange {
+            id: 0,
+            at: now(),
+            method,
+            path,
+            status: None,
+            duration_ms: 0,
+            head,
+            body: Vec::new(),
+            truncated: false,
+            response: Vec::new(),
+            started: Instant::now(),
+        };
+        exchange.request_data(body);
+        exchange
+    }
+
+    pub fn head(&self) -> &str {
+        &self.head
+    }
+
+    pub fn request_data(&mut self, data: &[u8]) {
+        if self.body.len() + data.len() > MAX_BODY_SIZE {
+            self.truncated = true;
+        } else {
+            self.body.extend_from_slice(data);
+        }
+    }
+
+    pub fn response_data(&mut self, data: &[u8]) {
+        if self.status.is_some() || self.response.len() > 1024 {
+            return;
+        }
+        self.response.extend_from_slice(data);
+        let response = String::from_utf8_lossy(&self.response);
+        if let Some((status_line, _)) = response.split_once("\r\n") {
+            self.status = status_line
+                .split(' ')
+                .nth(1)
+                .and_then(|status| status.parse().ok());
+        }
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = Some(status);
+    }
+}
+
+// keeps the last requests of the http proxy, prints each as it completes and serves them on a
+// local port to be reviewed and replayed:
+//   GET /                       the requests, newest first
+//   GET /requests               the same as json
+//   GET /requests/ID            the request as it was sent to the service
+//   POST /requests/ID/replay    sends the request again, answers with the service's answer
+#[derive(Default)]
+pub struct Inspector {
+    exchanges: Mutex<VecDeque<Exchange>>,
+    next_id: AtomicU64,
+}
+
+impl Inspector {
+    pub fn record(&self, mut exchange: Exchange) {
+        exchange.id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        exchange.duration_ms = exchange.started.elapsed().as_millis() as u64;
+        println!(
+            "{:<4} {} {} {}ms",
+            exchange.status.map_or("-".to_string(), |s| s.to_string()),
+            exchange.method,
+            exchange.path,
+            exchange.duration_ms
+        );
+        let mut exchanges = self.exchanges.lock().unwrap();
+        exchanges.push_back(exchange);
+        if exchanges.len() > MAX_EXCHANGES {
+            exchanges.pop_front();
+        }
+    }
+
+    // upstream is the local port of the tunnel, where replayed requests go
+    pub fn start(self: Arc<Self>, port: u16, upstream: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port)))?;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let inspector = self.clone();
+                thread::spawn(move || {
+                    let _ = inspector.serve(stream, upstream);
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn serve(&self, mut stream: TcpStream, upstream: u16) -> io::Result<()> {
+        let Some((head, _)) = http_proxy::read_head(&mut stream)? else {
+            return Ok(());
+        };
+        let Some(request) = Request::parse(&head) else {
+            return http_proxy::respond(&mut stream, "400 Bad Request", "");
+        };
+        let mut words = request.line.split(' ');
+        let (method, path) = (
+            words.next().unwrap_or_default(),
+            words.next().unwrap_or_default(),
+        );
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let find = |id: &str| {
+            let id: u64 = id.parse().ok()?;
+            let exchanges = self.exchanges.lock().unwrap();
+            exchanges.iter().find(|e| e.id == id).cloned()
+        };
+        match (method, segments.as_slice()) {
+            ("GET", [""]) => {
+                let exchanges = self.exchanges.lock().unwrap();
+                let mut text = format!(
+                    "{:<5} {:<6} {:<8} {:<8} path\n",
+                    "id", "status", "method", "time"
+                );
+                for exchange in exchanges.iter().rev() {
+                    text.push_str(&format!(
+                        "{:<5} {:<6} {:<8} {:<8} {}\n",
+                        exchange.id,
+                        exchange.status.map_or("-".to_string(), |s| s.to_string()),
+                        exchange.method,
+                        format!("{}ms", exchange.duration_ms),
+                        exchange.path
+                    ));
+                }
+                send(&mut stream, "text/plain", text.as_bytes())
+            }
+            ("GET", ["requests"]) => {
+                let exchanges = self.exchanges.lock().unwrap();
+                let json = serde_json::to_vec(&*exchanges).expect("failed to stringify requests");
+                send(&mut stream, "application/json", &json)
+            }
+            ("GET", ["requests", id]) => match find(id) {
+                Some(exchange) => {
+                    let mut raw = exchange.head.into_bytes();
+                    raw.extend_from_slice(&exchange.body);
+                    send(&mut stream, "text/plain", &raw)
+                }
+                None => http_proxy::respond(&mut stream, "404 Not Found", ""),
+            },
+            ("POST", ["requests", id, "replay"]) => match find(id) {
+                // the service would wait for the rest of the body
+                Some(exchange) if exchange.truncated => {
+                    http_proxy::respond(&mut stream, "409 Conflict", "")
+                }
+                Some(exchange) => {
+                    let raw = stream.try_clone()?;
+                    http_proxy::exchange(
+                        upstream,
+                        self,
+                        stream,
+                        &raw,
+                        exchange.head,
+                        &exchange.body,
+                    )
+                }
+                None => http_proxy::respond(&mut stream, "404 Not Found", ""),
+            },
+            _ => http_proxy::respond(&mut stream, "404 Not Found", ""),
+        }
+    }
+}
+
+fn send(stream: &mut impl Write, content_type: &str, body: &[u8]) -> io::Result<()> {
+    stream.write_all(
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            content_type,
+            body.len()
+        )
+        .as_bytes(),
+    )?;
+    stream.write_all(body)
+}
@@ -0,0 +1,126 @@
+use std::{
+    io::Write,
+    process::{self, Stdio},
+};
+
+// the secrets are kept by the platform keyring through its command line tool:
+// secret-tool (Secret Service) on linux, security (Keychain) on macos and the PasswordVault on windows
+const SERVICE: &str = "kensa-port-forwarder";
+
+// none when the keyring has no such secret or can't be reached
+pub fn get(account: &str) -> Option<String> {
+    let output = if cfg!(target_os = "macos") {
+        process::Command::new("security")
+            .args(["find-generic-password", "-s", SERVICE, "-a", account, "-w"])
+            .stderr(Stdio::null())
+            .output()
+    } else if cfg!(windows) {
+        powershell(&format!(
+            "{}; $vault.Retrieve('{}', '{}').Password",
+            VAULT,
+            SERVICE,
+            quote(account)
+        ))
+        .output()
+    } else {
+        process::Command::new("secret-tool")
+            .args(["lookup", "service", SERVICE, "account", account])
+            .stderr(Stdio::null())
+            .output()
+    }
+    .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let secret = String::from_utf8(output.stdout).ok()?;
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    (!secret.is_empty()).then(|| secret.to_string())
+}
+
+pub fn set(account: &str, secret: &str) -> Result<(), String> {
+    let mut command = if cfg!(target_os = "macos") {
+        // -U replaces the secret when the account already has one,
+        // security only reads it from the arguments or the terminal
+        let mut command = process::Command::new("security");
+        command.args([
+            "add-generic-password",
+            "-U",
+            "-s",
+            SERVICE,
+            "-a",
+            account,
+            "-w",
+            secret,
+        ]);
+        command
+    } else if cfg!(windows) {
+        powershell(&format!(
+            "{}; $vault.Add((New-Object Windows.Security.Credentials.PasswordCredential('{}', '{}', \
+             [Console]::In.ReadToEnd())))",
+            VAULT,
+            SERVICE,
+            quote(account)
+        ))
+    } else {
+        let mut command = process::Command::new("secret-tool");
+        command.args([
+            "store", "--label", SERVICE, "service", SERVICE, "account", account,
+        ]);
+        command
+    };
+    // elsewhere the secret goes through stdin so it never shows in the process list
+    let status = command
+        .stdin(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .and_then(|mut child| {
+            let mut stdin = child.stdin.take().unwrap();
+            if !cfg!(target_os = "macos") {
+                stdin.write_all(secret.as_bytes())?;
+            }
+            drop(stdin);
+            child.wait()
+        });
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(_) => Err("the keyring refused to store the secret".to_string()),
+        Err(err) => Err(format!("failed to reach the keyring: {}", err)),
+    }
+}
+
+// forgetting a secret the keyring doesn't have is not an error
+pub fn delete(account: &str) {
+    let _ = if cfg!(target_os = "macos") {
+        process::Command::new("security")
+            .args(["delete-generic-password", "-s", SERVICE, "-a", account])
+            .output()
+    } else if cfg!(windows) {
+        powershell(&format!(
+            "{}; $vault.Remove($vault.Retrieve('{}', '{}'))",
+            VAULT,
+            SERVICE,
+            quote(account)
+        ))
+        .output()
+    } else {
+        process::Command::new("secret-tool")
+            .args(["clear", "service", SERVICE, "account", account])
+            .output()
+    };
+}
+
+const VAULT: &str = "[void][Windows.Security.Credentials.PasswordVault, Windows.Security.Credentials, \
+                     ContentType = WindowsRuntime]; $vault = New-Object Windows.Security.Credentials.PasswordVault";
+
+fn powershell(script: &str) -> process::Command {
+    let mut command = process::Command::new("powershell");
+    command
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .stderr(Stdio::null());
+    command
+}
+
+// for a single quoted powershell string
+fn quote(value: &str) -> String {
+    value.replace('\'', "''")
+}
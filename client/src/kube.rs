@@ -0,0 +1,88 @@
+use std::{
+    io::{BufRead, BufReader, Lines},
+    process::{Child, ChildStdout, Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+// kubectl gives up when the pod behind the forward goes away, it is started again after this
+const RESTART_DELAY: Duration = Duration::from_secs(2);
+
+// a kubectl port-forward from a local port to the port of a resource of the cluster (svc/NAME,
+// pod/NAME, deployment/NAME...), kept up as long as the host runs. kubectl uses the user's own
+// configuration, so the context and credentials are whatever kubectl would use
+pub struct PortForward {
+    pub resource: String,
+    pub port: u16,
+    pub namespace: Option<String>,
+    pub local_port: u16,
+}
+
+impl PortForward {
+    // waits until kubectl listens, then restarts it on its own thread whenever it stops
+    pub fn start(self) -> Result<(), String> {
+        let first = self.spawn()?;
+        thread::spawn(move || {
+            let mut running = Some(first);
+            loop {
+                if let Some((mut child, lines)) = running.take() {
+                    // kubectl prints a line per connection until it exits
+                    lines.for_each(drop);
+                    let _ = child.wait();
+                    eprintln!(
+                        "kubectl port-forward {} stopped, restarting it",
+                        self.resource
+                    );
+                }
+                thread::sleep(RESTART_DELAY);
+                running = self.spawn().map_err(|err| eprintln!("{}", err)).ok();
+            }
+        });
+        Ok(())
+    }
+
+    fn spawn(&self) -> Result<(Child, Lines<BufReader<ChildStdout>>), String> {
+        let mut command = Command::new("kubectl");
+        command.args(["port-forward", "--address", "127.0.0.1"]);
+        if let Some(namespace) = &self.namespace {
+            command.arg("--namespace").arg(namespace);
+        }
+        command
+            .arg(&self.resource)
+            .arg(format!("{}:{}", self.local_port, self.port))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+        die_with_parent(&mut command);
+        let mut child = command
+            .spawn()
+            .map_err(|err| format!("failed to run kubectl: {}", err))?;
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        // "Forwarding from 127.0.0.1:PORT -> PORT" once it listens, its errors go to stderr
+        match lines.next() {
+            Some(Ok(line)) if line.starts_with("Forwarding from") => Ok((child, lines)),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(format!(
+                    "kubectl port-forward {} port {} failed",
+                    self.resource, self.port
+                ))
+            }
+        }
+    }
+}
+
+// kubectl is not left forwarding once the host is gone, however it exits
+#[cfg(target_os = "linux")]
+fn die_with_parent(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    unsafe {
+        command.pre_exec(|| {
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn die_with_parent(_command: &mut Command) {}
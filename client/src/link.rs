@@ -0,0 +1,60 @@
+use std::fmt;
+use url::Url;
+
+const SCHEME: &str = "kensa";
+
+// everything a receiver needs to connect to a host, shared as kensa://<host uuid>/<port>?server=<url>&invite=<token>
+pub struct Link {
+    pub server_url: String,
+    pub target: String,
+    pub port: u16,
+    pub invite: Option<String>,
+}
+
+impl Link {
+    pub fn is_link(s: &str) -> bool {
+        s.starts_with(&format!("{}://", SCHEME))
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let invalid = |reason: &str| format!("invalid link \"{}\": {}", s, reason);
+        let url = Url::parse(s).map_err(|err| invalid(&err.to_string()))?;
+        if url.scheme() != SCHEME {
+            return Err(invalid("it should start with kensa://"));
+        }
+        let target = url
+            .host_str()
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| invalid("the host is missing"))?
+            .to_string();
+        let port = url
+            .path()
+            .trim_matches('/')
+            .parse()
+            .map_err(|_| invalid("the port is missing"))?;
+        let query = |key: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.into_owned())
+        };
+        Ok(Link {
+            server_url: query("server").ok_or_else(|| invalid("the server is missing"))?,
+            target,
+            port,
+            invite: query("invite"),
+        })
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut url = Url::parse(&format!("{}://{}/{}", SCHEME, self.target, self.port))
+            .map_err(|_| fmt::Error)?;
+        url.query_pairs_mut()
+            .append_pair("server", &self.server_url);
+        if let Some(invite) = &self.invite {
+            url.query_pairs_mut().append_pair("invite", invite);
+        }
+        write!(f, "{}", url)
+    }
+}
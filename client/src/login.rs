@@ -0,0 +1,240 @@
+use crate::{history::now, http, storage::Storage};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, process, sync::OnceLock, thread, time::Duration};
+
+const FILE: &str = "tokens.json";
+// the token is refreshed when it expires sooner than this, in seconds
+const EXPIRY_MARGIN: u64 = 60;
+// offline_access asks for a refresh token, so the login lasts longer than the id token
+const SCOPE: &str = "openid profile email offline_access";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+pub const AUTH_TOKEN_ENV: &str = "KENSA_AUTH_TOKEN";
+pub const AUTH_USER_ENV: &str = "KENSA_AUTH_USER";
+
+// set once from the command line, for the servers authenticating with tokens, passwords or ldap
+static CREDENTIALS: OnceLock<Credentials> = OnceLock::new();
+
+// sent along the registration, the server's authentication backend looks at its own part
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Credentials {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+// kept out of the logs of the messages
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+// what a login to a server left, kept in tokens.json by server url
+#[derive(Serialize, Deserialize)]
+struct Token {
+    id_token: String,
+    refresh_token: Option<String>,
+    token_endpoint: String,
+    client_id: String,
+    expires_at: u64, // unix timestamp
+}
+
+fn load(storage: &Storage) -> BTreeMap<String, Token> {
+    storage
+        .read(FILE)
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save(storage: &Storage, tokens: &BTreeMap<String, Token>) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(tokens).expect("failed to stringify tokens");
+    storage.write(FILE, &json)
+}
+
+// logs in with the identity provider of the server through the device authorization flow:
+// the user approves the login in a browser, possibly on another machine, while the client polls
+pub fn login(storage: &Storage, server_url: &str) -> Result<(), String> {
+    let url = http::server_endpoint(server_url, "/oidc")?;
+    let (status, provider) = http::get_server_json(url.as_str())?;
+    if status == 404 {
+        return Err("this server does not ask for a login".to_string());
+    }
+    let (Some(issuer), Some(client_id)) =
+        (provider["issuer"].as_str(), provider["client_id"].as_str())
+    else {
+        return Err(format!(
+            "the server answered {} instead of its identity provider",
+            status
+        ));
+    };
+
+    let (_, configuration) = http::get_json(&format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    ))?;
+    let token_endpoint = configuration["token_endpoint"]
+        .as_str()
+        .ok_or("the identity provider has no token endpoint")?;
+    let device_endpoint = configuration["device_authorization_endpoint"]
+        .as_str()
+        .ok_or(
+        "the identity provider does not support logging in from a terminal (device authorization)",
+    )?;
+
+    let (_, device) = http::post_form(
+        device_endpoint,
+        &[("client_id", client_id), ("scope", SCOPE)],
+    )?;
+    let (Some(device_code), Some(user_code), Some(verification_uri)) = (
+        device["device_code"].as_str(),
+        device["user_code"].as_str(),
+        device["verification_uri"].as_str(),
+    ) else {
+        return Err(error_message(
+            &device,
+            "the identity provider refused the login",
+        ));
+    };
+    println!(
+        "to log in, open {} and enter the code {}",
+        verification_uri, user_code
+    );
+    open_browser(
+        device["verification_uri_complete"]
+            .as_str()
+            .unwrap_or(verification_uri),
+    );
+
+    let mut interval = device["interval"].as_u64().unwrap_or(5);
+    let response = loop {
+        thread::sleep(Duration::from_secs(interval));
+        let (status, response) = http::post_form(
+            token_endpoint,
+            &[
+                ("grant_type", DEVICE_CODE_GRANT),
+                ("device_code", device_code),
+                ("client_id", client_id),
+            ],
+        )?;
+        match response["error"].as_str() {
+            None if status == 200 => break response,
+            Some("authorization_pending") => {}
+            Some("slow_down") => interval += 5,
+            _ => return Err(error_message(&response, "the login failed")),
+        }
+    };
+    let id_token = response["id_token"]
+        .as_str()
+        .ok_or("the identity provider did not give an id token")?;
+
+    let mut tokens = load(storage);
+    tokens.insert(
+        server_url.to_string(),
+        Token {
+            id_token: id_token.to_string(),
+            refresh_token: response["refresh_token"].as_str().map(str::to_string),
+            token_endpoint: token_endpoint.to_string(),
+            client_id: client_id.to_string(),
+            expires_at: now() + response["expires_in"].as_u64().unwrap_or(3600),
+        },
+    );
+    save(storage, &tokens)
+}
+
+// user is USER:PASSWORD
+pub fn set_credentials(user: Option<&str>, token: Option<String>) {
+    let (user, password) = match user.and_then(|user| user.split_once(':')) {
+        Some((user, password)) => (Some(user.to_string()), Some(password.to_string())),
+        None => (None, None),
+    };
+    if user.is_some() || token.is_some() {
+        let _ = CREDENTIALS.set(Credentials {
+            user,
+            password,
+            token,
+        });
+    }
+}
+
+pub fn credentials() -> Option<Credentials> {
+    CREDENTIALS.get().cloned()
+}
+
+// returns whether there was a login to forget
+pub fn logout(storage: &Storage, server_url: &str) -> Result<bool, String> {
+    let mut tokens = load(storage);
+    if tokens.remove(server_url).is_none() {
+        return Ok(false);
+    }
+    save(storage, &tokens)?;
+    Ok(true)
+}
+
+// the id token to register on the server with, refreshed when it is about to expire,
+// the server tells the user to log in again when it refuses it
+pub fn id_token(storage: &Storage, server_url: &str) -> Option<String> {
+    let mut tokens = load(storage);
+    let token = tokens.get_mut(server_url)?;
+    if token.expires_at > now() + EXPIRY_MARGIN {
+        return Some(token.id_token.clone());
+    }
+    let Some(refresh_token) = &token.refresh_token else {
+        return Some(token.id_token.clone());
+    };
+    let refreshed = http::post_form(
+        &token.token_endpoint,
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("client_id", &token.client_id),
+        ],
+    );
+    if let Ok((200, response)) = refreshed {
+        if let Some(id_token) = response["id_token"].as_str() {
+            token.id_token = id_token.to_string();
+            token.expires_at = now() + response["expires_in"].as_u64().unwrap_or(3600);
+            if let Some(refresh_token) = response["refresh_token"].as_str() {
+                token.refresh_token = Some(refresh_token.to_string());
+            }
+        }
+    }
+    let id_token = token.id_token.clone();
+    let _ = save(storage, &tokens);
+    Some(id_token)
+}
+
+fn error_message(response: &Value, default: &str) -> String {
+    match (
+        response["error"].as_str(),
+        response["error_description"].as_str(),
+    ) {
+        (_, Some(description)) => format!("{}: {}", default, description),
+        (Some(error), None) => format!("{}: {}", default, error),
+        (None, None) => default.to_string(),
+    }
+}
+
+// failing is fine, the url is printed for the user to open
+fn open_browser(url: &str) {
+    let mut command = if cfg!(target_os = "macos") {
+        process::Command::new("open")
+    } else if cfg!(windows) {
+        // start would need the url escaped for cmd
+        let mut command = process::Command::new("rundll32");
+        command.arg("url.dll,FileProtocolHandler");
+        command
+    } else {
+        process::Command::new("xdg-open")
+    };
+    let _ = command
+        .arg(url)
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn();
+}
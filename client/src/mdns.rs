@@ -0,0 +1,176 @@
+use crate::p2p::local_ip;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+// hosts are looked up by uuid under this service, like <uuid>._kensa-pf._tcp.local
+const SERVICE: &str = "_kensa-pf._tcp.local";
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const TTL: u32 = 120;
+
+fn host_name(uuid: &str) -> String {
+    format!("{}.{}", uuid, SERVICE)
+}
+
+// answers the mdns queries for this host's name with its address on the local network, until the process exits
+pub fn advertise(uuid: &str) -> io::Result<()> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    let socket: UdpSocket = socket.into();
+    let name = host_name(uuid);
+    thread::spawn(move || {
+        let mut buf = [0; 1500];
+        while let Ok((len, source)) = socket.recv_from(&mut buf) {
+            let Some(id) = parse_query(&buf[..len], &name) else {
+                continue;
+            };
+            // looked up on every query, the machine can change networks while hosting
+            let Some(IpAddr::V4(ip)) = local_ip() else {
+                continue;
+            };
+            // one shot queries come from another port than 5353 and expect a unicast answer
+            let destination = if source.port() == MDNS_PORT {
+                SocketAddr::from((MDNS_ADDR, MDNS_PORT))
+            } else {
+                source
+            };
+            let _ = socket.send_to(&answer(id, &name, ip), destination);
+        }
+    });
+    Ok(())
+}
+
+// asks the local network for the address of the host, none when nobody answered in time
+pub fn resolve(uuid: &str, timeout: Duration) -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    let name = host_name(uuid);
+    socket.send_to(&query(&name), (MDNS_ADDR, MDNS_PORT)).ok()?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; 1500];
+    loop {
+        let left = deadline.checked_duration_since(Instant::now())?;
+        socket.set_read_timeout(Some(left)).ok()?;
+        let (len, _) = socket.recv_from(&mut buf).ok()?;
+        if let Some(ip) = parse_answer(&buf[..len], &name) {
+            return Some(ip);
+        }
+    }
+}
+
+pub fn write_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+pub fn header(id: u16, flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(128);
+    for field in [id, flags, questions, answers, 0, 0] {
+        packet.extend_from_slice(&field.to_be_bytes());
+    }
+    packet
+}
+
+fn query(name: &str) -> Vec<u8> {
+    let mut packet = header(0, 0, 1, 0);
+    write_name(&mut packet, name);
+    packet.extend_from_slice(&TYPE_A.to_be_bytes());
+    // the top bit asks for a unicast answer
+    packet.extend_from_slice(&(CLASS_IN | 0x8000).to_be_bytes());
+    packet
+}
+
+fn answer(id: u16, name: &str, ip: Ipv4Addr) -> Vec<u8> {
+    // a response with the authoritative bit, the question is repeated for one shot resolvers
+    let mut packet = header(id, 0x8400, 1, 1);
+    write_name(&mut packet, name);
+    packet.extend_from_slice(&TYPE_A.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    write_name(&mut packet, name);
+    packet.extend_from_slice(&TYPE_A.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet.extend_from_slice(&TTL.to_be_bytes());
+    packet.extend_from_slice(&4u16.to_be_bytes());
+    packet.extend_from_slice(&ip.octets());
+    packet
+}
+
+pub fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        packet.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+// reads the name at offset, following compression pointers, returns it with the offset after it
+pub fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    for _ in 0..32 {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            end.get_or_insert(offset + 2);
+            offset = (read_u16(packet, offset)? & 0x3fff) as usize;
+            continue;
+        }
+        let label = packet.get(offset + 1..offset + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        offset += 1 + len;
+    }
+    None
+}
+
+// the id of the query when it asks for the address of this name
+fn parse_query(packet: &[u8], name: &str) -> Option<u16> {
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 != 0 {
+        return None; // a response
+    }
+    let mut offset = 12;
+    for _ in 0..read_u16(packet, 4)? {
+        let (question, after) = read_name(packet, offset)?;
+        let qtype = read_u16(packet, after)?;
+        if question.eq_ignore_ascii_case(name) && (qtype == TYPE_A || qtype == 255) {
+            return read_u16(packet, 0);
+        }
+        offset = after + 4;
+    }
+    None
+}
+
+fn parse_answer(packet: &[u8], name: &str) -> Option<Ipv4Addr> {
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 == 0 {
+        return None; // a query
+    }
+    let mut offset = 12;
+    for _ in 0..read_u16(packet, 4)? {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+    for _ in 0..read_u16(packet, 6)? {
+        let (answer, after) = read_name(packet, offset)?;
+        let rtype = read_u16(packet, after)?;
+        let rdlength = read_u16(packet, after + 8)? as usize;
+        let rdata = packet.get(after + 10..after + 10 + rdlength)?;
+        if answer.eq_ignore_ascii_case(name) && rtype == TYPE_A && rdlength == 4 {
+            return Some(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]));
+        }
+        offset = after + 10 + rdlength;
+    }
+    None
+}
@@ -0,0 +1,47 @@
+use std::{
+    process::{self, Stdio},
+    thread,
+};
+
+// shows a desktop notification with the platform's own tool: notify-send (libnotify) on linux,
+// osascript on macos and a tray balloon from powershell on windows. nothing happens when the tool is missing
+// or the client was built without the notify feature
+pub fn desktop(title: &str, body: &str) {
+    if !cfg!(feature = "notify") {
+        return;
+    }
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = process::Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification \"{}\" with title \"{}\"",
+            escape_applescript(body),
+            escape_applescript(title)
+        ));
+        command
+    } else if cfg!(windows) {
+        let mut command = process::Command::new("powershell");
+        command
+            .args(["-NoProfile", "-NonInteractive", "-Command"])
+            .arg(format!(
+                "Add-Type -AssemblyName System.Windows.Forms; \
+             $n = New-Object System.Windows.Forms.NotifyIcon; \
+             $n.Icon = [System.Drawing.SystemIcons]::Information; $n.Visible = $true; \
+             $n.ShowBalloonTip(5000, '{}', '{}', 'Info'); Start-Sleep 6; $n.Dispose()",
+                title.replace('\'', "''"),
+                body.replace('\'', "''")
+            ));
+        command
+    } else {
+        let mut command = process::Command::new("notify-send");
+        command.args(["--app-name", "kensa-port-forwarder", title, body]);
+        command
+    };
+    // waited for on the side so it never lingers as a zombie, the windows one stays up for a few seconds
+    if let Ok(mut child) = command.stdout(Stdio::null()).stderr(Stdio::null()).spawn() {
+        thread::spawn(move || child.wait());
+    }
+}
+
+fn escape_applescript(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
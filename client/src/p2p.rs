@@ -0,0 +1,329 @@
+#[cfg(feature = "quic")]
+use crate::quic;
+use crate::relay::{Relay, RelayOutput};
+use socket2::{Domain, Socket, Type};
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+// how long both clients try to reach each other before falling back to the relay
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+const PUNCH_ATTEMPT_TIMEOUT: Duration = Duration::from_millis(500);
+
+// address of the interface used to reach the internet, which is the one on the local network behind a nat
+pub fn local_ip() -> Option<IpAddr> {
+    // connecting an udp socket doesn't send anything, but tells which interface would be used
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:80").ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+// addresses of this machine on a tailnet or another vpn, where a peer on the same one reaches it
+// directly whatever the nats in between. only ipv4, which is what the p2p listener takes
+pub fn vpn_ips() -> Vec<IpAddr> {
+    #[cfg(unix)]
+    {
+        let mut ips = Vec::new();
+        let mut interfaces: *mut libc::ifaddrs = std::ptr::null_mut();
+        if unsafe { libc::getifaddrs(&mut interfaces) } != 0 {
+            return ips;
+        }
+        let mut cursor = interfaces;
+        while !cursor.is_null() {
+            let interface = unsafe { &*cursor };
+            cursor = interface.ifa_next;
+            if interface.ifa_addr.is_null()
+                || i32::from(unsafe { (*interface.ifa_addr).sa_family }) != libc::AF_INET
+            {
+                continue;
+            }
+            let address = unsafe { &*(interface.ifa_addr as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr));
+            let name = unsafe { std::ffi::CStr::from_ptr(interface.ifa_name) }.to_string_lossy();
+            if is_vpn(&name, ip) && !ips.contains(&IpAddr::V4(ip)) {
+                ips.push(IpAddr::V4(ip));
+            }
+        }
+        unsafe { libc::freeifaddrs(interfaces) };
+        ips
+    }
+    #[cfg(not(unix))]
+    {
+        Vec::new()
+    }
+}
+
+// tailscale hands out addresses of 100.64.0.0/10, the other vpns are known by their interface
+#[cfg_attr(not(unix), allow(dead_code))]
+fn is_vpn(interface: &str, ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    let tailnet = a == 100 && b & 0xc0 == 64;
+    let vpn_interface = ["tailscale", "wg", "zt", "tun", "utun"]
+        .iter()
+        .any(|prefix| interface.starts_with(prefix));
+    (tailnet || vpn_interface) && !ip.is_loopback() && !ip.is_link_local()
+}
+
+// a direct connection with the peer, carrying the same streams as the relay
+pub trait DataLink {
+    fn closed(&self) -> bool;
+    fn send(&mut self, frame: &RelayOutput);
+    // exchanges what happened since the last call between the peer and the local sockets of the relay
+    fn flush(&mut self, relay: &mut Relay);
+}
+
+enum Transport {
+    Tcp(TcpListener),
+    #[cfg(feature = "quic")]
+    Quic(Option<UdpSocket>), // handed over to quic once punching starts
+}
+
+#[cfg(feature = "quic")]
+fn quic_transport() -> io::Result<(Transport, u16)> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let port = socket.local_addr()?.port();
+    Ok((Transport::Quic(Some(socket)), port))
+}
+
+#[cfg(not(feature = "quic"))]
+fn quic_transport() -> io::Result<(Transport, u16)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the client was built without the quic feature",
+    ))
+}
+
+// a p2p tunnel which is being negotiated: the relay is ready but nothing flows until the server says which path to use
+pub struct PendingP2p {
+    pub relay: Relay,
+    pub link: Option<Box<dyn DataLink>>,
+    pub over_vpn: bool, // the link goes through a vpn interface of this machine
+    transport: Transport,
+    port: u16,
+    token: String,
+    #[cfg_attr(not(feature = "quic"), allow(dead_code))]
+    initiator: bool, // the side which connects when using quic
+}
+
+impl PendingP2p {
+    pub fn new(relay: Relay, token: String, quic: bool, initiator: bool) -> io::Result<Self> {
+        let (transport, port) = if quic {
+            quic_transport()?
+        } else {
+            let socket = reusable_socket(SocketAddr::from(([0, 0, 0, 0], 0)))?;
+            socket.listen(1)?;
+            let listener: TcpListener = socket.into();
+            listener.set_nonblocking(true)?;
+            let port = listener.local_addr()?.port();
+            (Transport::Tcp(listener), port)
+        };
+        Ok(PendingP2p {
+            relay,
+            link: None,
+            over_vpn: false,
+            transport,
+            port,
+            token,
+            initiator,
+        })
+    }
+
+    // addresses the peer can try to reach us on, the server adds our public address.
+    // the vpn ones come first, they work when both machines are on the same vpn
+    pub fn candidates(&self) -> Vec<String> {
+        let mut ips = vpn_ips();
+        if let Some(local_ip) = local_ip().filter(|ip| !ips.contains(ip)) {
+            ips.push(local_ip);
+        }
+        ips.iter()
+            .map(|ip| format!("{}:{}", ip, self.port))
+            .collect()
+    }
+
+    pub fn punch(&mut self, candidates: &[String]) -> bool {
+        let candidates: Vec<SocketAddr> =
+            candidates.iter().filter_map(|c| c.parse().ok()).collect();
+        match &mut self.transport {
+            Transport::Tcp(listener) => match listener.try_clone() {
+                Ok(listener) => self.punch_tcp(listener, &candidates),
+                Err(_) => false,
+            },
+            #[cfg(feature = "quic")]
+            Transport::Quic(socket) => {
+                let Some(socket) = socket.take() else {
+                    return false;
+                };
+                match quic::connect(
+                    socket,
+                    &candidates,
+                    &self.token,
+                    self.initiator,
+                    PUNCH_TIMEOUT,
+                ) {
+                    Some(link) => {
+                        self.link = Some(Box::new(link));
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+
+    // tries to open a tcp connection with the peer from both sides at the same time, which gets through most nats
+    fn punch_tcp(&mut self, listener: TcpListener, candidates: &[SocketAddr]) -> bool {
+        let deadline = Instant::now() + PUNCH_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Ok((stream, _)) = listener.accept() {
+                if self.authenticate(stream) {
+                    return true;
+                }
+            }
+            for candidate in candidates {
+                let local = SocketAddr::new(
+                    if candidate.is_ipv4() {
+                        [0, 0, 0, 0].into()
+                    } else {
+                        [0u16; 8].into()
+                    },
+                    self.port,
+                );
+                let Ok(socket) = reusable_socket(local) else {
+                    continue;
+                };
+                if socket
+                    .connect_timeout(&(*candidate).into(), PUNCH_ATTEMPT_TIMEOUT)
+                    .is_ok()
+                    && self.authenticate(socket.into())
+                {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // both sides send the token given by the server, so we know we reached the peer and not something else
+    fn authenticate(&mut self, stream: TcpStream) -> bool {
+        let check = || -> io::Result<bool> {
+            stream.set_nonblocking(false)?;
+            stream.set_read_timeout(Some(PUNCH_ATTEMPT_TIMEOUT))?;
+            (&stream).write_all(self.token.as_bytes())?;
+            let mut token = vec![0; self.token.len()];
+            (&stream).read_exact(&mut token)?;
+            stream.set_read_timeout(None)?;
+            Ok(token == self.token.as_bytes())
+        };
+        self.over_vpn = stream
+            .local_addr()
+            .is_ok_and(|local| vpn_ips().contains(&local.ip()));
+        match check() {
+            Ok(true) => match DirectLink::new(stream) {
+                Ok(link) => {
+                    self.link = Some(Box::new(link));
+                    true
+                }
+                Err(_) => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+fn reusable_socket(local: SocketAddr) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(local), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&local.into())?;
+    Ok(socket)
+}
+
+// a direct tcp connection with the peer, every stream is multiplexed over it
+pub struct DirectLink {
+    stream: TcpStream,
+    frames: Receiver<RelayOutput>,
+    closed: bool,
+}
+
+impl DirectLink {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        let (sender, frames) = mpsc::channel();
+        let mut reader = BufReader::new(stream.try_clone()?);
+        thread::spawn(move || {
+            while let Ok(frame) = read_frame(&mut reader) {
+                if sender.send(frame).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(DirectLink {
+            stream,
+            frames,
+            closed: false,
+        })
+    }
+}
+
+impl DataLink for DirectLink {
+    fn closed(&self) -> bool {
+        self.closed
+    }
+
+    fn send(&mut self, frame: &RelayOutput) {
+        let (kind, stream, data): (u8, u32, &[u8]) = match frame {
+            RelayOutput::Open(stream) => (0, *stream, &[]),
+            RelayOutput::Data(stream, data) => (1, *stream, data),
+            RelayOutput::Close(stream) => (2, *stream, &[]),
+        };
+        let mut buf = Vec::with_capacity(9 + data.len());
+        buf.push(kind);
+        buf.extend_from_slice(&stream.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        if self.stream.write_all(&buf).is_err() {
+            self.closed = true;
+        }
+    }
+
+    fn flush(&mut self, relay: &mut Relay) {
+        loop {
+            match self.frames.try_recv() {
+                Ok(RelayOutput::Open(stream)) => {
+                    if relay.open(stream).is_err() {
+                        self.send(&RelayOutput::Close(stream));
+                    }
+                }
+                Ok(RelayOutput::Data(stream, data)) => relay.write(stream, &data),
+                Ok(RelayOutput::Close(stream)) => relay.close(stream),
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+        for output in relay.poll() {
+            self.send(&output);
+        }
+    }
+}
+
+fn read_frame(reader: &mut impl Read) -> io::Result<RelayOutput> {
+    let mut header = [0; 9];
+    reader.read_exact(&mut header)?;
+    let stream = u32::from_be_bytes(header[1..5].try_into().unwrap());
+    let len = u32::from_be_bytes(header[5..9].try_into().unwrap());
+    let mut data = vec![0; len as usize];
+    reader.read_exact(&mut data)?;
+    match header[0] {
+        0 => Ok(RelayOutput::Open(stream)),
+        1 => Ok(RelayOutput::Data(stream, data)),
+        2 => Ok(RelayOutput::Close(stream)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown frame")),
+    }
+}
@@ -0,0 +1,120 @@
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+// what to do with a connection request
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Accept,
+    Deny,
+    Prompt,
+}
+
+// a single port or an inclusive range written "8000-8100"
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum PortSpec {
+    Port(u16),
+    Range(String),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    action: Action,
+    uuids: Option<Vec<String>>,    // every receiver when missing
+    accounts: Option<Vec<String>>, // every receiver when missing, needs a server with logins
+    ports: Option<Vec<PortSpec>>,  // every port when missing
+}
+
+// rules deciding the connection requests of a host without asking, read from accept.toml:
+//
+//   default = "prompt"
+//
+//   [[rule]]
+//   action = "accept"
+//   uuids = ["4b0c...", "9e1f..."]
+//   ports = ["8000-8100", 3000]
+//
+//   [[rule]]
+//   action = "accept"
+//   accounts = ["alice", "bob"]
+//
+//   [[rule]]
+//   action = "deny"
+//   ports = [22]
+//
+// the first rule matching both the receiver and the port decides, the default applies otherwise.
+// accounts are the names receivers logged in to the server with, they stay the same when a receiver
+// reinstalls and gets a new uuid, receivers which didn't log in never match them
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default = "default_action")]
+    default: Action,
+    #[serde(default, rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+fn default_action() -> Action {
+    Action::Prompt
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+        let policy: Policy = toml::from_str(&content)
+            .map_err(|err| format!("invalid policy {}: {}", path.display(), err))?;
+        for spec in policy.rules.iter().flat_map(|r| r.ports.iter().flatten()) {
+            if let PortSpec::Range(range) = spec {
+                parse_range(range).ok_or_else(|| {
+                    format!(
+                        "invalid port range \"{}\" in {}, expected like 8000-8100",
+                        range,
+                        path.display()
+                    )
+                })?;
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn evaluate(&self, uuid: &str, account: Option<&str>, port: u16) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| {
+                let uuid_matches = rule
+                    .uuids
+                    .as_ref()
+                    .is_none_or(|uuids| uuids.iter().any(|u| u == uuid));
+                let account_matches = rule.accounts.as_ref().is_none_or(|accounts| {
+                    account.is_some_and(|account| accounts.iter().any(|a| a == account))
+                });
+                let port_matches = rule
+                    .ports
+                    .as_ref()
+                    .is_none_or(|ports| ports.iter().any(|spec| spec.contains(port)));
+                uuid_matches && account_matches && port_matches
+            })
+            .map_or(self.default, |rule| rule.action)
+    }
+}
+
+impl PortSpec {
+    fn contains(&self, port: u16) -> bool {
+        match self {
+            PortSpec::Port(p) => *p == port,
+            PortSpec::Range(range) => {
+                parse_range(range).is_some_and(|(start, end)| (start..=end).contains(&port))
+            }
+        }
+    }
+}
+
+fn parse_range(range: &str) -> Option<(u16, u16)> {
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let start = start.trim().parse().ok()?;
+    let end = end.trim().parse().ok()?;
+    (start <= end).then_some((start, end))
+}
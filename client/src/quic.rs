@@ -0,0 +1,348 @@
+use crate::p2p::DataLink;
+use crate::relay::{Relay, RelayOutput};
+use quinn::{
+    crypto::rustls::QuicClientConfig,
+    rustls::{
+        self,
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+        DigitallySignedStruct, SignatureScheme,
+    },
+    ClientConfig, Connection, Endpoint, EndpointConfig, RecvStream, SendStream, ServerConfig,
+    TokioRuntime, TransportConfig,
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::{
+    runtime::Runtime,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+
+const SERVER_NAME: &str = "kensa-port-forwarder";
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+type Writers = Arc<Mutex<HashMap<u32, UnboundedSender<Vec<u8>>>>>;
+
+// sends a few datagrams to every candidate so our nat lets the peer's packets in, then opens a quic connection with it.
+// the receiver connects and the host accepts, both sides then check the token given by the server
+pub fn connect(
+    socket: UdpSocket,
+    candidates: &[SocketAddr],
+    token: &str,
+    initiator: bool,
+    timeout: Duration,
+) -> Option<QuicLink> {
+    for _ in 0..3 {
+        for candidate in candidates {
+            let _ = socket.send_to(b"punch", candidate);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .ok()?;
+    let (endpoint, connection) = runtime.block_on(async {
+        tokio::time::timeout(timeout, handshake(socket, candidates, token, initiator))
+            .await
+            .ok()?
+            .ok()
+    })?;
+    Some(QuicLink::new(runtime, endpoint, connection))
+}
+
+async fn handshake(
+    socket: UdpSocket,
+    candidates: &[SocketAddr],
+    token: &str,
+    initiator: bool,
+) -> Result<(Endpoint, Connection), Box<dyn Error + Send + Sync>> {
+    let mut endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        Some(server_config()?),
+        socket,
+        Arc::new(TokioRuntime),
+    )?;
+    endpoint.set_default_client_config(client_config()?);
+
+    if initiator {
+        // every candidate is tried at the same time, the first one to answer wins
+        let (sender, mut results) = unbounded_channel();
+        for candidate in candidates {
+            let connecting = endpoint.connect(*candidate, SERVER_NAME)?;
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                if let Ok(connection) = connecting.await {
+                    let _ = sender.send(connection);
+                }
+            });
+        }
+        drop(sender);
+        while let Some(connection) = results.recv().await {
+            let check = async {
+                let (mut send, mut recv) = connection.open_bi().await?;
+                send.write_all(token.as_bytes()).await?;
+                let mut answer = vec![0; token.len()];
+                recv.read_exact(&mut answer).await?;
+                Ok::<_, Box<dyn Error + Send + Sync>>(answer == token.as_bytes())
+            };
+            if let Ok(true) = check.await {
+                return Ok((endpoint, connection));
+            }
+            connection.close(0u32.into(), b"invalid token");
+        }
+    } else {
+        while let Some(incoming) = endpoint.accept().await {
+            let Ok(connection) = incoming.await else {
+                continue;
+            };
+            let check = async {
+                let (mut send, mut recv) = connection.accept_bi().await?;
+                let mut answer = vec![0; token.len()];
+                recv.read_exact(&mut answer).await?;
+                if answer != token.as_bytes() {
+                    return Ok(false);
+                }
+                send.write_all(token.as_bytes()).await?;
+                Ok::<_, Box<dyn Error + Send + Sync>>(true)
+            };
+            if let Ok(true) = check.await {
+                return Ok((endpoint, connection));
+            }
+            connection.close(0u32.into(), b"invalid token");
+        }
+    }
+    Err("could not reach the peer".into())
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    // keeps the nat mappings open on both sides
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    Arc::new(transport)
+}
+
+// the certificate is thrown away after the tunnel, the peer is authenticated with the token instead
+fn server_config() -> Result<ServerConfig, Box<dyn Error + Send + Sync>> {
+    let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+    let cert = CertificateDer::from(certified.cert);
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+    let mut config = ServerConfig::with_single_cert(vec![cert], key)?;
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+fn client_config() -> Result<ClientConfig, Box<dyn Error + Send + Sync>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    let mut config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// a quic connection with the peer, every stream of the relay gets its own quic stream
+// so a lost packet only holds back the connection it belongs to
+pub struct QuicLink {
+    runtime: Runtime,
+    _endpoint: Endpoint,
+    connection: Connection,
+    frames: Receiver<RelayOutput>,
+    frame_sender: Sender<RelayOutput>,
+    writers: Writers,
+}
+
+impl QuicLink {
+    fn new(runtime: Runtime, endpoint: Endpoint, connection: Connection) -> Self {
+        let (frame_sender, frames) = mpsc::channel();
+        let writers: Writers = Arc::default();
+
+        // streams opened by the peer start with their id
+        let accept_connection = connection.clone();
+        let accept_frames = frame_sender.clone();
+        let accept_writers = writers.clone();
+        runtime.spawn(async move {
+            while let Ok((send, mut recv)) = accept_connection.accept_bi().await {
+                let mut id = [0; 4];
+                if recv.read_exact(&mut id).await.is_err() {
+                    continue;
+                }
+                let stream = u32::from_be_bytes(id);
+                let (writer, data) = unbounded_channel();
+                accept_writers.lock().unwrap().insert(stream, writer);
+                if accept_frames.send(RelayOutput::Open(stream)).is_err() {
+                    break;
+                }
+                tokio::spawn(read_stream(
+                    stream,
+                    recv,
+                    accept_frames.clone(),
+                    accept_writers.clone(),
+                ));
+                tokio::spawn(write_stream(send, data));
+            }
+        });
+
+        QuicLink {
+            runtime,
+            _endpoint: endpoint,
+            connection,
+            frames,
+            frame_sender,
+            writers,
+        }
+    }
+
+    fn open(&mut self, stream: u32) {
+        let (writer, data) = unbounded_channel();
+        self.writers.lock().unwrap().insert(stream, writer);
+        let connection = self.connection.clone();
+        let frames = self.frame_sender.clone();
+        let writers = self.writers.clone();
+        self.runtime.spawn(async move {
+            let opened = async {
+                let (mut send, recv) = connection.open_bi().await?;
+                send.write_all(&stream.to_be_bytes()).await?;
+                Ok::<_, Box<dyn Error + Send + Sync>>((send, recv))
+            };
+            match opened.await {
+                Ok((send, recv)) => {
+                    tokio::spawn(read_stream(stream, recv, frames, writers));
+                    write_stream(send, data).await;
+                }
+                Err(_) => {
+                    writers.lock().unwrap().remove(&stream);
+                    let _ = frames.send(RelayOutput::Close(stream));
+                }
+            }
+        });
+    }
+}
+
+impl DataLink for QuicLink {
+    fn closed(&self) -> bool {
+        self.connection.close_reason().is_some()
+    }
+
+    fn send(&mut self, frame: &RelayOutput) {
+        match frame {
+            RelayOutput::Open(stream) => self.open(*stream),
+            RelayOutput::Data(stream, data) => {
+                if let Some(writer) = self.writers.lock().unwrap().get(stream) {
+                    let _ = writer.send(data.clone());
+                }
+            }
+            // dropping the writer finishes the quic stream
+            RelayOutput::Close(stream) => {
+                self.writers.lock().unwrap().remove(stream);
+            }
+        }
+    }
+
+    fn flush(&mut self, relay: &mut Relay) {
+        while let Ok(frame) = self.frames.try_recv() {
+            match frame {
+                RelayOutput::Open(stream) => {
+                    if relay.open(stream).is_err() {
+                        self.send(&RelayOutput::Close(stream));
+                    }
+                }
+                RelayOutput::Data(stream, data) => relay.write(stream, &data),
+                RelayOutput::Close(stream) => relay.close(stream),
+            }
+        }
+        for output in relay.poll() {
+            self.send(&output);
+        }
+    }
+}
+
+async fn read_stream(
+    stream: u32,
+    mut recv: RecvStream,
+    frames: Sender<RelayOutput>,
+    writers: Writers,
+) {
+    let mut buf = [0; 16 * 1024];
+    while let Ok(Some(n)) = recv.read(&mut buf).await {
+        if frames
+            .send(RelayOutput::Data(stream, buf[..n].to_vec()))
+            .is_err()
+        {
+            return;
+        }
+    }
+    // the peer is done with this stream, finish ours too
+    writers.lock().unwrap().remove(&stream);
+    let _ = frames.send(RelayOutput::Close(stream));
+}
+
+async fn write_stream(mut send: SendStream, mut data: UnboundedReceiver<Vec<u8>>) {
+    while let Some(chunk) = data.recv().await {
+        if send.write_all(&chunk).await.is_err() {
+            return;
+        }
+    }
+    let _ = send.finish();
+}
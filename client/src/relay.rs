@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+// sent by the threads reading the local tcp sockets
+enum RelayEvent {
+    Accepted(TcpStream),
+    Data(u32, Vec<u8>),
+    Closed(u32),
+}
+
+// what the relay needs to send to the peer through the server
+pub enum RelayOutput {
+    Open(u32),
+    Data(u32, Vec<u8>),
+    Close(u32),
+}
+
+// forwards tcp streams through the websocket instead of ssh,
+// every tcp connection accepted by the receiver becomes a stream identified by an id
+pub struct Relay {
+    target: Option<String>, // only set for the host, HOST:PORT which streams are opened to
+    streams: HashMap<u32, TcpStream>,
+    events: Receiver<RelayEvent>,
+    sender: Sender<RelayEvent>,
+    next_stream: u32,
+    bytes_sent: u64,     // read from the local sockets and sent to the peer
+    bytes_received: u64, // received from the peer and written to the local sockets
+}
+
+impl Relay {
+    pub fn host(target: String) -> Self {
+        let (sender, events) = mpsc::channel();
+        Relay {
+            target: Some(target),
+            streams: HashMap::new(),
+            events,
+            sender,
+            next_stream: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+        }
+    }
+
+    pub fn receiver(local_port: u16) -> io::Result<Self> {
+        let (sender, events) = mpsc::channel();
+        let listener = TcpListener::bind(("127.0.0.1", local_port))?;
+        let accept_sender = sender.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if accept_sender.send(RelayEvent::Accepted(stream)).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Relay {
+            target: None,
+            streams: HashMap::new(),
+            events,
+            sender,
+            next_stream: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+        })
+    }
+
+    // called on the host when the receiver accepted a new connection
+    pub fn open(&mut self, stream: u32) -> io::Result<()> {
+        let target = self
+            .target
+            .as_deref()
+            .expect("only the host can open streams");
+        let tcp = TcpStream::connect(target)?;
+        self.add_stream(stream, tcp)
+    }
+
+    pub fn write(&mut self, stream: u32, data: &[u8]) {
+        if let Some(tcp) = self.streams.get_mut(&stream) {
+            self.bytes_received += data.len() as u64;
+            if tcp.write_all(data).is_err() {
+                self.close(stream);
+            }
+        }
+    }
+
+    pub fn close(&mut self, stream: u32) {
+        if let Some(tcp) = self.streams.remove(&stream) {
+            let _ = tcp.shutdown(Shutdown::Both);
+        }
+    }
+
+    // drains what happened on the local sockets since the last call
+    pub fn poll(&mut self) -> Vec<RelayOutput> {
+        let mut output = Vec::new();
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                RelayEvent::Accepted(tcp) => {
+                    let stream = self.next_stream;
+                    self.next_stream += 1;
+                    if self.add_stream(stream, tcp).is_ok() {
+                        output.push(RelayOutput::Open(stream));
+                    }
+                }
+                RelayEvent::Data(stream, data) if self.streams.contains_key(&stream) => {
+                    self.bytes_sent += data.len() as u64;
+                    output.push(RelayOutput::Data(stream, data));
+                }
+                RelayEvent::Closed(stream) if self.streams.contains_key(&stream) => {
+                    self.close(stream);
+                    output.push(RelayOutput::Close(stream));
+                }
+                _ => {}
+            }
+        }
+        output
+    }
+
+    // bytes sent to and received from the peer
+    pub fn traffic(&self) -> (u64, u64) {
+        (self.bytes_sent, self.bytes_received)
+    }
+
+    pub fn connections(&self) -> usize {
+        self.streams.len()
+    }
+
+    fn add_stream(&mut self, stream: u32, tcp: TcpStream) -> io::Result<()> {
+        let mut reader = tcp.try_clone()?;
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 16 * 1024];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => {
+                        let _ = sender.send(RelayEvent::Closed(stream));
+                        break;
+                    }
+                    Ok(n) => {
+                        if sender
+                            .send(RelayEvent::Data(stream, buf[..n].to_vec()))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        self.streams.insert(stream, tcp);
+        Ok(())
+    }
+}
+
+// binary websocket frames carry the stream id followed by the data
+pub fn encode_frame(stream: u32, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&stream.to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+pub fn decode_frame(frame: &[u8]) -> Option<(u32, &[u8])> {
+    if frame.len() < 4 {
+        return None;
+    }
+    let (id, data) = frame.split_at(4);
+    Some((u32::from_be_bytes(id.try_into().ok()?), data))
+}
@@ -0,0 +1,137 @@
+use crate::{history::now, policy::Action};
+use serde::Serialize;
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+// a script doing external lookups must still answer before the receiver gives up
+const TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// past requests sent to the script, the latest ones
+const HISTORY_LIMIT: usize = 100;
+
+#[derive(Serialize)]
+struct Request<'a> {
+    uuid: &'a str,
+    account: Option<&'a str>,
+    port: u16,
+    time: u64, // unix timestamp
+    history: Vec<&'a Past>,
+}
+
+#[derive(Serialize)]
+struct Past {
+    #[serde(skip)]
+    uuid: String,
+    port: u16,
+    time: u64,
+    accepted: bool,
+}
+
+// a program deciding the connection requests of a host, run for each one with the request as json on stdin:
+//
+//   {"uuid": "4b0c...", "account": "alice", "port": 8080, "time": 1715954580,
+//    "history": [{"port": 8080, "time": 1715950000, "accepted": true}]}
+//
+// account is null when the receiver did not log in, history is the last 100 past requests of the same
+// receiver since the host started. the script prints accept, deny or prompt; anything else, failing or taking
+// longer than 10s denies the request. any executable works, like a shell script starting with #!
+pub struct AcceptScript {
+    path: PathBuf,
+    history: Vec<Past>,
+}
+
+impl AcceptScript {
+    pub fn new(path: PathBuf) -> Self {
+        AcceptScript {
+            path,
+            history: Vec::new(),
+        }
+    }
+
+    pub fn evaluate(&self, uuid: &str, account: Option<&str>, port: u16) -> Action {
+        let mut history: Vec<&Past> = self.history.iter().filter(|p| p.uuid == uuid).collect();
+        history.drain(..history.len().saturating_sub(HISTORY_LIMIT));
+        let request = Request {
+            uuid,
+            account,
+            port,
+            time: now(),
+            history,
+        };
+        let input = serde_json::to_vec(&request).expect("failed to stringify request");
+        match self.run(&input) {
+            Ok(action) => action,
+            Err(err) => {
+                eprintln!("accept script {}: {}", self.path.display(), err);
+                Action::Deny
+            }
+        }
+    }
+
+    // remembers how a request ended, whatever decided it
+    pub fn record(&mut self, uuid: &str, port: u16, accepted: bool) {
+        self.history.push(Past {
+            uuid: uuid.to_string(),
+            port,
+            time: now(),
+            accepted,
+        });
+    }
+
+    fn run(&self, input: &[u8]) -> Result<Action, String> {
+        let mut child = Command::new(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("failed to run: {}", err))?;
+        // on their own threads, a script which doesn't read its input or prints a lot must not block the host.
+        // not reading the input must not fail the request either
+        let mut stdin = child.stdin.take().unwrap();
+        let input = input.to_vec();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+        let mut stdout = child.stdout.take().unwrap();
+        let (sender, answer) = mpsc::channel();
+        thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stdout.read_to_string(&mut output);
+            let _ = sender.send(output);
+        });
+        let started = Instant::now();
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if started.elapsed() < TIMEOUT => thread::sleep(POLL_INTERVAL),
+                Ok(None) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(format!("no answer after {}s", TIMEOUT.as_secs()));
+                }
+                Err(err) => return Err(err.to_string()),
+            }
+        };
+        if !status.success() {
+            return Err(format!("failed with {}", status));
+        }
+        // a process the script left in the background may keep its output open
+        let output = answer
+            .recv_timeout(TIMEOUT.saturating_sub(started.elapsed()).max(POLL_INTERVAL))
+            .map_err(|_| "its output was not closed".to_string())?;
+        match output.trim() {
+            "accept" => Ok(Action::Accept),
+            "deny" => Ok(Action::Deny),
+            "prompt" => Ok(Action::Prompt),
+            other => Err(format!(
+                "answered \"{}\", expected accept, deny or prompt",
+                other
+            )),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::{fs, time::Duration};
+
+// how long ssh may take to log in with a key of a security key: the user has to touch it, maybe type its pin
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(60);
+// the same without one, it only waits for the server
+const KEY_LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// a fido2 key (sk-ssh-ed25519@openssh.com, sk-ecdsa-sha2-nistp256@openssh.com), told by its .pub since
+// the private key file only holds a handle of the key kept on the security key
+pub fn is_security_key(ssh_key_path: &str) -> bool {
+    fs::read_to_string(format!("{}.pub", ssh_key_path))
+        .is_ok_and(|content| content.trim_start().starts_with("sk-"))
+}
+
+pub fn login_timeout(ssh_key_path: &str) -> Duration {
+    if is_security_key(ssh_key_path) {
+        LOGIN_TIMEOUT
+    } else {
+        KEY_LOGIN_TIMEOUT
+    }
+}
+
+// said before spawning ssh, which then asks for the touch (and the pin of the keys needing one) on the terminal
+pub fn announce(ssh_key_path: &str) {
+    println!(
+        "touch your security key when it blinks to open the tunnel with {}",
+        ssh_key_path
+    );
+}
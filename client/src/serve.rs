@@ -0,0 +1,241 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+// a client which stops sending its request is dropped after this
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+// what is escaped in the links of a listing, the path separators stay
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+// the static http server of the serve command, on localhost only since the host forwards it:
+// a folder is browsed with listings (or its index.html), a single file is served at /
+pub struct FileServer {
+    root: PathBuf,
+}
+
+impl FileServer {
+    pub fn new(root: PathBuf) -> Result<Self, String> {
+        let root = root
+            .canonicalize()
+            .map_err(|err| format!("can not serve {}: {}", root.display(), err))?;
+        Ok(FileServer { root })
+    }
+
+    // listens on a free port on its own threads, returns the port
+    pub fn start(self) -> io::Result<u16> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        let port = listener.local_addr()?.port();
+        let root = self.root;
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let root = root.clone();
+                thread::spawn(move || {
+                    let _ = serve(&root, stream);
+                });
+            }
+        });
+        Ok(port)
+    }
+}
+
+fn serve(root: &Path, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let Some(head) = read_head(&mut stream)? else {
+        return Ok(());
+    };
+    let mut parts = head.lines().next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return respond(&mut stream, "400 Bad Request", "", b"");
+    };
+    let head_only = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            return respond(
+                &mut stream,
+                "405 Method Not Allowed",
+                "Allow: GET, HEAD\r\n",
+                b"",
+            )
+        }
+    };
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    let Some(relative) = decode_path(path) else {
+        return respond(&mut stream, "404 Not Found", "", b"not found");
+    };
+
+    let mut file = if root.is_file() {
+        if !relative.as_os_str().is_empty() {
+            return respond(&mut stream, "404 Not Found", "", b"not found");
+        }
+        root.to_path_buf()
+    } else {
+        root.join(&relative)
+    };
+    if file.is_dir() {
+        // relative links of the listing need the trailing slash
+        if !path.ends_with('/') {
+            let location = format!("Location: {}/\r\n", path);
+            return respond(&mut stream, "301 Moved Permanently", &location, b"");
+        }
+        if file.join("index.html").is_file() {
+            file = file.join("index.html");
+        } else {
+            let listing = listing(&file, path)?;
+            let headers = "Content-Type: text/html; charset=utf-8\r\n";
+            let body = if head_only {
+                &[][..]
+            } else {
+                listing.as_bytes()
+            };
+            return send(&mut stream, headers, listing.len() as u64, body);
+        }
+    }
+    let Ok(mut content) = File::open(&file) else {
+        return respond(&mut stream, "404 Not Found", "", b"not found");
+    };
+    let length = content.metadata()?.len();
+    let mut headers = format!("Content-Type: {}\r\n", content_type(&file));
+    if root.is_file() {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        headers.push_str(&format!(
+            "Content-Disposition: attachment; filename=\"{}\"\r\n",
+            name.replace('"', "")
+        ));
+    }
+    send(&mut stream, &headers, length, b"")?;
+    if !head_only {
+        io::copy(&mut content, &mut stream)?;
+    }
+    Ok(())
+}
+
+// the path of the request relative to the root, none when it tries to get out of it
+fn decode_path(path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    let relative = PathBuf::from(decoded.trim_start_matches('/'));
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        .then_some(relative)
+}
+
+fn listing(folder: &Path, path: &str) -> io::Result<String> {
+    let mut entries: Vec<(String, bool)> = fs::read_dir(folder)?
+        .flatten()
+        .map(|entry| {
+            let is_dir = entry.path().is_dir();
+            (entry.file_name().to_string_lossy().into_owned(), is_dir)
+        })
+        .collect();
+    // folders first
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let title = escape(&percent_decode_str(path).decode_utf8_lossy());
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title></head><body>\n<h1>{}</h1>\n<ul>\n",
+        title, title
+    );
+    if path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        html.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            utf8_percent_encode(&name, PATH_SEGMENT),
+            slash,
+            escape(&name),
+            slash
+        ));
+    }
+    html.push_str("</ul>\n</body></html>\n");
+    Ok(html)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("txt" | "md" | "log") => "text/plain; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("pdf") => "application/pdf",
+        Some("mp4") => "video/mp4",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+// the head of the request, none when the client closed the connection before sending one
+fn read_head(stream: &mut impl Read) -> io::Result<Option<String>> {
+    let mut read = Vec::new();
+    let mut buffer = [0; 4096];
+    while !read.windows(4).any(|w| w == b"\r\n\r\n") {
+        if read.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "request too large"));
+        }
+        let n = stream.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        read.extend_from_slice(&buffer[..n]);
+    }
+    Ok(Some(String::from_utf8_lossy(&read).into_owned()))
+}
+
+// the head of a 200 answer, with the body when it is already in memory
+fn send(stream: &mut impl Write, headers: &str, length: u64, body: &[u8]) -> io::Result<()> {
+    stream.write_all(
+        format!(
+            "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            headers, length
+        )
+        .as_bytes(),
+    )?;
+    stream.write_all(body)
+}
+
+// headers are whole lines, ending with \r\n
+fn respond(stream: &mut impl Write, status: &str, headers: &str, body: &[u8]) -> io::Result<()> {
+    stream.write_all(
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            headers,
+            body.len()
+        )
+        .as_bytes(),
+    )?;
+    stream.write_all(body)
+}
@@ -0,0 +1,238 @@
+use crate::{http, server_stream, tls, IpFamily};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::Deserialize;
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    time::Duration,
+};
+use tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    stream::MaybeTlsStream,
+    Message,
+};
+use url::Url;
+
+const POST_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+struct Close {
+    code: u16,
+    reason: String,
+}
+
+// the connection to the server when a proxy in between kills websockets: the server's messages
+// come as server-sent events of GET /sse, each of ours is a POST /sse/<session>.
+// only the server's answers to the posts tell them apart from any other request
+pub struct SseSocket {
+    url: Url, // http:// or https:// of /sse, under the path of the server url
+    ip_family: Option<IpFamily>,
+    events: MaybeTlsStream<TcpStream>,
+    buffer: Vec<u8>, // read from the events and not parsed yet
+    session: String,
+    posts: Option<MaybeTlsStream<TcpStream>>, // kept alive between the messages
+}
+
+// the same errors as the websocket's, for the socket_* functions of main
+#[allow(clippy::result_large_err)]
+impl SseSocket {
+    pub fn open(server_url: &str, ip_family: Option<IpFamily>) -> Result<Self, String> {
+        let url = http::server_endpoint(server_url, "/sse")?;
+        let mut socket = SseSocket {
+            events: connect(&url, ip_family)?,
+            url,
+            ip_family,
+            buffer: Vec::new(),
+            session: String::new(),
+            posts: None,
+        };
+        // 1.0 so the stream is not chunked, it ends with the connection
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\n{}Accept: text/event-stream\r\nCache-Control: no-cache\r\n\r\n",
+            socket.url.path(),
+            socket.host(),
+            auth_header()
+        );
+        let failed =
+            |err: &dyn std::fmt::Display| format!("the server-sent events failed: {}", err);
+        socket
+            .events
+            .write_all(request.as_bytes())
+            .map_err(|err| failed(&err))?;
+        let status =
+            read_head(&mut socket.events, &mut socket.buffer).map_err(|err| failed(&err))?;
+        if status != 200 {
+            return Err(format!(
+                "the server answered {} to the server-sent events",
+                status
+            ));
+        }
+        match socket.read_event().map_err(|err| failed(&err))? {
+            (event, session) if event == "session" => socket.session = session,
+            _ => return Err("the server did not open a session".to_string()),
+        }
+        Ok(socket)
+    }
+
+    // blocks until the server sends something, or the read timeout of the events stream
+    pub fn read(&mut self) -> tungstenite::Result<Message> {
+        loop {
+            let (event, data) = self.read_event()?;
+            match event.as_str() {
+                "message" => return Ok(Message::text(data)),
+                "binary" => {
+                    let data = BASE64_STANDARD
+                        .decode(data)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    return Ok(Message::binary(data));
+                }
+                "close" => {
+                    let close: Close = serde_json::from_str(&data)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    return Ok(Message::Close(Some(CloseFrame {
+                        code: CloseCode::from(close.code),
+                        reason: close.reason.into(),
+                    })));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: Message) -> tungstenite::Result<()> {
+        let (content_type, body) = match message {
+            Message::Text(text) => ("application/json", text.into_bytes()),
+            Message::Binary(data) => ("application/octet-stream", data),
+            _ => return Ok(()),
+        };
+        // a proxy may have closed the connection since the last message, it is opened again once
+        let reused = self.posts.is_some();
+        match self.post(content_type, &body) {
+            Err(_) if reused => {
+                self.posts = None;
+                self.post(content_type, &body)
+            }
+            result => result,
+        }
+        .map_err(|err| {
+            self.posts = None;
+            tungstenite::Error::Io(err)
+        })
+    }
+
+    // ends the session, the server sees its events stream closed
+    pub fn close(&mut self) -> tungstenite::Result<()> {
+        if let Some(stream) = tls::tcp_stream(&mut self.events) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        Ok(())
+    }
+
+    pub fn stream(&self) -> &MaybeTlsStream<TcpStream> {
+        &self.events
+    }
+
+    pub fn stream_mut(&mut self) -> &mut MaybeTlsStream<TcpStream> {
+        &mut self.events
+    }
+
+    fn post(&mut self, content_type: &str, body: &[u8]) -> io::Result<()> {
+        if self.posts.is_none() {
+            let mut stream = connect(&self.url, self.ip_family).map_err(io::Error::other)?;
+            if let Some(tcp) = tls::tcp_stream(&mut stream) {
+                tcp.set_read_timeout(Some(POST_TIMEOUT))?;
+            }
+            self.posts = Some(stream);
+        }
+        let request = format!(
+            "POST {}/{} HTTP/1.1\r\nHost: {}\r\n{}Content-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            self.url.path(),
+            self.session,
+            self.host(),
+            auth_header(),
+            content_type,
+            body.len()
+        );
+        let stream = self.posts.as_mut().unwrap();
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(body)?;
+        // nothing follows the 204, the buffer never holds more than the head
+        let status = read_head(stream, &mut Vec::new())?;
+        if status != 204 {
+            return Err(io::Error::other(format!(
+                "the server answered {} to a message",
+                status
+            )));
+        }
+        Ok(())
+    }
+
+    fn host(&self) -> String {
+        match self.url.port() {
+            Some(port) => format!("{}:{}", self.url.host_str().unwrap_or_default(), port),
+            None => self.url.host_str().unwrap_or_default().to_string(),
+        }
+    }
+
+    // the next event, the comments keeping the stream alive are skipped
+    fn read_event(&mut self) -> io::Result<(String, String)> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+                let block: Vec<u8> = self.buffer.drain(..end + 2).collect();
+                let (mut event, mut data) = (String::new(), Vec::new());
+                for line in String::from_utf8_lossy(&block).lines() {
+                    match line.split_once(':') {
+                        Some(("event", value)) => event = value.trim_start().to_string(),
+                        Some(("data", value)) => data.push(value.trim_start().to_string()),
+                        _ => {}
+                    }
+                }
+                if !event.is_empty() {
+                    return Ok((event, data.join("\n")));
+                }
+                continue;
+            }
+            read_more(&mut self.events, &mut self.buffer)?;
+        }
+    }
+}
+
+// the credentials of the proxy in front of the server, if any
+fn auth_header() -> String {
+    http::server_auth()
+        .map(|auth| format!("Authorization: {}\r\n", auth))
+        .unwrap_or_default()
+}
+
+fn connect(url: &Url, ip_family: Option<IpFamily>) -> Result<MaybeTlsStream<TcpStream>, String> {
+    let stream = server_stream(url, ip_family)?;
+    if url.scheme() != "https" {
+        return Ok(MaybeTlsStream::Plain(stream));
+    }
+    tls::tls_stream(url.host_str().unwrap_or_default(), stream)
+}
+
+// reads the head of an answer, returns its status and leaves what follows it in the buffer
+fn read_head(stream: &mut impl Read, buffer: &mut Vec<u8>) -> io::Result<u16> {
+    loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head: Vec<u8> = buffer.drain(..end + 4).collect();
+            return String::from_utf8_lossy(&head)
+                .split_whitespace()
+                .nth(1)
+                .and_then(|status| status.parse().ok())
+                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "invalid answer"));
+        }
+        read_more(stream, buffer)?;
+    }
+}
+
+fn read_more(stream: &mut impl Read, buffer: &mut Vec<u8>) -> io::Result<()> {
+    let mut chunk = [0; 16 * 1024];
+    let read = stream.read(&mut chunk)?;
+    if read == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    buffer.extend_from_slice(&chunk[..read]);
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use crate::history::now;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::{collections::BTreeMap, fs, path::Path, process, sync::OnceLock};
+
+const USER_CERTIFICATE: u32 = 1;
+
+// the path and content of the certificate of each ssh key having one, set once the keys are known
+static CERTIFICATES: OnceLock<BTreeMap<String, (String, String)>> = OnceLock::new();
+
+// --ssh-certificate for the key it certifies, the <key>-cert.pub ssh itself would use for the others.
+// the one given has to be valid for one of the keys, the default ones are skipped with a warning otherwise
+pub fn load(given: Option<String>, ssh_keys: &[String]) {
+    let mut certificates = BTreeMap::new();
+    let mut given_error = None;
+    for ssh_key_path in ssh_keys {
+        if let Some(path) = &given {
+            match check(path, ssh_key_path) {
+                Ok(content) => {
+                    certificates.insert(ssh_key_path.clone(), (path.clone(), content));
+                    continue;
+                }
+                Err(err) => {
+                    given_error.get_or_insert(err);
+                }
+            }
+        }
+        let path = format!("{}-cert.pub", ssh_key_path);
+        if !Path::new(&path).exists() {
+            continue;
+        }
+        match check(&path, ssh_key_path) {
+            Ok(content) => {
+                certificates.insert(ssh_key_path.clone(), (path, content));
+            }
+            Err(err) => eprintln!("{}, it is not used", err),
+        }
+    }
+    if let Some(path) = given.filter(|path| !certificates.values().any(|(p, _)| p == path)) {
+        match given_error {
+            Some(err) if ssh_keys.len() == 1 => eprintln!("{}", err),
+            _ => eprintln!(
+                "the ssh certificate {} is not valid for any of the ssh keys",
+                path
+            ),
+        }
+        process::exit(1);
+    }
+    let _ = CERTIFICATES.set(certificates);
+}
+
+// sent when registering with the key, for the servers trusting its authority
+pub fn certificate(ssh_key_path: &str) -> Option<String> {
+    CERTIFICATES
+        .get()?
+        .get(ssh_key_path)
+        .map(|(_, content)| content.clone())
+}
+
+// makes ssh offer the certificate of the key before the key
+pub fn ssh_options(ssh_key_path: &str) -> Vec<String> {
+    CERTIFICATES
+        .get()
+        .and_then(|certificates| certificates.get(ssh_key_path))
+        .map(|(path, _)| vec!["-o".to_string(), format!("CertificateFile={}", path)])
+        .unwrap_or_default()
+}
+
+// the content of the certificate, once it is known to be a current user certificate of the key.
+// the server checks the signature, the client only tells the mistakes it can
+fn check(path: &str, ssh_key_path: &str) -> Result<String, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("failed to read the ssh certificate {}: {}", path, err))?;
+    let invalid = || format!("{} is not an ssh certificate", path);
+    let (cert_type, cert) = blob(&content).ok_or_else(invalid)?;
+    if !cert_type.ends_with("-cert-v01@openssh.com") {
+        return Err(invalid());
+    }
+    let public_path = format!("{}.pub", ssh_key_path);
+    let (_, key) = fs::read_to_string(&public_path)
+        .ok()
+        .and_then(|content| blob(&content))
+        .ok_or(format!("failed to read the public key {}", public_path))?;
+
+    // type and nonce, then the fields of the key, which follow its type in the public key
+    let mut rest = &cert[..];
+    read_string(&mut rest).ok_or_else(invalid)?;
+    read_string(&mut rest).ok_or_else(invalid)?;
+    let mut key_fields = &key[..];
+    read_string(&mut key_fields).ok_or(format!("{} is invalid", public_path))?;
+    let Some(after_key) = rest.strip_prefix(key_fields) else {
+        return Err(format!(
+            "the ssh certificate {} is not the one of {}",
+            path, ssh_key_path
+        ));
+    };
+    rest = after_key;
+
+    read_u64(&mut rest).ok_or_else(invalid)?; // serial
+    if read_u32(&mut rest).ok_or_else(invalid)? != USER_CERTIFICATE {
+        return Err(format!("{} is not a user certificate", path));
+    }
+    read_string(&mut rest).ok_or_else(invalid)?; // key id
+    read_string(&mut rest).ok_or_else(invalid)?; // principals
+    read_u64(&mut rest).ok_or_else(invalid)?; // valid after
+    if read_u64(&mut rest).ok_or_else(invalid)? < now() {
+        return Err(format!(
+            "the ssh certificate {} expired, get a new one from your certificate authority",
+            path
+        ));
+    }
+    Ok(content.trim().to_string())
+}
+
+// the type and the decoded data of a line of a .pub file
+fn blob(content: &str) -> Option<(String, Vec<u8>)> {
+    let mut fields = content.split_whitespace();
+    let key_type = fields.next()?.to_string();
+    let data = BASE64_STANDARD.decode(fields.next()?).ok()?;
+    Some((key_type, data))
+}
+
+fn read_u32(data: &mut &[u8]) -> Option<u32> {
+    let (value, rest) = data.split_first_chunk()?;
+    *data = rest;
+    Some(u32::from_be_bytes(*value))
+}
+
+fn read_u64(data: &mut &[u8]) -> Option<u64> {
+    let (value, rest) = data.split_first_chunk()?;
+    *data = rest;
+    Some(u64::from_be_bytes(*value))
+}
+
+fn read_string<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = read_u32(data)? as usize;
+    let (value, rest) = data.split_at_checked(len)?;
+    *data = rest;
+    Some(value)
+}
@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+// the ssh tunnel of a running client, kept in tunnels/<pid>.json of the data folder so
+// export-ssh-config can write it as a Host block of ~/.ssh/config
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActiveTunnel {
+    pub name: String, // what the tunnel is for, the target of connect or the uuid of the host
+    pub ssh_pid: u32, // the tunnel is gone when this process is
+    pub hostname: String,
+    pub port: u16,
+    pub user: String,
+    pub identity_file: PathBuf,
+    pub options: Vec<(String, String)>, // ssh_config keywords, like UserKnownHostsFile
+    pub local_forwards: Vec<(String, String)>, // listening address, destination
+    pub remote_forwards: Vec<(String, String)>,
+}
+
+impl ActiveTunnel {
+    // read back from the arguments the tunnel's ssh was started with
+    pub fn new(name: String, command: &process::Command, ssh: &process::Child) -> Self {
+        let mut tunnel = ActiveTunnel {
+            name,
+            ssh_pid: ssh.id(),
+            hostname: String::new(),
+            port: 22,
+            user: String::new(),
+            identity_file: PathBuf::new(),
+            options: Vec::new(),
+            local_forwards: Vec::new(),
+            remote_forwards: Vec::new(),
+        };
+        let mut args = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned());
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-o" => {
+                    let option = args.next().unwrap_or_default();
+                    let Some((keyword, value)) = option.split_once('=') else {
+                        continue;
+                    };
+                    // the control socket belongs to the client, a plain ssh opens its own connection
+                    if !keyword.starts_with("Control") {
+                        tunnel
+                            .options
+                            .push((keyword.to_string(), value.to_string()));
+                    }
+                }
+                "-p" => {
+                    tunnel.port = args.next().and_then(|p| p.parse().ok()).unwrap_or(22);
+                }
+                "-i" => tunnel.identity_file = PathBuf::from(args.next().unwrap_or_default()),
+                "-J" => {
+                    let jump = args.next().unwrap_or_default();
+                    tunnel.options.push(("ProxyJump".to_string(), jump));
+                }
+                "-4" => tunnel
+                    .options
+                    .push(("AddressFamily".to_string(), "inet".to_string())),
+                "-6" => tunnel
+                    .options
+                    .push(("AddressFamily".to_string(), "inet6".to_string())),
+                "-L" | "-R" => {
+                    let forward = args.next().unwrap_or_default();
+                    tunnel.add_forward(arg == "-R", &forward);
+                }
+                "-N" => {}
+                destination => {
+                    let (user, hostname) = destination.split_once('@').unwrap_or(("", destination));
+                    tunnel.user = user.to_string();
+                    tunnel.hostname = hostname.to_string();
+                }
+            }
+        }
+        tunnel
+    }
+
+    // a forward in the form of the command line, listening port:destination, replacing the one
+    // listening on the same port
+    fn add_forward(&mut self, remote: bool, forward: &str) {
+        let (listen, destination) = forward.split_once(':').unwrap_or((forward, ""));
+        let forwards = if remote {
+            &mut self.remote_forwards
+        } else {
+            &mut self.local_forwards
+        };
+        forwards.retain(|(other, _)| other != listen);
+        forwards.push((listen.to_string(), destination.to_string()));
+    }
+
+    // the block reproducing the tunnel with `ssh -N <alias>`, valid while the server keeps it open
+    pub fn host_block(&self, alias: &str) -> String {
+        let mut block = format!(
+            "# {}, exported by kensa-port-forwarder\nHost {}\n    HostName {}\n    Port {}\n    User {}\n    IdentityFile {}\n    IdentitiesOnly yes\n",
+            self.name,
+            alias,
+            self.hostname,
+            self.port,
+            self.user,
+            self.identity_file.display()
+        );
+        for (keyword, value) in &self.options {
+            block.push_str(&format!("    {} {}\n", keyword, value));
+        }
+        for (listen, destination) in &self.local_forwards {
+            block.push_str(&format!("    LocalForward {} {}\n", listen, destination));
+        }
+        for (listen, destination) in &self.remote_forwards {
+            block.push_str(&format!("    RemoteForward {} {}\n", listen, destination));
+        }
+        block
+    }
+
+    fn running(&self) -> bool {
+        #[cfg(unix)]
+        {
+            unsafe { libc::kill(self.ssh_pid as libc::pid_t, 0) == 0 }
+        }
+        #[cfg(not(unix))]
+        {
+            true
+        }
+    }
+}
+
+// what ~/.ssh/config sets for a host, from `ssh -G` which resolves it the way ssh does
+pub struct SshDefaults {
+    pub identity_files: Vec<PathBuf>, // in the order ssh tries them, the default ones when unset
+    pub proxy_jump: Option<String>,
+}
+
+// nothing is set when ssh can not be run, the tunnels would fail anyway
+pub fn defaults(host: &str) -> SshDefaults {
+    let mut defaults = SshDefaults {
+        identity_files: Vec::new(),
+        proxy_jump: None,
+    };
+    let Ok(output) = process::Command::new("ssh").arg("-G").arg(host).output() else {
+        return defaults;
+    };
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Some((keyword, value)) = line.split_once(' ') else {
+            continue;
+        };
+        match keyword {
+            "identityfile" => defaults.identity_files.push(PathBuf::from(value)),
+            "proxyjump" if value != "none" => defaults.proxy_jump = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    defaults
+}
+
+// the file of this process, replaced each time its tunnel is opened again.
+// failing to write it never stops the tunnel
+pub struct TunnelRecord {
+    path: PathBuf,
+    tunnel: Option<ActiveTunnel>,
+}
+
+impl TunnelRecord {
+    pub fn new(data_dir: &Path) -> Self {
+        TunnelRecord {
+            path: data_dir
+                .join("tunnels")
+                .join(format!("{}.json", process::id())),
+            tunnel: None,
+        }
+    }
+
+    pub fn open(&mut self, tunnel: ActiveTunnel) {
+        self.tunnel = Some(tunnel);
+        self.write();
+    }
+
+    // a forward ("-L" or "-R") added to the running tunnel through its control socket
+    pub fn add_forward(&mut self, direction: &str, forward: &str) {
+        let Some(tunnel) = self.tunnel.as_mut() else {
+            return;
+        };
+        tunnel.add_forward(direction == "-R", forward);
+        self.write();
+    }
+
+    fn write(&self) {
+        let Some(tunnel) = &self.tunnel else {
+            return;
+        };
+        let json = serde_json::to_string_pretty(tunnel).expect("failed to stringify tunnel");
+        let written = fs::create_dir_all(self.path.parent().unwrap())
+            .and_then(|_| fs::write(&self.path, json));
+        if let Err(err) = written {
+            eprintln!("failed to write {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+impl Drop for TunnelRecord {
+    fn drop(&mut self) {
+        if self.tunnel.is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// the tunnels up right now, the files of the ones which went away are removed
+pub fn active(data_dir: &Path) -> Vec<ActiveTunnel> {
+    let Ok(entries) = fs::read_dir(data_dir.join("tunnels")) else {
+        return Vec::new();
+    };
+    let mut tunnels = Vec::new();
+    for entry in entries.flatten() {
+        let tunnel = fs::read_to_string(entry.path())
+            .ok()
+            .and_then(|json| serde_json::from_str::<ActiveTunnel>(&json).ok());
+        match tunnel {
+            Some(tunnel) if tunnel.running() => tunnels.push(tunnel),
+            _ => {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+    tunnels.sort_by(|a, b| a.name.cmp(&b.name));
+    tunnels
+}
@@ -0,0 +1,313 @@
+use crate::{
+    format_bytes, format_duration, heartbeat::Heartbeat, relay::Relay, socket_request, Socket,
+    WSMessage,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+const STATS_INTERVAL: Duration = Duration::from_secs(30);
+const THROUGHPUT_INTERVAL: Duration = Duration::from_secs(1);
+// the graphs of the host's console cover the last 5 minutes
+const GRAPH_INTERVAL: Duration = Duration::from_secs(10);
+const GRAPH_SAMPLES: usize = 30;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// what a client measures of its tunnel, the counters are missing when the traffic doesn't go
+// through the client itself (ssh and wireguard)
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TunnelStats {
+    pub bytes_sent: Option<u64>,
+    pub bytes_received: Option<u64>,
+    pub active_connections: Option<u32>,
+    pub rtt_ms: Option<u64>, // round trip time of the control channel
+}
+
+// what the server knows of a receiver's use of the host's tunnel
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReceiverUsage {
+    pub receiver: String,
+    pub port: u16,
+    pub connected_for: u64,   // seconds
+    pub traffic: Option<u64>, // bytes, unknown for direct tunnels the receiver doesn't report
+    #[serde(default)]
+    pub shared: bool, // the forward is shared with other receivers, the traffic counts all of them
+}
+
+impl ReceiverUsage {
+    // for the host, whose peer is the receiver
+    pub fn summary(&self, transport: &'static str, reason: Option<String>) -> SessionSummary {
+        SessionSummary {
+            peer: self.receiver.clone(),
+            port: self.port,
+            local_port: None,
+            transport,
+            duration: self.connected_for,
+            bytes_sent: None,
+            bytes_received: None,
+            traffic: self.traffic,
+            reason: reason.unwrap_or_else(|| "closed".to_string()),
+        }
+    }
+}
+
+// what a tunnel was once it closed, printed by both clients
+#[derive(Serialize, Debug)]
+pub struct SessionSummary {
+    pub peer: String,
+    pub port: u16,
+    pub local_port: Option<u16>, // the port it was mapped onto, receivers only
+    pub transport: &'static str,
+    pub duration: u64,               // seconds
+    pub bytes_sent: Option<u64>,     // counted by the client, only when the traffic goes through it
+    pub bytes_received: Option<u64>, // same
+    pub traffic: Option<u64>,        // both ways, as measured by the server
+    pub reason: String,              // code of why it closed, like idle or kicked
+}
+
+impl SessionSummary {
+    // one json line with json, for scripts
+    pub fn print(&self, json: bool) {
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string(self).expect("failed to stringify summary")
+            );
+            return;
+        }
+        let ports = match self.local_port {
+            Some(local_port) => format!("{} -> {}", self.port, local_port),
+            None => self.port.to_string(),
+        };
+        let traffic = match (self.bytes_sent, self.bytes_received, self.traffic) {
+            (Some(sent), Some(received), _) => format!(
+                "{} sent, {} received",
+                format_bytes(sent),
+                format_bytes(received)
+            ),
+            (_, _, Some(traffic)) => format!("{} transferred", format_bytes(traffic)),
+            _ => "traffic unknown".to_string(),
+        };
+        println!(
+            "tunnel with {} on port {} over {} closed after {}: {}, {}",
+            self.peer,
+            ports,
+            self.transport,
+            format_duration(self.duration),
+            describe_reason(&self.reason),
+            traffic
+        );
+    }
+}
+
+fn describe_reason(reason: &str) -> String {
+    match reason {
+        "client_disconnected" => "the peer disconnected".to_string(),
+        "idle" => "it was idle for too long".to_string(),
+        "max_lifetime" => "it reached its maximum lifetime".to_string(),
+        "quota_exceeded" => "a transfer quota was exceeded".to_string(),
+        "admin" => "closed by an admin of the server".to_string(),
+        "kicked" => "kicked by the host".to_string(),
+        "revoked" => "revoked by the host".to_string(),
+        "forward_failed" => "the port could not be forwarded".to_string(),
+        "interrupted" => "interrupted".to_string(),
+        other => other.replace('_', " "),
+    }
+}
+
+// reports the stats of the tunnel to the server on an interval, the server forwards them to the peer
+pub struct StatsReporter {
+    last_sent: Instant,
+    peer_connections: Option<u32>,
+}
+
+impl StatsReporter {
+    pub fn new() -> Self {
+        StatsReporter {
+            last_sent: Instant::now(),
+            peer_connections: None,
+        }
+    }
+
+    pub fn tick(&mut self, socket: &mut Socket, relay: Option<&Relay>, heartbeat: &Heartbeat) {
+        if self.last_sent.elapsed() < STATS_INTERVAL {
+            return;
+        }
+        self.last_sent = Instant::now();
+        let mut stats = TunnelStats {
+            rtt_ms: heartbeat.rtt().map(|rtt| rtt.as_millis() as u64),
+            ..Default::default()
+        };
+        if let Some(relay) = relay {
+            let (sent, received) = relay.traffic();
+            stats.bytes_sent = Some(sent);
+            stats.bytes_received = Some(received);
+            stats.active_connections = Some(relay.connections() as u32);
+        }
+        socket_request(socket, WSMessage::TunnelStats(stats));
+    }
+
+    pub fn peer_stats(&mut self, stats: TunnelStats) {
+        if let Some(connections) = stats.active_connections {
+            if self.peer_connections != Some(connections) {
+                println!("the peer has {} open connection(s)", connections);
+            }
+        }
+        self.peer_connections = stats.active_connections;
+    }
+}
+
+// the readout of connect --progress, a single line rewritten in place every second
+pub struct Throughput {
+    last: Instant,
+    last_traffic: (u64, u64), // sent, received
+    shown: bool,
+}
+
+impl Throughput {
+    pub fn new() -> Self {
+        Throughput {
+            last: Instant::now(),
+            last_traffic: (0, 0),
+            shown: false,
+        }
+    }
+
+    // traffic gives what was sent and received since the tunnel opened, only called once per interval
+    pub fn tick(&mut self, traffic: impl FnOnce() -> Option<(u64, u64)>) {
+        let elapsed = self.last.elapsed();
+        if elapsed < THROUGHPUT_INTERVAL {
+            return;
+        }
+        let Some(traffic) = traffic() else {
+            return;
+        };
+        let rate = |now: u64, before: u64| {
+            format_bytes((now.saturating_sub(before) as f64 / elapsed.as_secs_f64()) as u64)
+        };
+        // \x1b[2K clears what was left of a longer line
+        print!(
+            "\r\x1b[2Kup {}/s, down {}/s ({} sent, {} received)",
+            rate(traffic.0, self.last_traffic.0),
+            rate(traffic.1, self.last_traffic.1),
+            format_bytes(traffic.0),
+            format_bytes(traffic.1)
+        );
+        let _ = io::stdout().flush();
+        self.last = Instant::now();
+        self.last_traffic = traffic;
+        self.shown = true;
+    }
+
+    // ends the readout line so the next message starts on its own
+    pub fn finish(&mut self) {
+        if self.shown {
+            println!();
+            self.shown = false;
+        }
+    }
+}
+
+// the throughput of each receiver of a host over the last minutes, for the graph command of the console,
+// sampled from the usage the server measures
+pub struct TrafficGraphs {
+    last_poll: Instant,
+    pending: Option<u64>, // request id of the ListReceivers in flight
+    receivers: BTreeMap<String, ReceiverGraph>,
+}
+
+struct ReceiverGraph {
+    sampled_at: Instant,
+    traffic: u64,
+    rates: VecDeque<u64>, // bytes per second, oldest first
+}
+
+impl TrafficGraphs {
+    pub fn new() -> Self {
+        TrafficGraphs {
+            last_poll: Instant::now(),
+            pending: None,
+            receivers: BTreeMap::new(),
+        }
+    }
+
+    // asks the server for the usage of the receivers once per interval
+    pub fn tick(&mut self, socket: &mut Socket) {
+        if self.pending.is_none() && self.last_poll.elapsed() >= GRAPH_INTERVAL {
+            self.last_poll = Instant::now();
+            self.pending = Some(socket_request(socket, WSMessage::ListReceivers {}));
+        }
+    }
+
+    pub fn answers(&self, request_id: Option<u64>) -> bool {
+        self.pending.is_some() && self.pending == request_id
+    }
+
+    pub fn record(&mut self, usage: &[ReceiverUsage]) {
+        self.pending = None;
+        // receivers which left are forgotten
+        self.receivers
+            .retain(|receiver, _| usage.iter().any(|u| u.receiver == *receiver));
+        for usage in usage {
+            let Some(traffic) = usage.traffic else {
+                continue;
+            };
+            let Some(graph) = self.receivers.get_mut(&usage.receiver) else {
+                self.receivers.insert(
+                    usage.receiver.clone(),
+                    ReceiverGraph {
+                        sampled_at: Instant::now(),
+                        traffic,
+                        rates: VecDeque::new(),
+                    },
+                );
+                continue;
+            };
+            let elapsed = graph.sampled_at.elapsed().as_secs_f64().max(1.0);
+            let rate = (traffic.saturating_sub(graph.traffic) as f64 / elapsed) as u64;
+            if graph.rates.len() == GRAPH_SAMPLES {
+                graph.rates.pop_front();
+            }
+            graph.rates.push_back(rate);
+            graph.sampled_at = Instant::now();
+            graph.traffic = traffic;
+        }
+    }
+
+    // one sparkline per receiver, scaled to its own peak
+    pub fn print(&self) {
+        let graphs: Vec<_> = self
+            .receivers
+            .iter()
+            .filter(|(_, graph)| !graph.rates.is_empty())
+            .collect();
+        if graphs.is_empty() {
+            println!(
+                "no throughput measured yet, the graphs are sampled every {}s",
+                GRAPH_INTERVAL.as_secs()
+            );
+        }
+        for (receiver, graph) in graphs {
+            let peak = graph.rates.iter().copied().max().unwrap_or(0);
+            let sparkline: String = graph
+                .rates
+                .iter()
+                .map(|rate| match peak {
+                    0 => SPARKS[0],
+                    _ => SPARKS[(*rate * (SPARKS.len() as u64 - 1) / peak) as usize],
+                })
+                .collect();
+            println!(
+                "{}  {:<width$}  now {}/s, peak {}/s",
+                receiver,
+                sparkline,
+                format_bytes(*graph.rates.back().unwrap()),
+                format_bytes(peak),
+                width = GRAPH_SAMPLES
+            );
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use crate::keyring;
+#[cfg(feature = "interactive")]
+use dialoguer::{theme::ColorfulTheme, Password};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{env, fs, num::NonZeroU32, path::PathBuf};
+
+// the files which tell who the user is and where they connected, encrypted when a passphrase is set
+const PRIVATE_FILES: [&str; 4] = ["id", "history.jsonl", "favorites.json", "tokens.json"];
+const SALT_FILE: &str = "passphrase.salt";
+// a known content encrypted with the key, to tell a wrong passphrase apart from a damaged file
+const CHECK_FILE: &str = "passphrase.check";
+const CHECK_CONTENT: &str = "kensa-port-forwarder";
+// at the start of every encrypted file, followed by the nonce then the ciphertext
+const MAGIC: &[u8] = b"kpfenc1";
+const PBKDF2_ITERATIONS: u32 = 600_000;
+// read instead of prompting, for unattended hosts
+const PASSPHRASE_ENV: &str = "KENSA_PASSPHRASE";
+// the passphrase is kept under this account of the keyring by encrypt --keyring
+const KEYRING_ACCOUNT: &str = "passphrase";
+
+// reads and writes the files of the data folder, encrypting the private ones once a passphrase is set
+#[derive(Clone)]
+pub struct Storage {
+    dir: PathBuf,
+    key: Option<[u8; 32]>,
+}
+
+impl Storage {
+    // asks for the passphrase when the files are encrypted
+    pub fn open(dir: PathBuf) -> Result<Self, String> {
+        let mut storage = Storage { dir, key: None };
+        let Ok(salt) = fs::read(storage.dir.join(SALT_FILE)) else {
+            return Ok(storage);
+        };
+        // left by an encryption interrupted before it encrypted anything
+        if !storage.dir.join(CHECK_FILE).exists() {
+            let _ = fs::remove_file(storage.dir.join(SALT_FILE));
+            return Ok(storage);
+        }
+        let passphrase = read_passphrase(false)?;
+        storage.key = Some(derive_key(&passphrase, &salt));
+        match storage.read(CHECK_FILE) {
+            Some(check) if check == CHECK_CONTENT => Ok(storage),
+            _ => Err("wrong passphrase".to_string()),
+        }
+    }
+
+    pub fn encrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub fn read(&self, name: &str) -> Option<String> {
+        let content = fs::read(self.dir.join(name)).ok()?;
+        let plain = match (&self.key, content.strip_prefix(MAGIC)) {
+            (Some(key), Some(sealed)) => decrypt(key, sealed)?,
+            (None, Some(_)) => return None,
+            // written before the passphrase was set
+            (_, None) => content,
+        };
+        String::from_utf8(plain).ok()
+    }
+
+    pub fn write(&self, name: &str, content: &str) -> Result<(), String> {
+        let data = match &self.key {
+            Some(key) if name == CHECK_FILE || PRIVATE_FILES.contains(&name) => {
+                encrypt(key, content.as_bytes())?
+            }
+            _ => content.as_bytes().to_vec(),
+        };
+        let path = self.dir.join(name);
+        fs::write(&path, data).map_err(|err| format!("failed to write {}: {}", path.display(), err))
+    }
+
+    // asks for a new passphrase and encrypts the private files with it,
+    // the passphrase is kept by the keyring instead of being asked on every start when keyring is set
+    pub fn encrypt_files(&mut self, keyring: bool) -> Result<(), String> {
+        if self.encrypted() {
+            return Err("the files are already encrypted".to_string());
+        }
+        let passphrase = read_passphrase(true)?;
+        // before anything is encrypted, a keyring which can't be reached leaves the files as they were
+        if keyring {
+            keyring::set(KEYRING_ACCOUNT, &passphrase)?;
+        }
+        let contents = self.read_private_files();
+        let mut salt = [0; 16];
+        SystemRandom::new()
+            .fill(&mut salt)
+            .map_err(|_| "failed to generate a salt")?;
+        // the salt goes first: files encrypted without it could never be read again.
+        // until every file is encrypted, read tells the plain ones apart
+        let result = self
+            .write_salt(&passphrase, &salt)
+            .and_then(|()| self.write_private_files(contents.clone()));
+        if result.is_err() {
+            // back to the plain files, which the removed salt no longer marks as encrypted
+            self.key = None;
+            let _ = self.write_private_files(contents);
+            let _ = fs::remove_file(self.dir.join(CHECK_FILE));
+            let _ = fs::remove_file(self.dir.join(SALT_FILE));
+            if keyring {
+                keyring::delete(KEYRING_ACCOUNT);
+            }
+        }
+        result
+    }
+
+    fn write_salt(&mut self, passphrase: &str, salt: &[u8]) -> Result<(), String> {
+        fs::write(self.dir.join(SALT_FILE), salt)
+            .map_err(|err| format!("failed to write the salt: {}", err))?;
+        self.key = Some(derive_key(passphrase, salt));
+        self.write(CHECK_FILE, CHECK_CONTENT)
+    }
+
+    pub fn decrypt_files(&mut self) -> Result<(), String> {
+        if !self.encrypted() {
+            return Err("the files are not encrypted".to_string());
+        }
+        let contents = self.read_private_files();
+        self.key = None;
+        self.write_private_files(contents)?;
+        let _ = fs::remove_file(self.dir.join(CHECK_FILE));
+        keyring::delete(KEYRING_ACCOUNT);
+        fs::remove_file(self.dir.join(SALT_FILE))
+            .map_err(|err| format!("failed to remove the salt: {}", err))
+    }
+
+    fn read_private_files(&self) -> Vec<(&'static str, String)> {
+        PRIVATE_FILES
+            .iter()
+            .filter_map(|name| Some((*name, self.read(name)?)))
+            .collect()
+    }
+
+    fn write_private_files(&self, contents: Vec<(&'static str, String)>) -> Result<(), String> {
+        contents
+            .iter()
+            .try_for_each(|(name, content)| self.write(name, content))
+    }
+}
+
+fn read_passphrase(new: bool) -> Result<String, String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    if let Some(passphrase) = (!new).then(|| keyring::get(KEYRING_ACCOUNT)).flatten() {
+        return Ok(passphrase);
+    }
+    prompt_passphrase(new)
+}
+
+#[cfg(feature = "interactive")]
+fn prompt_passphrase(new: bool) -> Result<String, String> {
+    let theme = ColorfulTheme::default();
+    let mut prompt = Password::with_theme(&theme);
+    if new {
+        prompt = prompt
+            .with_prompt("new passphrase")
+            .with_confirmation("confirm the passphrase", "the passphrases don't match");
+    } else {
+        prompt = prompt.with_prompt(format!("passphrase (or set {})", PASSPHRASE_ENV));
+    }
+    prompt
+        .interact()
+        .map_err(|err| format!("failed to read the passphrase: {}", err))
+}
+
+// the passphrase can't be typed without echoing it
+#[cfg(not(feature = "interactive"))]
+fn prompt_passphrase(_new: bool) -> Result<String, String> {
+    Err(format!(
+        "set the passphrase in {}, the client was built without the interactive feature",
+        PASSPHRASE_ENV
+    ))
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    key
+}
+
+fn aead_key(key: &[u8; 32]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&aead::CHACHA20_POLY1305, key).expect("invalid key length"))
+}
+
+fn encrypt(key: &[u8; 32], plain: &[u8]) -> Result<Vec<u8>, String> {
+    let mut nonce = [0; aead::NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| "failed to generate a nonce")?;
+    let mut data = plain.to_vec();
+    aead_key(key)
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+        .map_err(|_| "failed to encrypt")?;
+    Ok([MAGIC, &nonce, &data].concat())
+}
+
+fn decrypt(key: &[u8; 32], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < aead::NONCE_LEN {
+        return None;
+    }
+    let (nonce, data) = sealed.split_at(aead::NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+    let mut data = data.to_vec();
+    let plain = aead_key(key)
+        .open_in_place(nonce, Aad::empty(), &mut data)
+        .ok()?;
+    Some(plain.to_vec())
+}
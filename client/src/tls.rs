@@ -0,0 +1,221 @@
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+};
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use tungstenite::Connector;
+use tungstenite::{handshake::client::Request, stream::MaybeTlsStream, WebSocket};
+#[cfg(feature = "rustls")]
+use {
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+    },
+    std::{env, sync::Arc},
+};
+
+// where the certificate authorities of the system are, by distribution (debian and alpine, fedora, macos)
+#[cfg(feature = "rustls")]
+const CA_FILES: [&str; 3] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+pub trait Stream: Read + Write {}
+
+impl<S: Read + Write> Stream for S {}
+
+// the tls used by the connections of the client (server, identity providers): native-tls by default,
+// which is openssl on linux, or rustls with the rustls feature, which needs no system library
+// and trusts the authorities of the system's pem bundle (or SSL_CERT_FILE). with neither only ws:// works
+#[cfg(feature = "rustls")]
+fn connector() -> Result<Connector, String> {
+    Ok(Connector::Rustls(client_config()?))
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+fn connector() -> Result<Connector, String> {
+    native_tls::TlsConnector::new()
+        .map(Connector::NativeTls)
+        .map_err(|err| err.to_string())
+}
+
+// the websocket handshake with the server over the stream, in tls for wss://
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub fn client(
+    request: Request,
+    stream: TcpStream,
+) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, String> {
+    tungstenite::client_tls_with_config(request, stream, None, Some(connector()?))
+        .map(|(socket, _)| socket)
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+pub fn client(
+    request: Request,
+    stream: TcpStream,
+) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, String> {
+    if request.uri().scheme_str() == Some("wss") {
+        return Err("the client was built without tls, only ws:// servers can be used".to_string());
+    }
+    tungstenite::client(request, MaybeTlsStream::Plain(stream))
+        .map(|(socket, _)| socket)
+        .map_err(|err| err.to_string())
+}
+
+// wraps the stream in tls for the host, like the websocket of wss:// servers
+pub fn wrap(host: &str, stream: TcpStream) -> Result<Box<dyn Stream>, String> {
+    tls_stream(host, stream).map(|stream| Box::new(stream) as Box<dyn Stream>)
+}
+
+// the same, in the stream type of the websocket
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub fn tls_stream(host: &str, stream: TcpStream) -> Result<MaybeTlsStream<TcpStream>, String> {
+    match connector()? {
+        #[cfg(feature = "rustls")]
+        Connector::Rustls(config) => {
+            let name = ServerName::try_from(host.to_string()).map_err(|err| err.to_string())?;
+            let connection = ClientConnection::new(config, name).map_err(|err| err.to_string())?;
+            Ok(MaybeTlsStream::Rustls(StreamOwned::new(connection, stream)))
+        }
+        #[cfg(feature = "native-tls")]
+        Connector::NativeTls(connector) => connector
+            .connect(host, stream)
+            .map(MaybeTlsStream::NativeTls)
+            .map_err(|err| err.to_string()),
+        _ => unreachable!("the connector is tls"),
+    }
+}
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+pub fn tls_stream(_host: &str, _stream: TcpStream) -> Result<MaybeTlsStream<TcpStream>, String> {
+    Err("the client was built without tls".to_string())
+}
+
+// the der certificate the server presented, none when the stream is not tls
+pub fn peer_certificate(stream: &MaybeTlsStream<TcpStream>) -> Option<Option<Vec<u8>>> {
+    match stream {
+        #[cfg(feature = "native-tls")]
+        MaybeTlsStream::NativeTls(stream) => Some(
+            stream
+                .peer_certificate()
+                .ok()
+                .flatten()
+                .and_then(|certificate| certificate.to_der().ok()),
+        ),
+        #[cfg(feature = "rustls")]
+        MaybeTlsStream::Rustls(stream) => Some(
+            stream
+                .conn
+                .peer_certificates()
+                .and_then(|certificates| certificates.first())
+                .map(|certificate| certificate.to_vec()),
+        ),
+        _ => None,
+    }
+}
+
+// the tcp stream under the tls
+pub fn tcp_stream(stream: &mut MaybeTlsStream<TcpStream>) -> Option<&mut TcpStream> {
+    match stream {
+        MaybeTlsStream::Plain(stream) => Some(stream),
+        #[cfg(feature = "native-tls")]
+        MaybeTlsStream::NativeTls(stream) => Some(stream.get_mut()),
+        #[cfg(feature = "rustls")]
+        MaybeTlsStream::Rustls(stream) => Some(stream.get_mut()),
+        _ => None,
+    }
+}
+
+// the tls of connect --http --tls, served with the same library as the connections to the server
+#[cfg(feature = "rustls")]
+pub struct Acceptor(Arc<ServerConfig>);
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub struct Acceptor(native_tls::TlsAcceptor);
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+pub enum Acceptor {}
+
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+impl Acceptor {
+    // from the pem certificate (chain) and pkcs8 key
+    #[cfg(feature = "rustls")]
+    pub fn new(cert: &[u8], key: &[u8]) -> Result<Self, String> {
+        let certificates = CertificateDer::pem_slice_iter(cert)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(|err| err.to_string())?;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .map_err(|err| err.to_string())?;
+        Ok(Acceptor(Arc::new(config)))
+    }
+
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    pub fn new(cert: &[u8], key: &[u8]) -> Result<Self, String> {
+        native_tls::Identity::from_pkcs8(cert, key)
+            .and_then(native_tls::TlsAcceptor::new)
+            .map(Acceptor)
+            .map_err(|err| err.to_string())
+    }
+
+    #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+    pub fn new(_cert: &[u8], _key: &[u8]) -> Result<Self, String> {
+        Err("the client was built without tls".to_string())
+    }
+
+    // the handshake with the client is done before returning, like native-tls does it
+    #[cfg(feature = "rustls")]
+    pub fn accept(&self, mut stream: TcpStream) -> Result<Box<dyn Stream>, String> {
+        let mut connection =
+            ServerConnection::new(self.0.clone()).map_err(|err| err.to_string())?;
+        while connection.is_handshaking() {
+            connection
+                .complete_io(&mut stream)
+                .map_err(|err| err.to_string())?;
+        }
+        Ok(Box::new(StreamOwned::new(connection, stream)))
+    }
+
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    pub fn accept(&self, stream: TcpStream) -> Result<Box<dyn Stream>, String> {
+        self.0
+            .accept(stream)
+            .map(|stream| Box::new(stream) as Box<dyn Stream>)
+            .map_err(|err| err.to_string())
+    }
+
+    #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+    pub fn accept(&self, _stream: TcpStream) -> Result<Box<dyn Stream>, String> {
+        match *self {}
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn client_config() -> Result<Arc<ClientConfig>, String> {
+    let files: Vec<String> = match env::var("SSL_CERT_FILE") {
+        Ok(file) => vec![file],
+        Err(_) => CA_FILES.iter().map(|file| file.to_string()).collect(),
+    };
+    let mut roots = RootCertStore::empty();
+    for file in &files {
+        let Ok(certificates) = CertificateDer::pem_file_iter(file) else {
+            continue;
+        };
+        roots.add_parsable_certificates(certificates.flatten());
+    }
+    if roots.is_empty() {
+        return Err(format!(
+            "no certificate authority found in {}, install ca-certificates or set SSL_CERT_FILE",
+            files.join(", ")
+        ));
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
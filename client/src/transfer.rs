@@ -0,0 +1,238 @@
+use crate::format_bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+// the name receive gives its port, send looks it up on the host
+pub const PORT_NAME: &str = "files";
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// sent first as a json line, then the content and its sha256.
+// the receiver answers a line, ok or why the file was refused
+#[derive(Serialize, Deserialize)]
+struct Header {
+    name: String,
+    size: u64,
+}
+
+// the listener of the receive command, saving the files it is sent into the folder
+pub struct FileReceiver {
+    folder: PathBuf,
+}
+
+impl FileReceiver {
+    pub fn new(folder: PathBuf) -> Result<Self, String> {
+        if !folder.is_dir() {
+            return Err(format!("{} is not a folder", folder.display()));
+        }
+        Ok(FileReceiver { folder })
+    }
+
+    // listens on a free port of localhost on its own threads, returns the port
+    pub fn start(self) -> io::Result<u16> {
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        let port = listener.local_addr()?.port();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let folder = self.folder.clone();
+                thread::spawn(move || {
+                    if let Err(err) = receive(&folder, stream) {
+                        eprintln!("failed to receive a file: {}", err);
+                    }
+                });
+            }
+        });
+        Ok(port)
+    }
+}
+
+fn receive(folder: &Path, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(());
+    }
+    let header: Header =
+        serde_json::from_str(&line).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    // only the name is kept, a sender can not write outside the folder
+    let name = Path::new(&header.name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+        .unwrap_or_else(|| "received".to_string());
+    let destination = free_name(folder, &name);
+    let partial = destination.with_file_name(format!(
+        ".{}.part",
+        destination.file_name().unwrap().to_string_lossy()
+    ));
+
+    println!("receiving {} ({})", name, format_bytes(header.size));
+    let mut file = File::create(&partial)?;
+    let mut hasher = Sha256::new();
+    let mut progress = Progress::new(&name, header.size);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut left = header.size;
+    while left > 0 {
+        let n = reader.read(&mut buffer[..CHUNK_SIZE.min(left as usize)])?;
+        if n == 0 {
+            progress.finish();
+            let _ = fs::remove_file(&partial);
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("the sender stopped before the end of {}", name),
+            ));
+        }
+        file.write_all(&buffer[..n])?;
+        hasher.update(&buffer[..n]);
+        left -= n as u64;
+        progress.advance(n as u64);
+    }
+    progress.finish();
+    let mut checksum = [0; 32];
+    reader.read_exact(&mut checksum)?;
+    if hasher.finalize().as_slice() != checksum {
+        let _ = fs::remove_file(&partial);
+        writeln!(
+            writer,
+            "the checksum does not match, the file was corrupted on the way"
+        )?;
+        eprintln!("{} was corrupted on the way and was not kept", name);
+        return Ok(());
+    }
+    file.sync_all()?;
+    fs::rename(&partial, &destination)?;
+    writeln!(writer, "ok")?;
+    println!("received {}, checksum verified", destination.display());
+    Ok(())
+}
+
+// the name in the folder, numbered when a file already has it
+fn free_name(folder: &Path, name: &str) -> PathBuf {
+    let path = folder.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| folder.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// sends the file through the tunnel listening on the local port, waiting for it to come up
+pub fn send(port: u16, path: &Path) -> Result<(), String> {
+    let mut file =
+        File::open(path).map_err(|err| format!("failed to open {}: {}", path.display(), err))?;
+    let size = file.metadata().map_err(|err| err.to_string())?.len();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| format!("{} is not a file", path.display()))?;
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(RETRY_INTERVAL),
+        }
+    };
+
+    let failed = |err: io::Error| format!("failed to send {}: {}", name, err);
+    let header = Header {
+        name: name.clone(),
+        size,
+    };
+    let header = serde_json::to_string(&header).expect("failed to stringify header");
+    writeln!(stream, "{}", header).map_err(failed)?;
+    let mut hasher = Sha256::new();
+    let mut progress = Progress::new(&name, size);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut left = size;
+    while left > 0 {
+        let n = file.read(&mut buffer).map_err(failed)?;
+        if n == 0 {
+            return Err(format!("{} got shorter while it was sent", name));
+        }
+        let n = n.min(left as usize);
+        stream.write_all(&buffer[..n]).map_err(failed)?;
+        hasher.update(&buffer[..n]);
+        left -= n as u64;
+        progress.advance(n as u64);
+    }
+    progress.finish();
+    stream.write_all(&hasher.finalize()).map_err(failed)?;
+
+    let mut answer = String::new();
+    BufReader::new(stream)
+        .read_line(&mut answer)
+        .map_err(failed)?;
+    match answer.trim() {
+        "ok" => {
+            println!("sent {}, checksum verified by the receiver", name);
+            Ok(())
+        }
+        "" => Err(format!(
+            "the connection closed before the receiver confirmed {}",
+            name
+        )),
+        refused => Err(format!("the receiver refused {}: {}", name, refused)),
+    }
+}
+
+// how far a file is, on a line updated every second
+struct Progress<'a> {
+    name: &'a str,
+    total: u64,
+    done: u64,
+    last: Instant,
+    shown: bool,
+}
+
+impl<'a> Progress<'a> {
+    fn new(name: &'a str, total: u64) -> Self {
+        Progress {
+            name,
+            total,
+            done: 0,
+            last: Instant::now(),
+            shown: false,
+        }
+    }
+
+    fn advance(&mut self, n: u64) {
+        self.done += n;
+        if self.last.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        // \x1b[2K clears what was left of a longer line
+        print!(
+            "\r\x1b[2K{}: {}% ({} of {})",
+            self.name,
+            self.done * 100 / self.total.max(1),
+            format_bytes(self.done),
+            format_bytes(self.total)
+        );
+        let _ = io::stdout().flush();
+        self.last = Instant::now();
+        self.shown = true;
+    }
+
+    // ends the progress line so the next message starts on its own
+    fn finish(&mut self) {
+        if self.shown {
+            println!();
+            self.shown = false;
+        }
+    }
+}
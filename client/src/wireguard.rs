@@ -0,0 +1,159 @@
+use std::{
+    io::{self, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    process::{self, Stdio},
+    thread,
+};
+
+// a wireguard interface linking this client to its peer, needs the `ip` and `wg` tools and root privileges
+pub struct WireGuard {
+    interface: String,
+    pub address: Ipv4Addr,
+    pub peer_address: Ipv4Addr,
+    pub public_key: String,
+    pub listen_port: u16,
+}
+
+impl WireGuard {
+    // address is given with its prefix length, like "10.77.0.1/30"
+    pub fn new(address: &str, peer_address: &str) -> io::Result<Self> {
+        let invalid = |_| io::Error::new(io::ErrorKind::InvalidData, "invalid address");
+        let ip: Ipv4Addr = address
+            .split('/')
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(invalid)?;
+        let peer_ip: Ipv4Addr = peer_address.parse().map_err(invalid)?;
+
+        let private_key = run("wg", &["genkey"], None)?;
+        let public_key = run("wg", &["pubkey"], Some(&private_key))?;
+        // let the os pick a free udp port
+        let listen_port = UdpSocket::bind("0.0.0.0:0")?.local_addr()?.port();
+
+        let interface = format!("kpf{}", process::id());
+        run(
+            "ip",
+            &["link", "add", "dev", &interface, "type", "wireguard"],
+            None,
+        )?;
+        let wireguard = WireGuard {
+            interface,
+            address: ip,
+            peer_address: peer_ip,
+            public_key,
+            listen_port,
+        };
+        // from here on, dropping the struct removes the interface
+        run(
+            "ip",
+            &["address", "add", address, "dev", &wireguard.interface],
+            None,
+        )?;
+        run(
+            "wg",
+            &[
+                "set",
+                &wireguard.interface,
+                "listen-port",
+                &listen_port.to_string(),
+                "private-key",
+                "/dev/stdin",
+            ],
+            Some(&private_key),
+        )?;
+        run(
+            "ip",
+            &["link", "set", "up", "dev", &wireguard.interface],
+            None,
+        )?;
+        Ok(wireguard)
+    }
+
+    pub fn add_peer(&self, public_key: &str, endpoint: Option<&str>) -> io::Result<()> {
+        let allowed_ips = format!("{}/32", self.peer_address);
+        let mut args = vec![
+            "set",
+            &self.interface,
+            "peer",
+            public_key,
+            "allowed-ips",
+            &allowed_ips,
+            // keeps the nat mappings open on both sides
+            "persistent-keepalive",
+            "5",
+        ];
+        if let Some(endpoint) = endpoint {
+            args.push("endpoint");
+            args.push(endpoint);
+        }
+        run("wg", &args, None).map(|_| ())
+    }
+
+    // bytes sent to and received from the peer, wireguard's overhead included
+    pub fn traffic(&self) -> Option<(u64, u64)> {
+        let output = run("wg", &["show", &self.interface, "transfer"], None).ok()?;
+        // one "<public key>\t<received>\t<sent>" line per peer, there is only one
+        let mut fields = output.lines().next()?.split('\t').skip(1);
+        let received = fields.next()?.parse().ok()?;
+        let sent = fields.next()?.parse().ok()?;
+        Some((sent, received))
+    }
+}
+
+impl Drop for WireGuard {
+    fn drop(&mut self) {
+        let _ = run("ip", &["link", "del", "dev", &self.interface], None);
+    }
+}
+
+fn run(program: &str, args: &[&str], input: Option<&str>) -> io::Result<String> {
+    let mut child = process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(input) = input {
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// accepts connections on `listen` and pipes each of them to `target`
+pub fn forward(listen: SocketAddr, target: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            thread::spawn(move || {
+                if let Ok(upstream) = TcpStream::connect(target) {
+                    pipe(client, upstream);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn pipe(a: TcpStream, b: TcpStream) {
+    let (Ok(mut a_read), Ok(mut b_read)) = (a.try_clone(), b.try_clone()) else {
+        return;
+    };
+    let (mut a_write, mut b_write) = (a, b);
+    let upload = thread::spawn(move || {
+        let _ = io::copy(&mut a_read, &mut b_write);
+        let _ = b_write.shutdown(std::net::Shutdown::Write);
+    });
+    let _ = io::copy(&mut b_read, &mut a_write);
+    let _ = a_write.shutdown(std::net::Shutdown::Write);
+    let _ = upload.join();
+}
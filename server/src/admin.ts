@@ -0,0 +1,113 @@
+import type { IncomingMessage, ServerResponse } from 'http';
+import { timingSafeEqual } from 'crypto';
+import type { TunnelStats } from './schema';
+
+export interface AdminTunnel {
+    id: string;
+    sender: string;
+    label: string | null; // of the sender's session
+    receiver: string;
+    port: number;
+    transport: string;
+    opened_at: string;
+    traffic: number;
+    stats: { sender: TunnelStats | null; receiver: TunnelStats | null }; // last report of each client
+}
+
+export interface AdminClient {
+    uuid: string;
+    client_type: string;
+    label: string | null;
+    address: string | null;
+    account: string | null;
+    tunnels: number;
+}
+
+// what the cleanup of stale credentials removed, see cleanupStaleCredentials in server.ts
+export interface CleanupReport {
+    authorized_keys: string[]; // file names
+    users: string[];
+    sshd: number[]; // pids
+    embedded_tunnels: string[]; // users of the embedded ssh backend
+}
+
+// what the admin apis (http and grpc) can do, provided by the server.
+// the actions on a client or tunnel return false when there is no such thing
+export interface AdminActions {
+    tunnels(): AdminTunnel[];
+    closeTunnel(id: string): boolean;
+    clients(): AdminClient[];
+    kickClient(uuid: string): boolean;
+    cleanup(): CleanupReport;
+    bans(): string[];
+    setBanned(target: string, banned: boolean): void;
+}
+
+// GET /admin/tunnels lists the open tunnels, POST /admin/tunnels/<id>/close closes one.
+// GET /admin/clients lists the connected clients, POST /admin/clients/<uuid>/kick disconnects one.
+// GET /admin/bans lists the banned uuids and addresses, PUT and DELETE /admin/bans/<target> change them.
+// POST /admin/cleanup removes the credentials and sshd instances no tunnel owns, and lists them.
+// every request needs an "Authorization: Bearer <token>" header, or "X-Admin-Token: <token>" behind a proxy
+// which takes the authorization header for its own basic authentication. the api is disabled without a token
+export function adminHandler(token: string | undefined, actions: AdminActions) {
+    return (req: IncomingMessage, res: ServerResponse) => {
+        const url = new URL(req.url ?? '/', 'http://localhost');
+        if (!token || !url.pathname.startsWith('/admin/')) {
+            return sendJson(res, 404, { error: 'not found' });
+        }
+        if (!authorized(req, token)) {
+            return sendJson(res, 401, { error: 'unauthorized' });
+        }
+
+        if (req.method === 'GET' && url.pathname === '/admin/tunnels') {
+            return sendJson(res, 200, actions.tunnels());
+        }
+        const close = url.pathname.match(/^\/admin\/tunnels\/([^/]+)\/close$/);
+        if (req.method === 'POST' && close) {
+            if (!actions.closeTunnel(decodeURIComponent(close[1]!))) {
+                return sendJson(res, 404, { error: 'no such tunnel' });
+            }
+            return sendJson(res, 200, { success: true });
+        }
+        if (req.method === 'GET' && url.pathname === '/admin/clients') {
+            return sendJson(res, 200, actions.clients());
+        }
+        const kick = url.pathname.match(/^\/admin\/clients\/([^/]+)\/kick$/);
+        if (req.method === 'POST' && kick) {
+            if (!actions.kickClient(decodeURIComponent(kick[1]!))) {
+                return sendJson(res, 404, { error: 'no such client' });
+            }
+            return sendJson(res, 200, { success: true });
+        }
+        if (req.method === 'POST' && url.pathname === '/admin/cleanup') {
+            return sendJson(res, 200, actions.cleanup());
+        }
+        if (req.method === 'GET' && url.pathname === '/admin/bans') {
+            return sendJson(res, 200, actions.bans());
+        }
+        const ban = url.pathname.match(/^\/admin\/bans\/([^/]+)$/);
+        if ((req.method === 'PUT' || req.method === 'DELETE') && ban) {
+            actions.setBanned(decodeURIComponent(ban[1]!), req.method === 'PUT');
+            return sendJson(res, 200, { success: true });
+        }
+        sendJson(res, 404, { error: 'not found' });
+    };
+}
+
+function authorized(req: IncomingMessage, token: string) {
+    const adminToken = req.headers['x-admin-token'];
+    if (typeof adminToken === 'string') return tokenMatches(`Bearer ${adminToken}`, token);
+    return tokenMatches(req.headers.authorization ?? '', token);
+}
+
+// the value of an authorization header, for the grpc api too
+export function tokenMatches(header: string, token: string) {
+    const given = Buffer.from(header.replace(/^Bearer /, ''));
+    const expected = Buffer.from(token);
+    return header.startsWith('Bearer ') && given.length === expected.length && timingSafeEqual(given, expected);
+}
+
+function sendJson(res: ServerResponse, status: number, body: unknown) {
+    res.writeHead(status, { 'Content-Type': 'application/json' });
+    res.end(JSON.stringify(body));
+}
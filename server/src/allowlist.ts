@@ -0,0 +1,28 @@
+import { BlockList, isIP } from 'net';
+
+// a single address ("192.168.1.4") or a range in cidr notation ("10.0.0.0/8", "2001:db8::/32")
+export function isRange(range: string) {
+    const [address, prefix, ...rest] = range.split('/');
+    const family = isIP(address ?? '');
+    if (family === 0 || rest.length > 0) return false;
+    if (prefix === undefined) return true;
+    const length = Number(prefix);
+    return /^\d+$/.test(prefix) && length <= (family === 4 ? 32 : 128);
+}
+
+// ranges must have been checked with isRange
+export function addressAllowed(address: string, ranges: string[]) {
+    const family = isIP(address);
+    if (family === 0) return false;
+    const list = new BlockList();
+    for (const range of ranges) {
+        const [network, prefix] = range.split('/') as [string, string | undefined];
+        const type = isIP(network) === 4 ? 'ipv4' : 'ipv6';
+        if (prefix === undefined) {
+            list.addAddress(network, type);
+        } else {
+            list.addSubnet(network, parseInt(prefix), type);
+        }
+    }
+    return list.check(address, family === 4 ? 'ipv4' : 'ipv6');
+}
@@ -0,0 +1,227 @@
+import fs from 'fs';
+import { createHash, timingSafeEqual } from 'crypto';
+import bcrypt from 'bcryptjs';
+import { Client as LdapClient, InvalidCredentialsError } from 'ldapts';
+import { oidcEnabled, verifyIdToken } from './oidc';
+
+// how the clients prove who they are when registering: oidc, tokens, htpasswd, ldap or introspection,
+// oidc by default when OIDC_ISSUER is set, no authentication otherwise
+const AUTH_BACKEND = process.env.AUTH_BACKEND ?? (oidcEnabled() ? 'oidc' : undefined);
+// lines of <account>:<token>, for the tokens backend
+const AUTH_TOKENS_FILE = process.env.AUTH_TOKENS_FILE;
+// htpasswd file of bcrypt, apr1 or sha1 entries, for the htpasswd backend
+const AUTH_HTPASSWD_FILE = process.env.AUTH_HTPASSWD_FILE;
+// ldap:// or ldaps:// url of the directory the users bind to, for the ldap backend
+const AUTH_LDAP_URL = process.env.AUTH_LDAP_URL;
+// dn of the users, where {user} is replaced by the name they give
+const AUTH_LDAP_USER_DN = process.env.AUTH_LDAP_USER_DN;
+// rfc 7662 token introspection endpoint the tokens are checked against, for the introspection backend
+const AUTH_INTROSPECTION_URL = process.env.AUTH_INTROSPECTION_URL;
+// the server's credentials at the identity provider, most of them require some to introspect
+const AUTH_INTROSPECTION_CLIENT_ID = process.env.AUTH_INTROSPECTION_CLIENT_ID;
+const AUTH_INTROSPECTION_CLIENT_SECRET = process.env.AUTH_INTROSPECTION_CLIENT_SECRET;
+const LDAP_TIMEOUT = 10_000;
+
+// what a client sends along its registration, each backend looks at its own part
+export interface Credentials {
+    id_token?: string; // from the login command
+    user?: string;
+    password?: string;
+    token?: string;
+}
+
+interface AuthBackend {
+    // told to the clients without the credentials the backend needs
+    missing: string;
+    // added to the reason a backend refused the credentials
+    retry: string;
+    // the account of the credentials, undefined when they lack what the backend needs,
+    // throws the reason when it refuses them
+    account(credentials: Credentials): Promise<string | undefined>;
+}
+
+function required(name: string, value: string | undefined) {
+    if (!value) {
+        console.error(`${name} must be set for the ${AUTH_BACKEND} authentication`);
+        process.exit(1);
+    }
+    return value;
+}
+
+const backends: Record<string, () => AuthBackend> = {
+    oidc: () => {
+        required('OIDC_ISSUER', process.env.OIDC_ISSUER);
+        return {
+            missing: 'This server needs you to log in first, with the login command',
+            retry: 'log in again',
+            account: async ({ id_token }) => (id_token ? verifyIdToken(id_token) : undefined)
+        };
+    },
+    tokens: () => {
+        const file = required('AUTH_TOKENS_FILE', AUTH_TOKENS_FILE);
+        return {
+            missing: 'This server needs a token, given with --auth-token',
+            retry: 'check --auth-token',
+            account: async ({ token }) => {
+                if (!token) return undefined;
+                // read each time, so tokens are added and revoked without a restart
+                for (const [account, expected] of entries(file)) {
+                    if (sameSecret(token, expected)) return account;
+                }
+                throw new Error('unknown token');
+            }
+        };
+    },
+    htpasswd: () => {
+        const file = required('AUTH_HTPASSWD_FILE', AUTH_HTPASSWD_FILE);
+        return {
+            missing: 'This server needs a user and a password, given with --auth-user',
+            retry: 'check --auth-user',
+            account: async ({ user, password }) => {
+                if (!user || password === undefined) return undefined;
+                const hash = entries(file).find(([name]) => name === user)?.[1];
+                if (!hash || !(await checkHtpasswd(password, hash))) throw new Error('wrong user or password');
+                return user;
+            }
+        };
+    },
+    ldap: () => {
+        const url = required('AUTH_LDAP_URL', AUTH_LDAP_URL);
+        const userDn = required('AUTH_LDAP_USER_DN', AUTH_LDAP_USER_DN);
+        return {
+            missing: 'This server needs a user and a password, given with --auth-user',
+            retry: 'check --auth-user',
+            account: async ({ user, password }) => {
+                if (!user || password === undefined) return undefined;
+                // an empty password is an unauthenticated bind, which directories accept for anyone
+                if (!password) throw new Error('wrong user or password');
+                const client = new LdapClient({ url, timeout: LDAP_TIMEOUT, connectTimeout: LDAP_TIMEOUT });
+                try {
+                    await client.bind(userDn.replaceAll('{user}', escapeDn(user)), password);
+                } catch (err) {
+                    if (err instanceof InvalidCredentialsError) throw new Error('wrong user or password');
+                    console.error('ldap bind failed:', err);
+                    throw new Error('the directory could not be reached');
+                } finally {
+                    await client.unbind().catch(() => {});
+                }
+                return user;
+            }
+        };
+    },
+    introspection: () => {
+        const url = required('AUTH_INTROSPECTION_URL', AUTH_INTROSPECTION_URL);
+        const headers: Record<string, string> = { 'Content-Type': 'application/x-www-form-urlencoded' };
+        if (AUTH_INTROSPECTION_CLIENT_ID) {
+            const basic = `${encodeURIComponent(AUTH_INTROSPECTION_CLIENT_ID)}:${encodeURIComponent(AUTH_INTROSPECTION_CLIENT_SECRET ?? '')}`;
+            headers.Authorization = `Basic ${Buffer.from(basic).toString('base64')}`;
+        }
+        return {
+            missing: 'This server needs an access token of its identity provider, given with --auth-token',
+            retry: 'get a new access token',
+            account: async ({ token }) => {
+                if (!token) return undefined;
+                const res = await fetch(url, { method: 'POST', headers, body: new URLSearchParams({ token }) });
+                if (!res.ok) {
+                    console.error(`token introspection answered ${res.status}`);
+                    throw new Error('the identity provider could not check the token');
+                }
+                const introspection = (await res.json()) as { active?: unknown; username?: unknown; sub?: unknown };
+                if (introspection.active !== true) throw new Error('inactive token');
+                const account = introspection.username ?? introspection.sub;
+                if (typeof account !== 'string') throw new Error('token without an account name');
+                return account;
+            }
+        };
+    }
+};
+
+if (AUTH_BACKEND && !backends[AUTH_BACKEND]) {
+    console.error(`unknown AUTH_BACKEND ${AUTH_BACKEND}, expected one of ${Object.keys(backends).join(', ')}`);
+    process.exit(1);
+}
+const backend = AUTH_BACKEND ? backends[AUTH_BACKEND]!() : undefined;
+
+export function authEnabled() {
+    return backend !== undefined;
+}
+
+// the account the client registers as, undefined without authentication,
+// throws the message to tell the client when it can't register
+export async function authenticate(credentials: Credentials) {
+    if (!backend) return undefined;
+    let account: string | undefined;
+    try {
+        account = await backend.account(credentials);
+    } catch (err) {
+        throw new Error(`Your credentials were refused (${(err as Error).message}), ${backend.retry}`);
+    }
+    if (account === undefined) throw new Error(backend.missing);
+    return account;
+}
+
+// the <name>:<value> lines of a file, without the blank ones and the comments
+function entries(file: string): [string, string][] {
+    return fs
+        .readFileSync(file)
+        .toString()
+        .split('\n')
+        .map(line => line.trim())
+        .filter(line => line && !line.startsWith('#') && line.includes(':'))
+        .map(line => [line.slice(0, line.indexOf(':')), line.slice(line.indexOf(':') + 1)]);
+}
+
+// hashed first so the comparison takes the same time whatever the lengths
+function sameSecret(given: string, expected: string) {
+    const digest = (s: string) => createHash('sha256').update(s).digest();
+    return timingSafeEqual(digest(given), digest(expected));
+}
+
+async function checkHtpasswd(password: string, hash: string) {
+    if (/^\$2[abxy]\$/.test(hash)) return bcrypt.compare(password, hash);
+    if (hash.startsWith('$apr1$')) return sameSecret(apr1(password, hash.split('$')[2] ?? ''), hash);
+    if (hash.startsWith('{SHA}')) {
+        return sameSecret(`{SHA}${createHash('sha1').update(password).digest('base64')}`, hash);
+    }
+    // crypt() entries and plain text passwords are refused rather than compared insecurely
+    console.error('unsupported htpasswd entry, only bcrypt, apr1 and sha1 are');
+    return false;
+}
+
+// the md5 based hash of apache, what htpasswd -m makes
+function apr1(password: string, salt: string) {
+    const md5 = (...parts: (string | Buffer)[]) => {
+        const hash = createHash('md5');
+        for (const part of parts) hash.update(part);
+        return hash.digest();
+    };
+    const pw = Buffer.from(password);
+    salt = salt.slice(0, 8);
+    let final = md5(pw, salt, pw);
+    const parts: (string | Buffer)[] = [pw, '$apr1$', salt];
+    for (let left = pw.length; left > 0; left -= 16) parts.push(final.subarray(0, Math.min(left, 16)));
+    for (let i = pw.length; i; i >>= 1) parts.push(i & 1 ? Buffer.alloc(1) : pw.subarray(0, 1));
+    final = md5(...parts);
+    for (let i = 0; i < 1000; i++) {
+        final = md5(i & 1 ? pw : final, i % 3 ? salt : '', i % 7 ? pw : '', i & 1 ? final : pw);
+    }
+
+    const alphabet = './0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz';
+    let encoded = '';
+    const encode = (value: number, length: number) => {
+        for (let i = 0; i < length; i++, value >>= 6) encoded += alphabet[value & 0x3f];
+    };
+    for (const [a, b, c] of [[0, 6, 12], [1, 7, 13], [2, 8, 14], [3, 9, 15], [4, 10, 5]] as const) {
+        encode((final[a]! << 16) | (final[b]! << 8) | final[c]!, 4);
+    }
+    encode(final[11]!, 2);
+    return `$apr1$${salt}$${encoded}`;
+}
+
+// rfc 4514, so a user name can't change the dn it binds with
+function escapeDn(value: string) {
+    return value
+        .replace(/[\\,+"<>;=\0]/g, c => (c === '\0' ? '\\00' : `\\${c}`))
+        .replace(/^[ #]/, c => `\\${c}`)
+        .replace(/ $/, '\\ ');
+}
@@ -0,0 +1,29 @@
+import fs from 'fs';
+
+// client uuids and ip addresses refused by the server, set through the admin apis and kept in bans.json
+let bans = new Set<string>();
+let bansFile: string | undefined;
+
+export function loadBans(path: string) {
+    bansFile = path;
+    if (fs.existsSync(bansFile)) {
+        bans = new Set(JSON.parse(fs.readFileSync(bansFile).toString()) as string[]);
+    }
+}
+
+export function isBanned(target: string | undefined) {
+    return target !== undefined && bans.has(target);
+}
+
+export function listBans() {
+    return [...bans];
+}
+
+// returns whether it changed anything
+export function setBanned(target: string, banned: boolean) {
+    if (bans.has(target) === banned) return false;
+    if (banned) bans.add(target);
+    else bans.delete(target);
+    if (bansFile) fs.writeFileSync(bansFile, JSON.stringify([...bans], null, 4));
+    return true;
+}
@@ -0,0 +1,30 @@
+// features a client or the server can announce on register, so new ones can be rolled out
+// without sending messages an older peer doesn't understand
+export const CAPABILITIES = [
+    'relay',
+    'p2p',
+    'quic',
+    'wireguard',
+    'heartbeat',
+    'multi_port',
+    'tunnel_stats',
+    'presence',
+    'receivers',
+    'invites',
+    'dual',
+    'teams',
+    'max_connections',
+    'host_ports',
+    'set_ports',
+    'notes',
+    'move_forward'
+] as const;
+export type Capability = (typeof CAPABILITIES)[number];
+
+// what clients from before the negotiation are assumed to handle
+const LEGACY_CAPABILITIES: readonly Capability[] = ['relay', 'p2p', 'quic', 'wireguard', 'heartbeat'];
+
+// capabilities is undefined for clients which didn't announce any, unknown names are ignored
+export function supports(capabilities: string[] | undefined, capability: Capability) {
+    return (capabilities ?? LEGACY_CAPABILITIES).includes(capability);
+}
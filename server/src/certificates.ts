@@ -0,0 +1,253 @@
+import fs from 'fs';
+import { createPublicKey, verify, KeyObject } from 'crypto';
+import { addressAllowed, isRange } from './allowlist';
+
+// public keys of the certificate authorities the clients' ssh certificates are signed by, one per line like
+// sshd's TrustedUserCAKeys. read on every registration, so an authority can be removed without a restart
+const SSH_CA_KEYS_FILE = process.env.SSH_CA_KEYS_FILE;
+// clients without a certificate of one of the authorities are refused
+const SSH_CERTIFICATE_REQUIRED = process.env.SSH_CERTIFICATE_REQUIRED === 'true';
+// tolerated clock difference with the authority
+const CLOCK_SKEW = 60;
+const USER_CERTIFICATE = 1;
+
+if (SSH_CA_KEYS_FILE && !fs.existsSync(SSH_CA_KEYS_FILE)) {
+    console.error(`SSH_CA_KEYS_FILE ${SSH_CA_KEYS_FILE} does not exist`);
+    process.exit(1);
+}
+if (SSH_CERTIFICATE_REQUIRED && !SSH_CA_KEYS_FILE) {
+    console.error('SSH_CERTIFICATE_REQUIRED needs SSH_CA_KEYS_FILE to be set');
+    process.exit(1);
+}
+
+// a certificate which passed the checks of verifyCertificate
+export interface SshCertificate {
+    ca: string; // the authority's key, as written in authorized_keys
+    keyId: string;
+    principal?: string; // the one the tunnels' sshd checks, undefined for a certificate valid for anyone
+    validBefore: number; // in seconds, Infinity when it never expires
+}
+
+// the fields of the certified key after its type, by type
+const KEY_FIELDS: Record<string, number> = {
+    'ssh-rsa': 2,
+    'ssh-ed25519': 1,
+    'ecdsa-sha2-nistp256': 2,
+    'ecdsa-sha2-nistp384': 2,
+    'ecdsa-sha2-nistp521': 2,
+    'sk-ssh-ed25519@openssh.com': 2,
+    'sk-ecdsa-sha2-nistp256@openssh.com': 3
+};
+
+const CURVES: Record<string, { crv: string; hash: string; size: number }> = {
+    nistp256: { crv: 'P-256', hash: 'sha256', size: 32 },
+    nistp384: { crv: 'P-384', hash: 'sha384', size: 48 },
+    nistp521: { crv: 'P-521', hash: 'sha512', size: 66 }
+};
+
+// checks the certificate a client registers with against the authorities, returns undefined when the server
+// has none or the client gave no certificate, throws the message to tell the client when it can't register
+export function verifyCertificate(
+    certificate: string | undefined,
+    sshKey: string,
+    account: string | undefined,
+    address: string | undefined
+): SshCertificate | undefined {
+    if (!SSH_CA_KEYS_FILE) return undefined;
+    if (!certificate) {
+        if (!SSH_CERTIFICATE_REQUIRED) return undefined;
+        throw new Error('This server needs an ssh certificate signed by its authority, given with --ssh-certificate');
+    }
+    try {
+        return checkCertificate(certificate, sshKey, account, address);
+    } catch (err) {
+        throw new Error(`Your ssh certificate was refused (${(err as Error).message})`);
+    }
+}
+
+function checkCertificate(
+    certificate: string,
+    sshKey: string,
+    account: string | undefined,
+    address: string | undefined
+): SshCertificate {
+    const [type, base64] = certificate.trim().split(/\s+/);
+    if (!type?.endsWith('-cert-v01@openssh.com') || !base64) throw new Error('not an ssh certificate');
+    const blob = Buffer.from(base64, 'base64');
+    const reader = new Reader(blob);
+    if (reader.string().toString() !== type) throw new Error('malformed certificate');
+    reader.string(); // nonce
+
+    // the certified key is the one the client registers with
+    const keyType = type.replace('-cert-v01@openssh.com', '') + (type.startsWith('sk-') ? '@openssh.com' : '');
+    const fields = KEY_FIELDS[keyType];
+    if (fields === undefined) throw new Error(`unsupported key type ${keyType}`);
+    const key = [encodeString(Buffer.from(keyType))];
+    for (let i = 0; i < fields; i++) key.push(encodeString(reader.string()));
+    const [registeredType, registeredKey] = sshKey.trim().split(/\s+/);
+    if (registeredType !== keyType || !Buffer.concat(key).equals(Buffer.from(registeredKey ?? '', 'base64'))) {
+        throw new Error('it certifies another key than the one you registered with');
+    }
+
+    reader.uint64(); // serial
+    if (reader.uint32() !== USER_CERTIFICATE) throw new Error('not a user certificate');
+    const keyId = reader.string().toString();
+    const principals = strings(reader.string()).map(principal => principal.toString());
+    const validAfter = reader.uint64();
+    const validBefore = reader.uint64();
+    const criticalOptions = options(reader.string());
+    const extensions = options(reader.string());
+    reader.string(); // reserved
+    const caKey = reader.string();
+    const signed = blob.subarray(0, reader.offset);
+    const signature = reader.string();
+
+    const ca = trustedAuthorities().find(ca => ca.blob.equals(caKey));
+    if (!ca) throw new Error('signed by an authority this server does not trust');
+    if (!verifySignature(caKey, signed, signature)) throw new Error('invalid signature');
+
+    const now = Date.now() / 1000;
+    if (validAfter > now + CLOCK_SKEW) throw new Error('not valid yet');
+    if (validBefore + CLOCK_SKEW < now) throw new Error('expired');
+    if (account !== undefined && principals.length > 0 && !principals.includes(account)) {
+        throw new Error(`issued for ${principals.join(', ')}, not for your account ${account}`);
+    }
+    // the tunnels' sshd applies them as well
+    if (!extensions.has('permit-port-forwarding')) throw new Error('it does not permit port forwarding');
+    for (const [name, value] of criticalOptions) {
+        if (name === 'source-address') {
+            const ranges = strings(value)[0]?.toString().split(',') ?? [];
+            if (!address || !ranges.every(isRange) || !addressAllowed(address, ranges)) {
+                throw new Error(`not valid from your address ${address}`);
+            }
+        } else if (name !== 'force-command') {
+            throw new Error(`unsupported critical option ${name}`);
+        }
+    }
+
+    return {
+        ca: ca.line,
+        keyId,
+        principal: account !== undefined && principals.includes(account) ? account : principals[0],
+        validBefore: validBefore >= Number.MAX_SAFE_INTEGER ? Infinity : validBefore
+    };
+}
+
+// the authorities' keys, without the blank lines and the comments
+function trustedAuthorities() {
+    return fs
+        .readFileSync(SSH_CA_KEYS_FILE!)
+        .toString()
+        .split('\n')
+        .map(line => line.trim().split(/\s+/))
+        .filter(([type, base64]) => type && !type.startsWith('#') && base64)
+        .map(([type, base64]) => ({ line: `${type} ${base64}`, blob: Buffer.from(base64!, 'base64') }));
+}
+
+// the signature of the authority, with the algorithms sshd accepts for certificates by default
+function verifySignature(caKey: Buffer, data: Buffer, signature: Buffer) {
+    const key = new Reader(caKey);
+    const keyType = key.string().toString();
+    const sig = new Reader(signature);
+    const algorithm = sig.string().toString();
+    const bytes = sig.string();
+    if (keyType === 'ssh-ed25519' && algorithm === keyType) {
+        return verify(null, data, jwk({ kty: 'OKP', crv: 'Ed25519', x: base64url(key.string()) }), bytes);
+    }
+    if (keyType === 'ssh-rsa' && (algorithm === 'rsa-sha2-256' || algorithm === 'rsa-sha2-512')) {
+        const e = key.string();
+        const n = key.string();
+        const publicKey = jwk({ kty: 'RSA', n: base64url(unsigned(n)), e: base64url(unsigned(e)) });
+        return verify(algorithm === 'rsa-sha2-256' ? 'sha256' : 'sha512', data, publicKey, bytes);
+    }
+    if (keyType.startsWith('ecdsa-sha2-') && algorithm === keyType) {
+        const curve = CURVES[key.string().toString()];
+        const point = key.string();
+        if (!curve || point[0] !== 4 || point.length !== 1 + 2 * curve.size) return false;
+        const x = point.subarray(1, 1 + curve.size);
+        const y = point.subarray(1 + curve.size);
+        const publicKey = jwk({ kty: 'EC', crv: curve.crv, x: base64url(x), y: base64url(y) });
+        // two mpints in ssh, the fixed size halves node expects
+        const rs = new Reader(bytes);
+        const p1363 = Buffer.concat([fixedSize(rs.string(), curve.size), fixedSize(rs.string(), curve.size)]);
+        return verify(curve.hash, data, { key: publicKey, dsaEncoding: 'ieee-p1363' }, p1363);
+    }
+    throw new Error(`unsupported signature algorithm ${algorithm}`);
+}
+
+function jwk(key: Record<string, string>): KeyObject {
+    return createPublicKey({ key, format: 'jwk' });
+}
+
+function base64url(data: Buffer) {
+    return data.toString('base64url');
+}
+
+// an mpint without the leading zero of positive numbers
+function unsigned(mpint: Buffer) {
+    let start = 0;
+    while (start < mpint.length - 1 && mpint[start] === 0) start++;
+    return mpint.subarray(start);
+}
+
+function fixedSize(mpint: Buffer, size: number) {
+    const value = unsigned(mpint);
+    if (value.length > size) throw new Error('malformed signature');
+    return Buffer.concat([Buffer.alloc(size - value.length), value]);
+}
+
+function encodeString(data: Buffer) {
+    const length = Buffer.alloc(4);
+    length.writeUInt32BE(data.length);
+    return Buffer.concat([length, data]);
+}
+
+// the strings packed in a field, like the principals
+function strings(data: Buffer) {
+    const reader = new Reader(data);
+    const values: Buffer[] = [];
+    while (reader.offset < data.length) values.push(reader.string());
+    return values;
+}
+
+// the name and data of the critical options and extensions
+function options(data: Buffer) {
+    const reader = new Reader(data);
+    const values = new Map<string, Buffer>();
+    while (reader.offset < data.length) values.set(reader.string().toString(), reader.string());
+    return values;
+}
+
+// reads the big endian fields of the ssh wire format, throws past the end of the data
+class Reader {
+    offset = 0;
+
+    constructor(private data: Buffer) {}
+
+    uint32() {
+        this.need(4);
+        const value = this.data.readUInt32BE(this.offset);
+        this.offset += 4;
+        return value;
+    }
+
+    // as a number, which is exact up to 2^53: enough for dates, and a serial is only skipped
+    uint64() {
+        this.need(8);
+        const value = Number(this.data.readBigUInt64BE(this.offset));
+        this.offset += 8;
+        return value;
+    }
+
+    string() {
+        const length = this.uint32();
+        this.need(length);
+        const value = this.data.subarray(this.offset, this.offset + length);
+        this.offset += length;
+        return value;
+    }
+
+    private need(length: number) {
+        if (this.offset + length > this.data.length) throw new Error('malformed certificate');
+    }
+}
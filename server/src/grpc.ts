@@ -0,0 +1,136 @@
+import * as grpc from '@grpc/grpc-js';
+import * as protoLoader from '@grpc/proto-loader';
+import fs from 'fs';
+import path from 'path';
+import { AdminActions, AdminTunnel, tokenMatches } from './admin';
+import type { TunnelStats } from './schema';
+
+// shipped next to package.json, it is also what clients are generated from
+const PROTO_FILE = path.resolve(__dirname, '..', 'admin.proto');
+const DEFAULT_WATCH_INTERVAL = 5;
+
+interface TunnelRequest {
+    id: string;
+}
+interface ClientRequest {
+    uuid: string;
+}
+interface BanRequest {
+    target: string;
+}
+interface WatchRequest {
+    interval_seconds: number;
+}
+
+// a failed call, with the grpc status it answers
+class CallError extends Error {
+    constructor(
+        readonly code: grpc.status,
+        message: string
+    ) {
+        super(message);
+    }
+}
+
+// the Admin service of admin.proto on the port, doing what the http admin api does.
+// plaintext unless a certificate and its key are given
+export function startGrpcAdmin(
+    host: string,
+    port: number,
+    token: string,
+    actions: AdminActions,
+    tls?: { cert: string; key: string }
+) {
+    const definition = protoLoader.loadSync(PROTO_FILE, { keepCase: true, longs: Number, defaults: true });
+    const proto = grpc.loadPackageDefinition(definition);
+    const admin = ((proto.kensa as grpc.GrpcObject).admin as grpc.GrpcObject).v1 as grpc.GrpcObject;
+    const service = (admin.Admin as grpc.ServiceClientConstructor).service;
+
+    const checkToken = (metadata: grpc.Metadata) => {
+        const header = metadata.get('authorization')[0]?.toString() ?? '';
+        if (!tokenMatches(header, token)) throw new CallError(grpc.status.UNAUTHENTICATED, 'invalid token');
+    };
+    const unary =
+        <Request>(handler: (request: Request) => object) =>
+        (call: grpc.ServerUnaryCall<Request, object>, callback: grpc.sendUnaryData<object>) => {
+            try {
+                checkToken(call.metadata);
+                callback(null, handler(call.request));
+            } catch (err) {
+                const code = err instanceof CallError ? err.code : grpc.status.INTERNAL;
+                callback({ code, details: (err as Error).message });
+            }
+        };
+    const found = (exists: boolean, what: string) => {
+        if (!exists) throw new CallError(grpc.status.NOT_FOUND, `no such ${what}`);
+        return {};
+    };
+
+    const server = new grpc.Server();
+    server.addService(service, {
+        ListTunnels: unary(() => ({ tunnels: actions.tunnels().map(protoTunnel) })),
+        WatchTunnels: (call: grpc.ServerWritableStream<WatchRequest, object>) => {
+            try {
+                checkToken(call.metadata);
+            } catch (err) {
+                call.emit('error', { code: grpc.status.UNAUTHENTICATED, details: (err as Error).message });
+                return;
+            }
+            const send = () => call.write({ tunnels: actions.tunnels().map(protoTunnel) });
+            send();
+            const interval = setInterval(send, (call.request.interval_seconds || DEFAULT_WATCH_INTERVAL) * 1000);
+            call.on('cancelled', () => clearInterval(interval));
+            call.on('close', () => clearInterval(interval));
+        },
+        CloseTunnel: unary(({ id }: TunnelRequest) => found(actions.closeTunnel(id), 'tunnel')),
+        ListClients: unary(() => ({ clients: actions.clients().map(nullsToUnset) })),
+        KickClient: unary(({ uuid }: ClientRequest) => found(actions.kickClient(uuid), 'client')),
+        Ban: unary(({ target }: BanRequest) => {
+            if (!target) throw new CallError(grpc.status.INVALID_ARGUMENT, 'the target is empty');
+            actions.setBanned(target, true);
+            return {};
+        }),
+        Unban: unary(({ target }: BanRequest) => {
+            actions.setBanned(target, false);
+            return {};
+        }),
+        ListBans: unary(() => ({ targets: actions.bans() })),
+        Cleanup: unary(() => actions.cleanup())
+    });
+
+    const credentials = tls
+        ? grpc.ServerCredentials.createSsl(null, [
+              { cert_chain: fs.readFileSync(tls.cert), private_key: fs.readFileSync(tls.key) }
+          ])
+        : grpc.ServerCredentials.createInsecure();
+    server.bindAsync(`${host}:${port}`, credentials, err => {
+        if (err) {
+            console.error(`failed to start the grpc admin api: ${err.message}`);
+            process.exit(1);
+        }
+        console.log(`grpc admin api started on ${host}:${port}${tls ? '' : ' without tls'}`);
+    });
+}
+
+function protoTunnel(tunnel: AdminTunnel) {
+    const { stats, ...rest } = tunnel;
+    return {
+        ...nullsToUnset(rest),
+        sender_stats: stats.sender ? protoStats(stats.sender) : undefined,
+        receiver_stats: stats.receiver ? protoStats(stats.receiver) : undefined
+    };
+}
+
+function protoStats(stats: TunnelStats) {
+    return nullsToUnset({
+        bytes_sent: stats.bytes_sent,
+        bytes_received: stats.bytes_received,
+        active_connections: stats.active_connections,
+        rtt_ms: stats.rtt_ms
+    });
+}
+
+// the json of the http api uses null where protobuf leaves optional fields unset
+function nullsToUnset<T extends object>(value: T) {
+    return Object.fromEntries(Object.entries(value).filter(([, v]) => v !== null && v !== undefined));
+}
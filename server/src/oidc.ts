@@ -0,0 +1,84 @@
+import type { IncomingMessage, ServerResponse } from 'http';
+import { createPublicKey, verify, JsonWebKey } from 'crypto';
+
+// clients must log in with this identity provider before registering when set
+const OIDC_ISSUER = process.env.OIDC_ISSUER?.replace(/\/$/, '');
+// the client id registered at the identity provider, the id tokens must be issued for it
+const OIDC_CLIENT_ID = process.env.OIDC_CLIENT_ID;
+// claim of the id token naming the account, falls back on the email then the subject
+const OIDC_ACCOUNT_CLAIM = process.env.OIDC_ACCOUNT_CLAIM ?? 'preferred_username';
+// tolerated clock difference with the identity provider
+const CLOCK_SKEW = 60;
+// the keys are fetched again when a token is signed by an unknown one, at most this often
+const JWKS_REFRESH_INTERVAL = 60_000;
+
+if (OIDC_ISSUER && !OIDC_CLIENT_ID) {
+    console.error('OIDC_CLIENT_ID must be set along with OIDC_ISSUER');
+    process.exit(1);
+}
+
+let jwks: (JsonWebKey & { kid?: string })[] = [];
+let jwksFetchedAt = 0;
+
+export function oidcEnabled() {
+    return OIDC_ISSUER !== undefined;
+}
+
+// GET /oidc tells the clients which identity provider to log in with, answers false for other paths
+export function oidcHandler(req: IncomingMessage, res: ServerResponse) {
+    if (req.method !== 'GET' || req.url !== '/oidc') return false;
+    res.writeHead(oidcEnabled() ? 200 : 404, { 'Content-Type': 'application/json' });
+    res.end(JSON.stringify(oidcEnabled() ? { issuer: OIDC_ISSUER, client_id: OIDC_CLIENT_ID } : { error: 'not found' }));
+    return true;
+}
+
+// returns the account the token was issued to, throws when the token can't be trusted
+export async function verifyIdToken(token: string) {
+    const [header, payload, signature] = token.split('.');
+    if (!header || !payload || signature === undefined) throw new Error('malformed token');
+    const { alg, kid } = JSON.parse(Buffer.from(header, 'base64url').toString());
+    const claims = JSON.parse(Buffer.from(payload, 'base64url').toString());
+
+    const key = await findKey(kid);
+    const data = Buffer.from(`${header}.${payload}`);
+    const sig = Buffer.from(signature, 'base64url');
+    const publicKey = createPublicKey({ key, format: 'jwk' });
+    let valid: boolean;
+    if (alg === 'RS256') {
+        valid = verify('sha256', data, publicKey, sig);
+    } else if (alg === 'ES256') {
+        valid = verify('sha256', data, { key: publicKey, dsaEncoding: 'ieee-p1363' }, sig);
+    } else {
+        throw new Error(`unsupported signature algorithm ${alg}`);
+    }
+    if (!valid) throw new Error('invalid signature');
+
+    const now = Date.now() / 1000;
+    const audiences = Array.isArray(claims.aud) ? claims.aud : [claims.aud];
+    if (claims.iss?.replace(/\/$/, '') !== OIDC_ISSUER) throw new Error('token issued by another provider');
+    if (!audiences.includes(OIDC_CLIENT_ID)) throw new Error('token issued for another client');
+    if (typeof claims.exp !== 'number' || claims.exp + CLOCK_SKEW < now) throw new Error('token expired');
+    if (typeof claims.nbf === 'number' && claims.nbf - CLOCK_SKEW > now) throw new Error('token not valid yet');
+
+    const account = claims[OIDC_ACCOUNT_CLAIM] ?? claims.email ?? claims.sub;
+    if (typeof account !== 'string') throw new Error('token without an account name');
+    return account;
+}
+
+async function findKey(kid: string | undefined) {
+    const find = () => jwks.find(k => kid === undefined || k.kid === kid);
+    if (!find() && Date.now() - jwksFetchedAt > JWKS_REFRESH_INTERVAL) {
+        jwksFetchedAt = Date.now();
+        const configuration = await fetchJson(`${OIDC_ISSUER}/.well-known/openid-configuration`);
+        jwks = (await fetchJson(configuration.jwks_uri)).keys ?? [];
+    }
+    const key = find();
+    if (!key) throw new Error('token signed by an unknown key');
+    return key;
+}
+
+async function fetchJson(url: string) {
+    const res = await fetch(url);
+    if (!res.ok) throw new Error(`${url} answered ${res.status}`);
+    return res.json();
+}
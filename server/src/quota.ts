@@ -0,0 +1,105 @@
+import fs from 'fs';
+import { Quota, quotasSchema } from './schema';
+
+// who is charged for a client's traffic: its account when it logged in, its address otherwise. the uuid is chosen
+// by the client, a new one would come with a new budget, so a quota set for a uuid is only advisory:
+// the usage it is checked against is still the one of the account or address
+export interface QuotaSubject {
+    uuid: string;
+    account?: string;
+    address?: string;
+}
+
+interface Usage {
+    day: string; // YYYY-MM-DD
+    daily: number;
+    month: string; // YYYY-MM
+    monthly: number;
+}
+
+let quotas: Record<string, Quota> = {};
+let usage: Record<string, Usage> = {};
+let usageFile: string | undefined;
+let dirty = false;
+
+export function loadQuotas(quotasFile: string, usagePath: string) {
+    quotas = quotasSchema.parse(JSON.parse(fs.readFileSync(quotasFile).toString()));
+    usageFile = usagePath;
+    if (fs.existsSync(usageFile)) {
+        usage = JSON.parse(fs.readFileSync(usageFile).toString());
+    }
+}
+
+export function quotasEnabled() {
+    return usageFile !== undefined;
+}
+
+// like "account:alice" or "address:203.0.113.7", which are also the keys of their quotas
+function usageKey(subject: QuotaSubject) {
+    if (subject.account !== undefined) return `account:${subject.account}`;
+    return subject.address !== undefined ? `address:${subject.address}` : subject.uuid;
+}
+
+function quotaOf(subject: QuotaSubject): Quota | undefined {
+    const key = [usageKey(subject), subject.uuid, '*'].find(key => Object.hasOwn(quotas, key));
+    return key === undefined ? undefined : quotas[key];
+}
+
+function usageOf(subject: QuotaSubject) {
+    const now = new Date().toISOString();
+    const day = now.slice(0, 10);
+    const month = now.slice(0, 7);
+    const key = usageKey(subject);
+    let entry = Object.hasOwn(usage, key) ? usage[key] : undefined;
+    if (!entry) {
+        entry = { day, daily: 0, month, monthly: 0 };
+        usage[key] = entry;
+    }
+    if (entry.day !== day) {
+        entry.day = day;
+        entry.daily = 0;
+    }
+    if (entry.month !== month) {
+        entry.month = month;
+        entry.monthly = 0;
+    }
+    return entry;
+}
+
+export function recordUsage(subject: QuotaSubject, bytes: number) {
+    if (!quotasEnabled() || bytes <= 0) return;
+    const entry = usageOf(subject);
+    entry.daily += bytes;
+    entry.monthly += bytes;
+    dirty = true;
+}
+
+export function isOverQuota(subject: QuotaSubject) {
+    const quota = quotaOf(subject);
+    if (!quota) return false;
+    const entry = usageOf(subject);
+    return (
+        (quota.daily !== undefined && entry.daily >= quota.daily) ||
+        (quota.monthly !== undefined && entry.monthly >= quota.monthly)
+    );
+}
+
+// the quota_status message sent to the client, undefined if it has no quota
+export function quotaStatus(subject: QuotaSubject) {
+    const quota = quotaOf(subject);
+    if (!quota) return undefined;
+    const entry = usageOf(subject);
+    return {
+        type: 'quota_status',
+        daily_used: entry.daily,
+        daily_limit: quota.daily,
+        monthly_used: entry.monthly,
+        monthly_limit: quota.monthly
+    };
+}
+
+export function saveUsage() {
+    if (!usageFile || !dirty) return;
+    fs.writeFileSync(usageFile, JSON.stringify(usage));
+    dirty = false;
+}
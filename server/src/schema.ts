@@ -1,28 +1,186 @@
 import { z } from 'zod';
+import { isRange } from './allowlist';
 
 export const portSchema = z.number().positive().max(65_535);
 export const clientTypeSchema = z.enum(['sender', 'receiver']);
 export type ClientType = z.infer<typeof clientTypeSchema>;
+// a client registered as both hosts and connects to other hosts on the same socket
+export const registerTypeSchema = z.enum(['sender', 'receiver', 'both']);
+export type RegisterType = z.infer<typeof registerTypeSchema>;
+export const noticeLevelSchema = z.enum(['info', 'warning', 'critical']);
+export type NoticeLevel = z.infer<typeof noticeLevelSchema>;
+export type ErrorCode =
+    | 'unauthorized'
+    | 'host_offline'
+    | 'ambiguous_target'
+    | 'quota_exceeded'
+    | 'port_not_allowed'
+    | 'address_not_allowed'
+    | 'host_busy'
+    | 'host_full'
+    | 'server_full'
+    | 'tunnel_failed'
+    | 'denied'
+    | 'host_timeout'
+    | 'rate_limited'
+    | 'invalid_message'
+    | 'internal_error'
+    | 'unsupported'
+    | 'not_found'
+    | 'key_refused';
+
+// what a client measures of its tunnel, missing when its transport can't tell (ssh runs in another process)
+export const tunnelStatsSchema = z.object({
+    bytes_sent: z.number().int().nonnegative().nullish(),
+    bytes_received: z.number().int().nonnegative().nullish(),
+    active_connections: z.number().int().nonnegative().nullish(),
+    rtt_ms: z.number().int().nonnegative().nullish() // round trip time of the control channel
+});
+export type TunnelStats = z.infer<typeof tunnelStatsSchema>;
+
+// optional on every message, echoed in the answers to it
+export const requestIdSchema = z.number().int().nonnegative().optional();
 
 export const messagesSchema = z.discriminatedUnion('type', [
     z.object({
         type: z.literal('register'),
         ssh_key: z.string(),
+        ssh_certificate: z.string().max(16384).optional(), // certificate of the ssh key, see certificates.ts
         uuid: z.string(),
         auto_accept: z.boolean(),
         port_whitelist: portSchema.array(),
         port_blacklist: portSchema.array(),
-        client_type: clientTypeSchema
+        source_allowlist: z.string().refine(isRange, 'invalid ip range').array().max(64).optional(), // receivers allowed to connect to a sender
+        client_type: registerTypeSchema, // both needs the dual capability
+        capabilities: z.string().array().max(64).optional(), // see capabilities.ts, missing for older clients
+        label: z.string().max(64).optional(), // name of the hosting session, shown to receivers and admins
+        id_token: z.string().max(8192).optional(), // from the server's identity provider, for its oidc authentication
+        credentials: z
+            .object({
+                user: z.string().max(256).optional(),
+                password: z.string().max(1024).optional(),
+                token: z.string().max(8192).optional()
+            })
+            .optional(), // for the other authentication backends, see auth.ts
+        team: z.string().max(64).optional(), // hosts under this team, only its members see and reach the host
+        max_connections: z.number().int().positive().max(1024).optional(), // receivers the host takes at once
+        port_aliases: z
+            .record(z.string().regex(/^[\w-]{1,32}$/), portSchema)
+            .refine(aliases => Object.keys(aliases).length <= 64, 'too many port aliases')
+            .optional(), // names receivers can give the host's ports by, like web for 8080
+        whitelist_only: z.boolean().optional(), // an empty whitelist shares no port instead of every port
+        port_precedence: z.enum(['blacklist', 'whitelist']).optional() // the list deciding for a port in both, whitelist for older clients
     }),
     z.object({
         type: z.literal('connect_to_host'),
         target: z.string(),
-        port: portSchema
+        port: portSchema,
+        relay: z.boolean().optional(),
+        p2p: z.boolean().optional(),
+        quic: z.boolean().optional(), // use quic for the direct connection, only with p2p
+        wireguard: z.boolean().optional(),
+        invite: z.string().max(64).optional() // token minted by the host with create_invite, accepted without asking it
     }),
     z.object({
         type: z.literal('connect_accept')
     }),
     z.object({
         type: z.literal('connect_deny')
+    }),
+    z.object({
+        type: z.literal('heartbeat')
+    }),
+    z.object({
+        type: z.literal('kick_receiver'),
+        receiver: z.string() // uuid of the receiver
+    }),
+    z.object({
+        type: z.literal('create_invite'), // answered with invite_created
+        port: portSchema // the only port the invite can be used for
+    }),
+    z.object({
+        type: z.literal('list_receivers') // answered with receiver_usage
+    }),
+    z.object({
+        type: z.literal('set_ports'), // replaces the whitelist of the host, with the set_ports capability
+        port_whitelist: portSchema.array().max(1024)
+    }),
+    z.object({
+        type: z.literal('send_note'), // relayed as note to the peers the client has a tunnel with, with the notes capability
+        text: z.string().min(1).max(500)
+    }),
+    z.object({
+        type: z.literal('list_host_ports'), // answered with host_ports
+        target: z.string().max(128) // like the target of connect_to_host
+    }),
+    z.object({
+        type: z.literal('list_team_hosts'), // answered with team_hosts
+        team: z.string().max(64)
+    }),
+    z.object({
+        type: z.literal('revoke_receiver'), // kicks the receiver and refuses it until the host disconnects
+        receiver: z.string().max(64)
+    }),
+    z.object({
+        type: z.literal('subscribe_host'),
+        target: z.string().max(64) // uuid of the host
+    }),
+    z.object({
+        type: z.literal('unsubscribe_host'),
+        target: z.string().max(64)
+    }),
+    tunnelStatsSchema.extend({
+        type: z.literal('tunnel_stats')
+    }),
+    z.object({
+        type: z.literal('add_port'),
+        port: portSchema
+    }),
+    z.object({
+        type: z.literal('forward_failed'),
+        port: portSchema.optional() // an added port whose forward failed, the whole tunnel when unset
+    }),
+    z.object({
+        type: z.literal('relay_open'),
+        stream: z.number().int().nonnegative()
+    }),
+    z.object({
+        type: z.literal('relay_close'),
+        stream: z.number().int().nonnegative()
+    }),
+    z.object({
+        type: z.literal('p2p_candidates'),
+        candidates: z.string().array().max(16)
+    }),
+    z.object({
+        type: z.literal('p2p_result'),
+        success: z.boolean()
+    }),
+    z.object({
+        type: z.literal('wireguard_peer'),
+        public_key: z.string(),
+        port: portSchema,
+        endpoint: z.string().nullish() // filled in by the server
     })
 ]);
+
+const quotaSchema = z.object({
+    daily: z.number().nonnegative().optional(), // bytes
+    monthly: z.number().nonnegative().optional() // bytes
+});
+export type Quota = z.infer<typeof quotaSchema>;
+
+// quotas by "account:<name>", "address:<ip>" or client uuid (advisory, see quota.ts), in this order of precedence.
+// "*" applies to clients without their own entry
+export const quotasSchema = z.record(z.string(), quotaSchema);
+
+// a host may host under the team and connect to its hosts, a member may only connect
+export const teamRoleSchema = z.enum(['host', 'member']);
+export type TeamRole = z.infer<typeof teamRoleSchema>;
+const teamSchema = z.object({
+    members: z.record(z.string(), teamRoleSchema) // by account name, as given by the identity provider
+});
+export type Team = z.infer<typeof teamSchema>;
+
+// teams by name
+export const teamsSchema = z.record(z.string(), teamSchema);
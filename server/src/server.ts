@@ -1,25 +1,72 @@
-import { createServer } from 'http';
+import { createServer, IncomingMessage } from 'http';
+import net from 'net';
 import ws from 'ws';
 import { ZodError } from 'zod';
-import { ClientType, messagesSchema } from './schema';
+import {
+    ClientType,
+    RegisterType,
+    ErrorCode,
+    messagesSchema,
+    NoticeLevel,
+    requestIdSchema,
+    TunnelStats,
+    tunnelStatsSchema
+} from './schema';
 import { ChildProcess, spawn, execSync, spawnSync } from 'child_process';
+import { randomUUID } from 'crypto';
 import fs from 'fs';
 import path from 'path';
+import {
+    addLocalPort,
+    addReceiver,
+    addTunnel,
+    getTunnelTraffic,
+    removeReceiver,
+    embeddedTunnelUsers,
+    generateHostKey,
+    removeTunnel,
+    startEmbeddedSSH
+} from './ssh';
+import { emitEvent } from './webhooks';
+import { addressAllowed, isRange } from './allowlist';
+import { holdsSocket, loginSockets, trackLogins } from './sessions';
+import { adminHandler, AdminActions, CleanupReport } from './admin';
+import { isBanned, listBans, loadBans, setBanned } from './bans';
+import { startGrpcAdmin } from './grpc';
+import { authEnabled, authenticate } from './auth';
+import { SshCertificate, verifyCertificate } from './certificates';
+import { oidcHandler } from './oidc';
+import { ClientSocket, sseHandler, sseSessions } from './sse';
+import { canHost, isMember, loadTeams } from './teams';
+import { CAPABILITIES, supports } from './capabilities';
+import { isOverQuota, loadQuotas, quotasEnabled, quotaStatus, recordUsage, saveUsage } from './quota';
 
-let SSHD = '';
-try {
-    SSHD = execSync('which sshd').toString().trim();
-} catch {
-    console.log('no sshd found');
+// "sshd" spawns a system sshd per tunnel, "embedded" serves every tunnel from an in-process ssh server
+const SSH_BACKEND = process.env.SSH_BACKEND ?? 'sshd';
+if (SSH_BACKEND !== 'sshd' && SSH_BACKEND !== 'embedded') {
+    console.error('SSH_BACKEND must be either "sshd" or "embedded"');
     process.exit(1);
 }
 
-if (!fs.existsSync(SSHD)) {
-    console.error('no sshd found');
-    process.exit(1);
+let SSHD = '';
+if (SSH_BACKEND === 'sshd') {
+    try {
+        SSHD = execSync('which sshd').toString().trim();
+    } catch {
+        console.log('no sshd found');
+        process.exit(1);
+    }
+
+    if (!fs.existsSync(SSHD)) {
+        console.error('no sshd found');
+        process.exit(1);
+    }
 }
 
 const SERVER_PORT = parseInt(process.env.SERVER_PORT ?? '7856');
+// the path the server is reached under when a proxy does not strip it, like /portfwd for
+// https://tools.example.com/portfwd/, the clients are given the full url
+const BASE_PATH = (process.env.BASE_PATH ?? '').replace(/\/+$/, '').replace(/^(?=[^/])/, '/');
 const FORWARDING_USER = process.env.FORWARDING_USER;
 const OPENED_PORTS = (process.env.OPENED_PORTS ?? '')
     .split(',')
@@ -36,315 +83,1768 @@ if (!fs.existsSync(KEYS_FOLDER)) {
 
 for (const key of KEYS) {
     if (!fs.existsSync(key)) {
-        const keyType = path.parse(key).name.split('_').at(-2)!;
-        const args = ['-t', keyType];
-        if (keyType == 'rsa') {
-            args.push('-b');
-            args.push('4096');
-        }
-        if (keyType == 'ecsda') {
-            args.push('-b');
-            args.push('521');
+        const keyType = path.parse(key).name.split('_').at(-2) as 'rsa' | 'ecdsa' | 'ed25519';
+        const bits = { rsa: 4096, ecdsa: 521, ed25519: undefined }[keyType];
+        if (SSH_BACKEND === 'embedded') {
+            generateHostKey(key, keyType, bits);
+        } else {
+            const args = ['-t', keyType];
+            if (bits) {
+                args.push('-b');
+                args.push(bits.toString());
+            }
+            args.push('-f');
+            args.push(key);
+            // args.push('-N');
+            // args.push('""');
+            spawnSync('ssh-keygen', args);
         }
-        args.push('-f');
-        args.push(key);
-        // args.push('-N');
-        // args.push('""');
-        spawnSync('ssh-keygen', args);
         console.log('generated', key);
     }
 }
 
-if (!FORWARDING_USER) {
-    console.error('please specify the FORWARDING_USER env variable');
-    process.exit(1);
-} else {
-    const userCheck = spawnSync('getent', ['passwd', FORWARDING_USER]);
-    if (userCheck.status != 0) {
-        console.error(`user ${FORWARDING_USER} does not exists`);
+const SSH_PORT = parseInt(process.env.SSH_PORT ?? '7857');
+// tunnels without any traffic for this many seconds get closed, 0 disables it
+const TUNNEL_IDLE_TIMEOUT = parseInt(process.env.TUNNEL_IDLE_TIMEOUT ?? '0');
+// tunnels are closed this many seconds after being opened whatever their activity, 0 disables it
+const TUNNEL_MAX_LIFETIME = parseInt(process.env.TUNNEL_MAX_LIFETIME ?? '0');
+// how many seconds before the end of their lifetime both clients get warned
+const TUNNEL_LIFETIME_WARNING = parseInt(process.env.TUNNEL_LIFETIME_WARNING ?? '300');
+const MONITOR_INTERVAL = 30_000;
+// seconds between the checks for users, authorized_keys files and sshd instances no tunnel owns, 0 disables it
+const CLEANUP_INTERVAL = parseInt(process.env.CLEANUP_INTERVAL ?? '300');
+// clients which send heartbeats are disconnected after this many seconds without hearing from them
+const HEARTBEAT_TIMEOUT = parseInt(process.env.HEARTBEAT_TIMEOUT ?? '120');
+const HEARTBEAT_CHECK_INTERVAL = 15_000;
+// seconds a host has to accept or deny a connection request
+const CONNECT_CONFIRM_TIMEOUT = parseInt(process.env.CONNECT_CONFIRM_TIMEOUT ?? '60');
+// websocket and server-sent events sessions a single ip address may hold at once, 0 disables it
+const MAX_SESSIONS_PER_IP = parseInt(process.env.MAX_SESSIONS_PER_IP ?? '0');
+// tunnels the receivers of a single ip address may have open at once, 0 disables it
+const MAX_TUNNELS_PER_IP = parseInt(process.env.MAX_TUNNELS_PER_IP ?? '0');
+
+const DATA_FOLDER = process.env.DATA_FOLDER ?? 'data';
+if (!fs.existsSync(DATA_FOLDER)) {
+    fs.mkdirSync(DATA_FOLDER);
+}
+
+// the content of this file is shown to every client when it registers, and broadcast whenever it changes
+const NOTICE_FILE = path.resolve(DATA_FOLDER, 'notice.txt');
+
+// json file mapping accounts, addresses and client uuids to their daily/monthly transfer quotas (in bytes)
+const QUOTAS_FILE = process.env.QUOTAS_FILE;
+if (QUOTAS_FILE) {
+    loadQuotas(QUOTAS_FILE, path.resolve(DATA_FOLDER, 'usage.json'));
+}
+// json file of the teams, with the role of each member account, needs an AUTH_BACKEND to know who the clients are
+const TEAMS_FILE = process.env.TEAMS_FILE;
+if (TEAMS_FILE) {
+    if (!authEnabled()) {
+        console.error('TEAMS_FILE needs AUTH_BACKEND or OIDC_ISSUER to be set');
         process.exit(1);
     }
+    loadTeams(TEAMS_FILE);
 }
-
-const FORWARDING_USER_HOME = path.join('/home', FORWARDING_USER!);
-if (!fs.existsSync(FORWARDING_USER_HOME)) {
-    console.error(`cannot find home for "${FORWARDING_USER}" at "${FORWARDING_USER_HOME}"`);
+// comma separated ip ranges receivers must connect from, on top of the allowlist each sender can set
+const RECEIVER_ALLOWLIST = (process.env.RECEIVER_ALLOWLIST ?? '')
+    .split(',')
+    .map(e => e.trim())
+    .filter(e => e);
+const invalidRange = RECEIVER_ALLOWLIST.find(range => !isRange(range));
+if (invalidRange) {
+    console.error(`invalid ip range "${invalidRange}" in RECEIVER_ALLOWLIST`);
     process.exit(1);
 }
+// first port handed out as the virtual forwarding port when using the embedded backend
+const EMBEDDED_LOCAL_PORT_START = 10_000;
+// number of /30 subnets which fit in the 10.77.0.0/16 used by wireguard tunnels
+const WIREGUARD_MAX_SUBNETS = 16_384;
+// ports a receiver can add to an ssh tunnel after it was opened, sshd needs them reserved upfront
+const TUNNEL_EXTRA_PORTS = parseInt(process.env.TUNNEL_EXTRA_PORTS ?? '4');
+// how many times a tunnel is moved to another port when the sender fails to bind it
+const MAX_FORWARD_RETRIES = 3;
 
-if (OPENED_PORTS.length === 0) {
-    console.error(
-        'please set the OPENED_PORTS env variable to contain a list (comma separated) of opened port for the sshd instances'
-    );
+// "shared" logs every tunnel in as FORWARDING_USER, "per-tunnel" creates a throwaway user for each tunnel
+const TUNNEL_USERS = process.env.TUNNEL_USERS ?? 'shared';
+if (TUNNEL_USERS !== 'shared' && TUNNEL_USERS !== 'per-tunnel') {
+    console.error('TUNNEL_USERS must be either "shared" or "per-tunnel"');
     process.exit(1);
 }
+const TUNNEL_USER_PREFIX = 'kpf-';
+// empty folder owned by root sshd locks the tunnel users in once they logged in, empty to disable it
+const TUNNEL_CHROOT = process.env.TUNNEL_CHROOT ?? '/var/empty';
+// shells of the accounts which can't log in, the only ones tunnel users may have
+const NO_LOGIN_SHELLS = ['/usr/sbin/nologin', '/sbin/nologin', '/bin/false', '/usr/bin/false'];
 
-const httpServer = createServer();
-const wss = new ws.Server({ server: httpServer });
+// folder holding the authorized_keys files of each tunnel, out of the tunnel users' reach
+const AUTHORIZED_KEYS_FOLDER = path.resolve(KEYS_FOLDER, 'authorized_keys');
+if (SSH_BACKEND === 'sshd' && TUNNEL_USERS === 'shared') {
+    if (!FORWARDING_USER) {
+        console.error('please specify the FORWARDING_USER env variable');
+        process.exit(1);
+    }
+    const entry = spawnSync('getent', ['passwd', FORWARDING_USER]);
+    if (entry.status !== 0) {
+        // created like the per-tunnel users, without a shell or a home
+        if (!addTunnelUser(FORWARDING_USER)) process.exit(1);
+    } else {
+        const shell = entry.stdout.toString().trim().split(':')[6] ?? '';
+        if (!NO_LOGIN_SHELLS.includes(shell)) {
+            console.error(
+                `user ${FORWARDING_USER} has the login shell "${shell}", tunnel users must not have one: usermod --shell /usr/sbin/nologin ${FORWARDING_USER}`
+            );
+            process.exit(1);
+        }
+    }
+}
+
+if (SSH_BACKEND === 'sshd') {
+    if (OPENED_PORTS.length === 0) {
+        console.error(
+            'please set the OPENED_PORTS env variable to contain a list (comma separated) of opened port for the sshd instances'
+        );
+        process.exit(1);
+    }
+    if (!fs.existsSync(AUTHORIZED_KEYS_FOLDER)) {
+        fs.mkdirSync(AUTHORIZED_KEYS_FOLDER, { recursive: true, mode: 0o755 });
+    }
+    if (TUNNEL_CHROOT) prepareChroot(TUNNEL_CHROOT);
+} else {
+    startEmbeddedSSH(SSH_PORT, KEYS);
+}
+
+// enables the admin api when set
+const ADMIN_TOKEN = process.env.ADMIN_TOKEN;
+// serves the admin api over grpc too (see admin.proto), with tls when both files are set
+const ADMIN_GRPC_PORT = process.env.ADMIN_GRPC_PORT ? parseInt(process.env.ADMIN_GRPC_PORT) : undefined;
+const ADMIN_GRPC_CERT = process.env.ADMIN_GRPC_CERT;
+const ADMIN_GRPC_KEY = process.env.ADMIN_GRPC_KEY;
+// without tls the token travels in clear, so the grpc api only listens on the loopback unless this is 'true'
+const ADMIN_GRPC_INSECURE = process.env.ADMIN_GRPC_INSECURE === 'true';
+loadBans(path.resolve(DATA_FOLDER, 'bans.json'));
+
+const adminActions: AdminActions = {
+    tunnels: () =>
+        connections.map(connection => ({
+            id: connection.id,
+            sender: connection.sender.uuid,
+            label: connection.sender.label ?? null,
+            receiver: connection.receiver.uuid,
+            port: connection.port,
+            transport: tunnelTransport(connection),
+            opened_at: new Date(connection.openedAt).toISOString(),
+            traffic: connection.traffic,
+            stats: {
+                sender: connection.stats.get(connection.sender) ?? null,
+                receiver: connection.stats.get(connection.receiver) ?? null
+            }
+        })),
+    closeTunnel: id => {
+        const connection = connections.find(c => c.id === id);
+        if (!connection) return false;
+        console.log(`closing tunnel ${connection.sender.uuid} -> ${connection.receiver.uuid}: closed by an admin`);
+        closeConnection(connection, 'admin');
+        return true;
+    },
+    clients: () =>
+        clients.map(client => ({
+            uuid: client.uuid,
+            client_type: client.client_type,
+            label: client.label ?? null,
+            address: client.address ?? null,
+            account: client.account ?? null,
+            tunnels: connections.filter(c => c.sender === client || c.receiver === client).length
+        })),
+    kickClient: uuid => {
+        const client = clients.find(c => c.uuid === uuid);
+        if (!client) return false;
+        console.log(`disconnecting ${uuid}: kicked by an admin`);
+        client.ws.close(1008, 'disconnected by an admin');
+        return true;
+    },
+    cleanup: () => {
+        const report = cleanupStaleCredentials();
+        console.log(`cleanup asked by an admin: ${JSON.stringify(report)}`);
+        return report;
+    },
+    bans: listBans,
+    setBanned: (target, banned) => {
+        if (!setBanned(target, banned)) return;
+        console.log(`${banned ? 'banned' : 'unbanned'} ${target}`);
+        if (!banned) return;
+        for (const client of clients.filter(c => c.uuid === target || c.address === target)) {
+            client.ws.close(1008, 'banned from this server');
+        }
+    }
+};
+const adminApi = adminHandler(ADMIN_TOKEN, adminActions);
+if (ADMIN_GRPC_PORT !== undefined) {
+    if (!ADMIN_TOKEN) {
+        console.error('ADMIN_GRPC_PORT needs ADMIN_TOKEN to be set');
+        process.exit(1);
+    }
+    if (!ADMIN_GRPC_CERT !== !ADMIN_GRPC_KEY) {
+        console.error('ADMIN_GRPC_CERT and ADMIN_GRPC_KEY have to be set together');
+        process.exit(1);
+    }
+    const tls = ADMIN_GRPC_CERT && ADMIN_GRPC_KEY ? { cert: ADMIN_GRPC_CERT, key: ADMIN_GRPC_KEY } : undefined;
+    const host = tls || ADMIN_GRPC_INSECURE ? '0.0.0.0' : '127.0.0.1';
+    startGrpcAdmin(host, ADMIN_GRPC_PORT, ADMIN_TOKEN, adminActions, tls);
+}
+// clients whose websocket upgrade fails fall back to server-sent events on the same port
+const sseApi = sseHandler(onConnection);
+const httpServer = createServer((req, res) => {
+    if (!stripBasePath(req)) {
+        res.writeHead(404, { 'Content-Type': 'application/json' });
+        res.end(JSON.stringify({ error: 'not found' }));
+        return;
+    }
+    oidcHandler(req, res) || sseApi(req, res) || adminApi(req, res);
+});
+const wss = new ws.Server({ noServer: true });
+httpServer.on('upgrade', (req, socket, head) => {
+    if (!stripBasePath(req)) {
+        socket.destroy();
+        return;
+    }
+    wss.handleUpgrade(req, socket, head, ws => wss.emit('connection', ws, req));
+});
 httpServer.listen(SERVER_PORT, () => console.log(`Server started on port ${SERVER_PORT}`));
 
 interface Client {
-    ws: ws.WebSocket;
+    ws: ClientSocket;
     uuid: string;
     ssh_key: string;
     auto_accept: boolean;
     port_whitelist: number[];
     port_blacklist: number[];
-    client_type: ClientType;
+    source_allowlist?: string[]; // ip ranges receivers must connect from, empty allows everyone
+    client_type: RegisterType;
+    capabilities?: string[]; // announced on register, undefined for older clients
+    label?: string; // name the host gave its session
+    revoked?: string[]; // receivers the host revoked, until it disconnects
+    invites?: Map<string, number>; // single use tokens the host minted, with the port each is for
+    address?: string; // ip the client connects from
+    account?: string; // name the identity provider knows the client by, only set when the server uses oidc
+    certificate?: SshCertificate; // of the ssh key, only set when the server trusts an ssh certificate authority
+    team?: string; // the host is only visible to and reachable by the members of this team
+    max_connections?: number; // receivers the host takes at once, unlimited when unset
+    port_aliases?: Record<string, number>; // names the host gave its ports
+    whitelist_only?: boolean; // the host shares its whitelist and nothing else, even when it is empty
+    port_precedence?: 'blacklist' | 'whitelist'; // the list deciding for a port in both, whitelist when unset
 }
 
 interface Connection {
+    id: string;
     sender: Client;
     receiver: Client;
-    sshd: ChildProcess;
+    sshd?: ChildProcess; // only set with the sshd backend
+    user: string; // user both clients log in as
+    authorizedKeyFile?: string; // only set with the sshd backend
     sshdPort: number; // port on which this instance of sshd runs
     localPort: number; // port used by both client to push/pull the true port being forwarded from one client to the other
+    localPorts?: number[]; // only set with the sshd backend, ports sshd lets the clients use (localPort and the ones for added ports)
+    extraForwards: { port: number; localPort: number }[]; // ports added to the tunnel after it was opened
+    port: number; // port of the sender being forwarded
+    relay: boolean; // whether the tunnel goes through the websockets instead of ssh
+    p2p?: Map<Client, boolean>; // result of the hole punching of each client, only set for p2p tunnels
+    wireguardSubnet?: number; // index of the /30 given to the wireguard interfaces, only set for wireguard tunnels
+    direct?: boolean; // the traffic doesn't go through the server, so it can't be monitored
+    relayedBytes: number; // bytes relayed through the websockets
+    traffic: number; // last traffic counter seen for this tunnel
+    lastActivity: number; // timestamp of the last time the traffic counter moved
+    openedAt: number; // timestamp of the creation of the tunnel
+    forwardRetries?: number; // how many times the tunnel was moved to another port
+    expiryWarned?: boolean; // whether the clients were told the tunnel is about to reach its max lifetime
+    stats: Map<Client, TunnelStats & { reported_at: string }>; // last tunnel_stats of each client
 }
 
 const clients: Client[] = [];
 const connections: Connection[] = [];
+const sessionsPerAddress = new Map<string, number>();
+// ports of the sshd instances being started, before their tunnel is in connections
+const startingSshdPorts = new Set<number>();
+// sockets to tell when a host comes online or goes offline, by host uuid
+const presenceSubscribers = new Map<string, Set<ClientSocket>>();
+// hosts a single socket can watch
+const MAX_PRESENCE_SUBSCRIPTIONS = 32;
+// unused invites a host can have at once
+const MAX_INVITES = 64;
 
-wss.on('connection', ws => {
-    ws.on('message', async data => {
+if (TUNNEL_IDLE_TIMEOUT > 0 || TUNNEL_MAX_LIFETIME > 0 || quotasEnabled()) {
+    setInterval(monitorTunnels, MONITOR_INTERVAL);
+}
+// a previous run of the server may have left some behind
+cleanupStaleCredentials();
+if (CLEANUP_INTERVAL > 0) {
+    setInterval(() => {
+        const report = cleanupStaleCredentials();
+        if (Object.values(report).some(removed => removed.length > 0)) {
+            console.log(`removed what crashed tunnels left behind: ${JSON.stringify(report)}`);
+        }
+    }, CLEANUP_INTERVAL * 1000);
+}
+
+fs.watchFile(NOTICE_FILE, { interval: 5000 }, () => {
+    const notice = readNotice();
+    if (notice) broadcastNotice('info', notice);
+});
+
+for (const signal of ['SIGINT', 'SIGTERM'] as const) {
+    process.on(signal, () => {
+        // emptied first so shared tunnels don't wait for each other
+        connections.splice(0).forEach(closeTunnel);
+        saveUsage();
+        // clients are told the server is going away instead of seeing their connection drop,
+        // the close frames are given a moment to go out
+        [...wss.clients, ...sseSessions()].forEach(client => client.close(1001, 'the server is shutting down'));
+        setTimeout(() => process.exit(0), 500);
+    });
+}
+
+wss.on('connection', onConnection);
+
+// removes BASE_PATH from the url of the request, false when the request is outside of it
+function stripBasePath(req: IncomingMessage) {
+    if (!BASE_PATH) return true;
+    const url = req.url ?? '/';
+    if (url !== BASE_PATH && !url.startsWith(`${BASE_PATH}/`) && !url.startsWith(`${BASE_PATH}?`)) {
+        return false;
+    }
+    const rest = url.slice(BASE_PATH.length);
+    req.url = rest.startsWith('/') ? rest : `/${rest}`;
+    return true;
+}
+
+function onConnection(ws: ClientSocket, req: IncomingMessage) {
+    const address = req.socket.remoteAddress?.replace(/^::ffff:/, '');
+    if (isBanned(address)) {
+        ws.close(1008, 'banned from this server');
+        return;
+    }
+    if (address) {
+        const sessions = sessionsPerAddress.get(address) ?? 0;
+        if (MAX_SESSIONS_PER_IP > 0 && sessions >= MAX_SESSIONS_PER_IP) {
+            console.log(`refusing a connection from ${address}, it already has ${sessions}`);
+            ws.close(1008, 'too many connections from your address');
+            return;
+        }
+        sessionsPerAddress.set(address, sessions + 1);
+    }
+    // older clients don't send heartbeats, they are only watched once they sent one
+    let heartbeats = false;
+    const subscriptions = new Set<string>(); // hosts this socket watches
+    let lastSeen = Date.now();
+    const watchdog = setInterval(() => {
+        if (heartbeats && Date.now() - lastSeen > HEARTBEAT_TIMEOUT * 1000) {
+            console.log(`no heartbeat from ${address} for ${HEARTBEAT_TIMEOUT}s, disconnecting it`);
+            ws.terminate();
+        }
+        // the tunnels end with the certificate the client registered with, it registers again with a new one
+        const certificate = clients.find(c => c.ws === ws)?.certificate;
+        if (certificate && certificate.validBefore * 1000 < Date.now()) {
+            console.log(`the ssh certificate ${certificate.keyId} of ${address} expired, disconnecting it`);
+            ws.close(1008, 'your ssh certificate expired');
+        }
+    }, HEARTBEAT_CHECK_INTERVAL);
+    ws.on('message', async (data, isBinary) => {
+        lastSeen = Date.now();
         // console.log(data.toString());
+        if (isBinary) {
+            relayData(ws, data as Buffer);
+            return;
+        }
+        // echoed in the answers to this message, so a client with several requests in flight can match them
+        let requestId: number | undefined;
+        const reply = (payload: object) => ws.send(JSON.stringify({ ...payload, request_id: requestId }));
+        // the error is a human readable detail, clients mostly rely on the code
+        const replyError = (code: ErrorCode, error?: string) => reply({ type: 'response', success: false, code, error });
         try {
-            const message = messagesSchema.parse(JSON.parse(data.toString()));
-            if (message.type === 'register') {
+            const raw = JSON.parse(data.toString());
+            requestId = requestIdSchema.parse(raw?.request_id);
+            const message = messagesSchema.parse(raw);
+            if (message.type === 'heartbeat') {
+                heartbeats = true;
+                reply({ type: 'heartbeat_ack' });
+            } else if (message.type === 'register') {
+                let account: string | undefined;
+                try {
+                    account = await authenticate({ id_token: message.id_token, ...message.credentials });
+                } catch (err) {
+                    replyError('unauthorized', (err as Error).message);
+                    return;
+                }
+                // ssh2 reads no key of a security key, the client can try another of its keys
+                if (SSH_BACKEND === 'embedded' && message.ssh_key.trim().startsWith('sk-')) {
+                    return replyError('key_refused', 'This server does not support the keys of security keys (sk-)');
+                }
+                let certificate: SshCertificate | undefined;
+                try {
+                    certificate = verifyCertificate(message.ssh_certificate, message.ssh_key, account, address);
+                } catch (err) {
+                    replyError('key_refused', (err as Error).message);
+                    return;
+                }
+                if (isBanned(message.uuid)) {
+                    replyError('denied', 'This client is banned from this server');
+                    ws.close(1008, 'banned from this server');
+                    return;
+                }
+                if (message.team !== undefined) {
+                    if (!TEAMS_FILE) return replyError('unsupported', 'This server has no teams');
+                    if (!isHost(message)) return replyError('invalid_message', 'Only hosts register under a team');
+                    if (!canHost(message.team, account)) {
+                        return replyError('denied', `You are not allowed to host for the team ${message.team}`);
+                    }
+                }
+                const { id_token, credentials, ssh_certificate, ...registration } = message;
                 let client = clients.find(c => c.uuid === message.uuid);
+                // the uuid is chosen by the client, taking over a registration needs its account and ssh key too.
+                // a client on the same socket only has to be the same account
+                if (
+                    client &&
+                    (client.account !== account || (client.ws !== ws && client.ssh_key.trim() !== message.ssh_key.trim()))
+                ) {
+                    return replyError('denied', 'Another client is registered with this uuid');
+                }
                 if (client) {
-                    client.ws = ws;
+                    // the previous socket of the client, most likely dead, no longer speaks for it
+                    if (client.ws !== ws) client.ws.close(1008, 'registered again from another connection');
+                    // the new registration replaces the old one as a whole, the optional fields it left out included.
+                    // the same object, the tunnels hold it. only what the host did during its session stays
+                    const { revoked, invites } = client;
+                    for (const key of Object.keys(client)) delete (client as Partial<Client>)[key as keyof Client];
+                    Object.assign(client, registration, { ws, address, account, certificate, revoked, invites });
                 } else {
-                    clients.push({ ...message, ws });
+                    clients.push({ ...registration, ws, address, account, certificate });
                 }
+                if (isHost(message)) notifyPresence(message.uuid, true);
+                emitEvent('register', {
+                    uuid: message.uuid,
+                    client_type: message.client_type,
+                    label: message.label,
+                    account
+                });
 
-                ws.send(
-                    JSON.stringify({
-                        type: 'response',
-                        success: true
-                    })
-                );
+                reply({
+                    type: 'response',
+                    success: true,
+                    capabilities: CAPABILITIES,
+                    account
+                });
+                const status = quotaStatus({ uuid: message.uuid, account, address });
+                if (status) {
+                    ws.send(JSON.stringify(status));
+                }
+                const notice = readNotice();
+                if (notice) {
+                    sendNotice(ws, 'info', notice);
+                }
             } else if (message.type === 'connect_to_host') {
                 const sourceClient = clients.find(c => c.ws === ws);
                 if (!sourceClient) {
-                    wsSendResponse(ws, false, 'you are not registered');
+                    replyError('unauthorized', 'you are not registered');
                     return;
                 }
-                const search = clients.filter(c => {
-                    if (c.client_type !== 'sender') return false;
-                    if (!c.uuid.startsWith(message.target)) return false;
-                    return true;
-                });
+                const search = findHosts(message.target);
 
                 if (search.length === 0) {
-                    wsSendResponse(ws, false, 'There is no client that matches this search');
+                    replyError('host_offline', 'There is no client that matches this search');
                     return;
                 }
                 if (search.length > 1) {
-                    wsSendResponse(
-                        ws,
-                        false,
+                    replyError(
+                        'ambiguous_target',
                         'There are multiples clients that match this search, please be more precise with the uuid provided'
                     );
                     return;
                 }
                 const targetClient = search[0]!;
-                if (targetClient.port_whitelist.length > 0) {
-                    // there is a whitelist
-                    if (!targetClient.port_whitelist.includes(message.port)) {
-                        wsSendResponse(ws, false, `the port "${message.port}" isn't in the client's whitelist`);
-                        return false;
-                    }
-                } else if (targetClient.port_blacklist.length > 0) {
-                    //there is a blacklist
-                    if (targetClient.port_blacklist.includes(message.port)) {
-                        wsSendResponse(ws, false, `the port "${message.port}" is in the client's blacklist`);
-                        return false;
+                if (targetClient === sourceClient) {
+                    replyError('denied', 'You can not connect to yourself');
+                    return;
+                }
+                if (isOverQuota(sourceClient)) {
+                    replyError('quota_exceeded', 'You have used up your transfer quota');
+                    return;
+                }
+                if (isOverQuota(targetClient)) {
+                    replyError('quota_exceeded', 'The host has used up its transfer quota');
+                    return;
+                }
+                if (targetClient.team !== undefined && !isMember(targetClient.team, sourceClient.account)) {
+                    replyError('denied', 'The host is only reachable by the members of its team');
+                    return;
+                }
+                if (targetClient.revoked?.includes(sourceClient.uuid)) {
+                    replyError('denied', 'The host revoked your access');
+                    return;
+                }
+                const refusal = portRefusal(targetClient, message.port);
+                if (refusal) {
+                    replyError('port_not_allowed', refusal);
+                    return false;
+                }
+                if (MAX_TUNNELS_PER_IP > 0 && sourceClient.address) {
+                    const open = connections.filter(c => c.receiver.address === sourceClient.address).length;
+                    if (open >= MAX_TUNNELS_PER_IP) {
+                        replyError('rate_limited', `Your address already has ${MAX_TUNNELS_PER_IP} tunnels open`);
+                        return;
                     }
                 }
 
+                const transports = (['relay', 'p2p', 'quic', 'wireguard'] as const).filter(transport => message[transport]);
+                const unsupported = transports.find(transport => !supports(targetClient.capabilities, transport));
+                if (unsupported) {
+                    replyError('unsupported', `The host's client does not support ${unsupported} tunnels`);
+                    return;
+                }
+                // relayed and direct tunnels are found by socket, which is ambiguous for a client in tunnels on both sides
+                if (transports.length > 0 && [sourceClient, targetClient].some(c => c.client_type === 'both')) {
+                    replyError('unsupported', 'Clients hosting and connecting at once only support ssh tunnels');
+                    return;
+                }
+
+                if (!receiverAllowed(targetClient, sourceClient.address)) {
+                    replyError('address_not_allowed', 'Your address is not allowed to connect to this client');
+                    emitEvent('abuse', { uuid: sourceClient.uuid, reason: 'address_not_allowed' });
+                    return;
+                }
+
+                // refused before asking, so a full host isn't flooded with prompts
+                if (isFull(targetClient)) {
+                    replyError('host_full', 'The host already has as many receivers as it takes');
+                    return;
+                }
+
                 async function createConnection() {
-                    let sshdPort = OPENED_PORTS.find(port => !connections.some(con => con.sshdPort === port));
-                    console.log(OPENED_PORTS);
-                    if (!sshdPort) {
-                        // no port available
-                        wsSendResponse(ws, false, 'Server is full');
+                    if (message.type !== 'connect_to_host') return;
+                    // several requests may have been accepted while the host was filling up
+                    if (isFull(targetClient)) {
+                        replyError('host_full', 'The host already has as many receivers as it takes');
                         return;
                     }
-
-                    let localPort = Math.max(...OPENED_PORTS) + 1;
-                    while (connections.some(con => con.localPort === localPort)) localPort++;
-                    // const authorizedKeyArgs = '';
-                    const authorizedKeyArgs = `command="echo 'This account is restricted to port forwarding'",no-pty,no-agent-forwarding,no-X11-forwarding`;
-                    const sshKeys = [targetClient.ssh_key, sourceClient!.ssh_key]
-                        .map(key => authorizedKeyArgs + ' ' + key)
-                        .join('\n');
-
-                    const sshFolder = path.resolve(FORWARDING_USER_HOME, '.ssh');
-                    if (!fs.existsSync(sshFolder)) {
-                        fs.mkdirSync(sshFolder);
-                    }
-                    const authorizedKeyFile = path.join(sshFolder, `authorized_keys_${sshdPort}`);
-                    if (fs.existsSync(authorizedKeyFile)) {
-                        fs.rmSync(authorizedKeyFile);
-                    }
-                    // fs.writeFileSync(authorizedKeyFile, `#!/bin/sh\n/bin/echo "${sshKeys}"`);
-                    fs.writeFileSync(authorizedKeyFile, `${sshKeys}`);
-                    // fs.chmodSync(authorizedKeyFile, 700);
-
-                    const sshdArgs: string[] = [
-                        '-f',
-                        '/dev/null',
-                        '-o',
-                        `AllowUsers=${FORWARDING_USER}`,
-                        '-o',
-                        'PasswordAuthentication=no',
-                        '-o',
-                        'PubkeyAuthentication=yes',
-                        '-o',
-                        'AllowTcpForwarding=yes',
-                        '-o',
-                        'PermitTunnel=no',
-                        '-o',
-                        'PermitRootLogin=no',
-                        '-o',
-                        'X11Forwarding=no',
-                        '-o',
-                        'PermitUserEnvironment=no',
-                        '-o',
-                        'AllowAgentForwarding=no',
-                        '-o',
-                        `Port=${sshdPort}`,
-                        '-o',
-                        `PermitOpen=localhost:${localPort}`,
-                        // '-o',
-                        // `AuthorizedKeysCommandUser=${FORWARDING_USER}`,
-                        '-o',
-                        `AuthorizedKeysFile=${authorizedKeyFile}`,
-                        '-o',
-                        `HostKey=${KEYS[0]}`,
-                        '-o',
-                        `HostKey=${KEYS[1]}`,
-                        '-o',
-                        `HostKey=${KEYS[2]}`,
-                        '-D'
-                    ];
-
-                    const sshd = spawn(SSHD!, sshdArgs, {});
-                    await wait(1000);
-                    let connection: Connection = {
-                        sshd,
+                    const base = {
+                        id: randomUUID(),
                         sender: targetClient,
                         receiver: sourceClient!,
-                        localPort,
-                        sshdPort
+                        port: message.port,
+                        relay: false,
+                        relayedBytes: 0,
+                        traffic: 0,
+                        lastActivity: Date.now(),
+                        openedAt: Date.now(),
+                        extraForwards: [],
+                        stats: new Map()
                     };
+                    const wantsSsh = !message.relay && !message.p2p && !message.wireguard;
+                    const hostTunnels = connections.filter(c => c.sender === targetClient);
+                    // the host runs a single relayed or direct tunnel, only ssh forwards can be shared
+                    if (hostTunnels.some(c => !isSshTunnel(c)) || (!wantsSsh && hostTunnels.length > 0)) {
+                        replyError('host_busy', 'The client is already in a tunnel which can not be shared');
+                        return;
+                    }
+                    const shared = wantsSsh ? hostTunnels.find(c => c.port === message.port) : undefined;
+                    let connection: Connection;
+                    if (shared) {
+                        // another receiver joins the forward the host already has for this port
+                        connection = {
+                            ...base,
+                            sshd: shared.sshd,
+                            user: shared.user,
+                            authorizedKeyFile: shared.authorizedKeyFile,
+                            localPort: shared.localPort,
+                            localPorts: shared.localPorts,
+                            sshdPort: shared.sshdPort,
+                            traffic: shared.sshd ? shared.traffic : 0
+                        };
+                        if (shared.authorizedKeyFile) {
+                            const receivers = [...sharedTunnels(shared), shared].map(c => c.receiver);
+                            receivers.push(sourceClient!);
+                            writeAuthorizedKeys(shared.authorizedKeyFile, targetClient, receivers, shared);
+                        } else {
+                            addReceiver(shared.user, {
+                                key: sourceClient!.ssh_key,
+                                allowed: address => receiverAllowed(targetClient, address)
+                            });
+                        }
+                    } else if (message.p2p) {
+                        // the relay is used as a fallback if the clients fail to reach each other
+                        connection = { ...base, user: '', localPort: 0, sshdPort: 0, relay: true, p2p: new Map() };
+                    } else if (message.wireguard) {
+                        let wireguardSubnet = 0;
+                        while (connections.some(con => con.wireguardSubnet === wireguardSubnet)) wireguardSubnet++;
+                        if (wireguardSubnet >= WIREGUARD_MAX_SUBNETS) {
+                            replyError('server_full', 'Server is full');
+                            return;
+                        }
+                        connection = {
+                            ...base,
+                            user: '',
+                            localPort: 0,
+                            sshdPort: 0,
+                            wireguardSubnet,
+                            direct: true
+                        };
+                    } else if (message.relay) {
+                        connection = { ...base, user: '', localPort: 0, sshdPort: 0, relay: true };
+                    } else if (SSH_BACKEND === 'embedded') {
+                        let localPort = EMBEDDED_LOCAL_PORT_START;
+                        while (usedLocalPorts().includes(localPort)) localPort++;
+                        const user = randomUUID();
+                        addTunnel(
+                            { user, senderKey: targetClient.ssh_key, localPort },
+                            { key: sourceClient!.ssh_key, allowed: address => receiverAllowed(targetClient, address) }
+                        );
+                        connection = { ...base, user, localPort, sshdPort: SSH_PORT };
+                    } else {
+                        const tunnel = await startSshd(targetClient, sourceClient!);
+                        if (typeof tunnel === 'string') {
+                            replyError(tunnel);
+                            return;
+                        }
+                        connection = { ...base, ...tunnel };
+                        // sshd can die on its own later on, don't leave its credentials behind
+                        tunnel.sshd.on('exit', () => cleanupCredentials(connection));
+                    }
                     connections.push(connection);
-                    connection.receiver.ws.send(
-                        JSON.stringify({
-                            type: 'tunnel_connect',
-                            client_type: 'receiver',
-                            user: FORWARDING_USER,
-                            sshd_port: sshdPort, // ssh port
-                            local_port: localPort, // port that is used to forward between the 2 clients
-                            forwarded_port: 0 // ignored for receiver
-                        })
-                    );
-                    connection.sender.ws.send(
-                        JSON.stringify({
-                            type: 'tunnel_connect',
-                            client_type: 'sender',
-                            user: FORWARDING_USER,
-                            sshd_port: sshdPort, // ssh port
-                            local_port: localPort, // port that is used to forward between the 2 clients
-                            forwarded_port: message.type == 'connect_to_host' ? message.port : 0 // port to forward to local_port
-                        })
-                    );
+                    emitEvent('tunnel_open', {
+                        id: connection.id,
+                        sender: connection.sender.uuid,
+                        receiver: connection.receiver.uuid,
+                        port: connection.port
+                    });
+                    if (supports(connection.sender.capabilities, 'receivers')) {
+                        connection.sender.ws.send(
+                            JSON.stringify({
+                                type: 'receiver_joined',
+                                receiver: connection.receiver.uuid,
+                                port: connection.port
+                            })
+                        );
+                    }
+                    if (connection.p2p) {
+                        const token = randomUUID();
+                        for (const [client, clientType] of [
+                            [connection.receiver, 'receiver'],
+                            [connection.sender, 'sender']
+                        ] as const) {
+                            client.ws.send(
+                                JSON.stringify({
+                                    type: 'p2p_connect',
+                                    client_type: clientType,
+                                    forwarded_port: clientType === 'sender' ? connection.port : 0,
+                                    token,
+                                    quic: message.quic ?? false
+                                })
+                            );
+                        }
+                        return;
+                    }
+                    if (connection.wireguardSubnet !== undefined) {
+                        const [senderAddress, receiverAddress] = wireguardAddresses(connection.wireguardSubnet);
+                        connection.receiver.ws.send(
+                            JSON.stringify({
+                                type: 'wireguard_connect',
+                                client_type: 'receiver',
+                                forwarded_port: 0, // ignored for receiver
+                                address: `${receiverAddress}/30`,
+                                peer_address: senderAddress
+                            })
+                        );
+                        connection.sender.ws.send(
+                            JSON.stringify({
+                                type: 'wireguard_connect',
+                                client_type: 'sender',
+                                forwarded_port: connection.port,
+                                address: `${senderAddress}/30`,
+                                peer_address: receiverAddress
+                            })
+                        );
+                        return;
+                    }
+                    if (connection.relay) {
+                        sendRelayConnect(connection);
+                        return;
+                    }
+                    sendTunnelConnect(connection, 'receiver');
+                    // the sender's ssh forward is already up
+                    if (shared) return;
+                    sendTunnelConnect(connection, 'sender');
                 }
 
-                if (targetClient.auto_accept) {
+                // an invite stands for the host's confirmation, it is consumed even if the tunnel fails
+                const invite = message.invite;
+                if (invite !== undefined) {
+                    if (targetClient.invites?.get(invite) !== message.port) {
+                        replyError('unauthorized', 'The invite is invalid, already used or for another port');
+                        return;
+                    }
+                    targetClient.invites.delete(invite);
+                }
+                const answer =
+                    targetClient.auto_accept || invite !== undefined
+                        ? 'accepted'
+                        : await askHost(targetClient, sourceClient, message.port);
+                if (answer === 'accepted') {
+                    // older clients take any response for an error, they don't send request ids
+                    if (requestId !== undefined) reply({ type: 'response', success: true });
                     createConnection();
+                } else if (answer === 'denied') {
+                    replyError('denied', 'The client denied the connection');
                 } else {
-                    const listener = (data: ws.RawData) => {
-                        const message = messagesSchema.parse(JSON.parse(data.toString()));
-                        if (message.type === 'connect_accept') {
-                            targetClient.ws.removeListener('message', listener);
-                            createConnection();
-                        } else if (message.type === 'connect_deny') {
-                            targetClient.ws.removeListener('message', listener);
-                            wsSendResponse(ws, false, 'The client denied the connection');
-                        }
-                    };
-                    targetClient.ws.on('message', listener);
-                    targetClient.ws.send(
-                        JSON.stringify({
-                            type: 'connect_confirm',
-                            source_client: sourceClient.uuid,
-                            port: message.port
-                        })
-                    );
+                    replyError('host_timeout', 'The host did not respond to the connection request');
                 }
-            }
-        } catch (err) {
-            if (err instanceof ZodError) {
-                ws.send(
+            } else if (message.type === 'kick_receiver') {
+                if (kickReceiver(ws, message.receiver, 'kicked') === 0) {
+                    return replyError('not_found', 'No receiver with this uuid is connected');
+                }
+                if (requestId !== undefined) reply({ type: 'response', success: true });
+            } else if (message.type === 'create_invite') {
+                const client = clients.find(c => c.ws === ws);
+                if (!client || !isHost(client)) return replyError('unauthorized', 'Only hosts can create invites');
+                const refusal = portRefusal(client, message.port);
+                if (refusal) return replyError('port_not_allowed', refusal);
+                client.invites ??= new Map();
+                if (client.invites.size >= MAX_INVITES) {
+                    return replyError('rate_limited', `You can not have more than ${MAX_INVITES} unused invites`);
+                }
+                const token = randomUUID();
+                client.invites.set(token, message.port);
+                reply({ type: 'invite_created', token, port: message.port });
+            } else if (message.type === 'set_ports') {
+                const client = clients.find(c => c.ws === ws);
+                if (!client || !isHost(client)) return replyError('unauthorized', 'Only hosts have ports');
+                // tunnels already open to a port taken out stay open until they close
+                client.port_whitelist = message.port_whitelist;
+                if (requestId !== undefined) reply({ type: 'response', success: true });
+            } else if (message.type === 'send_note') {
+                const client = clients.find(c => c.ws === ws);
+                if (!client) return replyError('unauthorized', 'you are not registered');
+                const peers = new Set(
+                    connections
+                        .filter(c => c.sender === client || c.receiver === client)
+                        .map(c => (c.sender === client ? c.receiver : c.sender))
+                        .filter(peer => supports(peer.capabilities, 'notes'))
+                );
+                if (peers.size === 0) return replyError('not_found', 'No connected peer can receive notes');
+                const note = JSON.stringify({
+                    type: 'note',
+                    from: client.uuid,
+                    label: client.label ?? null,
+                    text: message.text
+                });
+                peers.forEach(peer => peer.ws.send(note));
+                if (requestId !== undefined) reply({ type: 'response', success: true });
+            } else if (message.type === 'list_receivers') {
+                const receivers = connections.filter(c => c.sender.ws === ws).map(receiverUsage);
+                reply({ type: 'receiver_usage', receivers });
+            } else if (message.type === 'list_host_ports') {
+                const client = clients.find(c => c.ws === ws);
+                if (!client) return replyError('unauthorized', 'you are not registered');
+                const search = findHosts(message.target);
+                if (search.length === 0) return replyError('host_offline', 'There is no client that matches this search');
+                if (search.length > 1) {
+                    return replyError('ambiguous_target', 'There are multiples clients that match this search');
+                }
+                const host = search[0]!;
+                if (host.team !== undefined && !isMember(host.team, client.account)) {
+                    return replyError('denied', 'The host is only reachable by the members of its team');
+                }
+                // the whitelist is what the host advertises, it shares every other port when it has none
+                // unless it is whitelist only
+                reply({
+                    type: 'host_ports',
+                    uuid: host.uuid,
+                    label: host.label ?? null,
+                    ports: host.port_whitelist,
+                    aliases: host.port_aliases ?? {},
+                    whitelist_only: host.whitelist_only ?? false
+                });
+            } else if (message.type === 'list_team_hosts') {
+                const client = clients.find(c => c.ws === ws);
+                if (!client) return replyError('unauthorized', 'you are not registered');
+                if (!isMember(message.team, client.account)) {
+                    return replyError('denied', `You are not a member of the team ${message.team}`);
+                }
+                const hosts = clients
+                    .filter(c => isHost(c) && c.team === message.team)
+                    .map(c => ({ uuid: c.uuid, label: c.label ?? null, account: c.account ?? null }));
+                reply({ type: 'team_hosts', team: message.team, hosts });
+            } else if (message.type === 'revoke_receiver') {
+                const client = clients.find(c => c.ws === ws);
+                if (!client || !isHost(client)) return replyError('unauthorized', 'Only hosts can revoke receivers');
+                // also works for receivers which aren't connected right now
+                client.revoked = [...new Set([...(client.revoked ?? []), message.receiver])].slice(-256);
+                kickReceiver(ws, message.receiver, 'revoked');
+                if (requestId !== undefined) reply({ type: 'response', success: true });
+            } else if (message.type === 'subscribe_host') {
+                if (!clients.some(c => c.ws === ws)) return replyError('unauthorized', 'you are not registered');
+                if (!subscriptions.has(message.target) && subscriptions.size >= MAX_PRESENCE_SUBSCRIPTIONS) {
+                    return replyError('rate_limited', `You can not watch more than ${MAX_PRESENCE_SUBSCRIPTIONS} hosts`);
+                }
+                subscriptions.add(message.target);
+                const subscribers = presenceSubscribers.get(message.target) ?? new Set();
+                presenceSubscribers.set(message.target, subscribers.add(ws));
+                // the current state first, then every change
+                const host = onlineHost(message.target);
+                reply({ type: 'host_presence', uuid: message.target, online: !!host, label: host?.label });
+            } else if (message.type === 'unsubscribe_host') {
+                subscriptions.delete(message.target);
+                unsubscribePresence(message.target, ws);
+            } else if (message.type === 'tunnel_stats') {
+                const stats = tunnelStatsSchema.parse(message); // drops the type
+                // a sender shared by several receivers reports once for all of them
+                for (const connection of connections.filter(c => c.sender.ws === ws || c.receiver.ws === ws)) {
+                    const client = connection.sender.ws === ws ? connection.sender : connection.receiver;
+                    const peer = client === connection.sender ? connection.receiver : connection.sender;
+                    connection.stats.set(client, { ...stats, reported_at: new Date().toISOString() });
+                    if (supports(peer.capabilities, 'tunnel_stats')) {
+                        peer.ws.send(JSON.stringify({ ...stats, type: 'peer_stats' }));
+                    }
+                }
+            } else if (message.type === 'add_port') {
+                const tunnel = tunnelPeer(ws, 'receiver');
+                const refuse = (code: ErrorCode, error: string) =>
+                    reply({ type: 'port_refused', port: message.port, code, error });
+                if (!tunnel || tunnel.client !== tunnel.connection.receiver || !isSshTunnel(tunnel.connection)) {
+                    return refuse('unauthorized', 'Ports can only be added to an ssh tunnel you are the receiver of');
+                }
+                const { connection } = tunnel;
+                if (!supports(connection.sender.capabilities, 'multi_port')) {
+                    return refuse('unsupported', "The host's client can not add ports to a tunnel");
+                }
+                const refusal = portRefusal(connection.sender, message.port);
+                if (refusal) return refuse('port_not_allowed', refusal);
+                if (!connection.sender.auto_accept) {
+                    const answer = await askHost(connection.sender, connection.receiver, message.port);
+                    if (answer === 'denied') return refuse('denied', 'The client denied the port');
+                    if (answer === 'timeout') return refuse('host_timeout', 'The host did not respond to the request');
+                }
+                // the tunnel could have been closed while the host was deciding
+                if (!connections.includes(connection)) return;
+                const error = addForward(connection, message.port);
+                if (error) return refuse(error, 'No more ports can be added to this tunnel');
+                if (requestId !== undefined) reply({ type: 'response', success: true });
+            } else if (message.type === 'forward_failed') {
+                const tunnel = tunnelPeer(ws, 'sender');
+                if (!tunnel?.connection.sshd || tunnel.client !== tunnel.connection.sender) return;
+                if (message.port !== undefined) moveExtraForward(tunnel.connection, message.port);
+                else await moveForward(tunnel.connection);
+            } else if (message.type === 'relay_open' || message.type === 'relay_close') {
+                const relay = tunnelPeer(ws);
+                if (!relay?.connection.relay) return;
+                relay.peer.ws.send(JSON.stringify(message));
+            } else if (message.type === 'p2p_candidates') {
+                const relay = tunnelPeer(ws);
+                if (!relay?.connection.p2p) return;
+                // also try the public address the server sees, assuming the nat keeps the port
+                const candidates = [...message.candidates];
+                for (const candidate of message.candidates) {
+                    const port = candidate.split(':').at(-1);
+                    const publicCandidate = `${relay.client.address}:${port}`;
+                    if (relay.client.address && !candidates.includes(publicCandidate)) {
+                        candidates.push(publicCandidate);
+                    }
+                }
+                relay.peer.ws.send(JSON.stringify({ type: 'p2p_candidates', candidates }));
+            } else if (message.type === 'p2p_result') {
+                const relay = tunnelPeer(ws);
+                const results = relay?.connection.p2p;
+                if (!relay || !results) return;
+                results.set(relay.client, message.success);
+                if (results.size < 2) return;
+                if ([...results.values()].every(success => success)) {
+                    relay.connection.direct = true;
+                    for (const client of [relay.connection.sender, relay.connection.receiver]) {
+                        client.ws.send(JSON.stringify({ type: 'p2p_ready' }));
+                    }
+                } else {
+                    sendRelayConnect(relay.connection);
+                }
+            } else if (message.type === 'wireguard_peer') {
+                const tunnel = tunnelPeer(ws);
+                if (tunnel?.connection.wireguardSubnet === undefined) return;
+                tunnel.peer.ws.send(
                     JSON.stringify({
-                        type: 'response',
-                        success: false,
-                        error: JSON.stringify(err.errors)
+                        type: 'wireguard_peer',
+                        public_key: message.public_key,
+                        port: message.port,
+                        endpoint: tunnel.client.address ? `${tunnel.client.address}:${message.port}` : null
                     })
                 );
+            }
+        } catch (err) {
+            if (err instanceof ZodError) {
+                replyError('invalid_message', JSON.stringify(err.errors));
             } else {
                 console.log((err as Error).stack);
-                ws.send(
-                    JSON.stringify({
-                        type: 'response',
-                        success: false,
-                        error: (err as Error).message
-                    })
-                );
+                replyError('internal_error', (err as Error).message);
             }
         }
     });
     ws.on('close', () => {
+        clearInterval(watchdog);
+        if (address) {
+            const sessions = (sessionsPerAddress.get(address) ?? 1) - 1;
+            if (sessions > 0) sessionsPerAddress.set(address, sessions);
+            else sessionsPerAddress.delete(address);
+        }
+        subscriptions.forEach(target => unsubscribePresence(target, ws));
         let clientIndex = clients.findIndex(c => c.ws === ws);
         if (clientIndex !== -1) {
             const [client] = clients.splice(clientIndex, 1);
             // console.log(`socket ${clients[clientIndex]!.uuid} disconnected`);
-            const connectionIndex = connections.findIndex(c => c.sender === client || c.receiver === client);
-            if (connectionIndex !== -1) {
-                const [connection] = connections.splice(connectionIndex, 1);
-                // TODO: close connection
-                if (connection!.sender !== client) {
-                    connection!.sender.ws.send(
-                        JSON.stringify({
-                            type: 'tunnel_close'
-                        })
-                    );
-                }
-                if (connection!.receiver !== client) {
-                    connection!.receiver.ws.send(
-                        JSON.stringify({
-                            type: 'tunnel_close'
-                        })
-                    );
-                }
+            for (const connection of connections.filter(c => c.sender === client || c.receiver === client)) {
+                closeConnection(connection, 'client_disconnected', client);
+            }
+            if (client && isHost(client)) notifyPresence(client.uuid, false);
+        }
+    });
+}
+
+// closes the tunnels the host on this socket has with the receiver, returns how many were closed
+function kickReceiver(ws: ClientSocket, receiver: string, reason: string) {
+    const kicked = connections.filter(c => c.sender.ws === ws && c.receiver.uuid === receiver);
+    for (const connection of kicked) {
+        console.log(`closing tunnel ${connection.sender.uuid} -> ${connection.receiver.uuid}: ${reason}`);
+        closeConnection(connection, reason);
+    }
+    return kicked.length;
+}
+
+function isHost(client: { client_type: RegisterType }) {
+    return client.client_type !== 'receiver';
+}
+
+function onlineHost(uuid: string) {
+    return clients.find(c => c.uuid === uuid && isHost(c));
+}
+
+function notifyPresence(uuid: string, online: boolean) {
+    const label = online ? onlineHost(uuid)?.label : undefined;
+    for (const subscriber of presenceSubscribers.get(uuid) ?? []) {
+        subscriber.send(JSON.stringify({ type: 'host_presence', uuid, online, label }));
+    }
+}
 
-                connection!.sshd.kill();
+function unsubscribePresence(target: string, ws: ClientSocket) {
+    const subscribers = presenceSubscribers.get(target);
+    subscribers?.delete(ws);
+    if (subscribers?.size === 0) presenceSubscribers.delete(target);
+}
+
+// the hosts a connect target matches, "team/label" finds a host of the team by the label of its session
+function findHosts(target: string) {
+    const [team, label] = target.includes('/') ? target.split('/', 2) : [];
+    return clients.filter(c => {
+        if (!isHost(c)) return false;
+        if (team !== undefined) return c.team === team && c.label === label;
+        return c.uuid.startsWith(target);
+    });
+}
+
+// the reason the sender doesn't share this port, if any
+function portRefusal(sender: Client, port: number) {
+    // the host told which list wins, decided like it does
+    if (sender.port_precedence === 'blacklist' && sender.port_blacklist.includes(port)) {
+        return `the port "${port}" is in the client's blacklist`;
+    }
+    if (sender.port_whitelist.length > 0 || sender.whitelist_only) {
+        // there is a whitelist
+        if (!sender.port_whitelist.includes(port)) return `the port "${port}" isn't in the client's whitelist`;
+    } else if (sender.port_blacklist.length > 0) {
+        //there is a blacklist
+        if (sender.port_blacklist.includes(port)) return `the port "${port}" is in the client's blacklist`;
+    }
+    return undefined;
+}
+
+// whether the sender already has as many receivers as it takes
+function isFull(sender: Client) {
+    if (sender.max_connections === undefined) return false;
+    return connections.filter(c => c.sender === sender).length >= sender.max_connections;
+}
+
+// sends a connect_confirm to the sender and waits for its answer
+function askHost(sender: Client, receiver: Client, port: number) {
+    return new Promise<'accepted' | 'denied' | 'timeout'>(resolve => {
+        const done = (answer: 'accepted' | 'denied' | 'timeout') => {
+            sender.ws.removeListener('message', listener);
+            clearTimeout(expiry);
+            resolve(answer);
+        };
+        const listener = (data: ws.RawData, isBinary: boolean) => {
+            if (isBinary) return;
+            // anything else the host sends is handled, or refused, by its own message listener
+            let raw: unknown;
+            try {
+                raw = JSON.parse(data.toString());
+            } catch {
+                return;
+            }
+            const message = messagesSchema.safeParse(raw).data;
+            if (message?.type === 'connect_accept') {
+                done('accepted');
+            } else if (message?.type === 'connect_deny') {
+                done('denied');
             }
+        };
+        const expiry = setTimeout(() => done('timeout'), CONNECT_CONFIRM_TIMEOUT * 1000);
+        sender.ws.on('message', listener);
+        sender.ws.send(
+            JSON.stringify({
+                type: 'connect_confirm',
+                source_client: receiver.uuid,
+                source_account: receiver.account ?? null,
+                port
+            })
+        );
+    });
+}
+
+// forwards another port of the sender through the ssh connections the clients already have,
+// returns an error code when there is no port left for it
+function addForward(connection: Connection, port: number): ErrorCode | undefined {
+    const group = [connection, ...sharedTunnels(connection)];
+    const forwards = group.flatMap(c => [{ port: c.port, localPort: c.localPort }, ...c.extraForwards]);
+    // another receiver of the group may already have the sender forward this port
+    let localPort = forwards.find(forward => forward.port === port)?.localPort;
+    if (!localPort) {
+        if (connection.localPorts) {
+            localPort = connection.localPorts.find(p => !forwards.some(forward => forward.localPort === p));
+            if (!localPort) return 'server_full';
+        } else {
+            const used = usedLocalPorts();
+            localPort = EMBEDDED_LOCAL_PORT_START;
+            while (used.includes(localPort)) localPort++;
+            addLocalPort(connection.user, localPort);
         }
+        sendPortAdded(connection, 'sender', port, localPort);
+    }
+    connection.extraForwards.push({ port, localPort });
+    sendPortAdded(connection, 'receiver', port, localPort);
+    return undefined;
+}
+
+function sendPortAdded(connection: Connection, clientType: ClientType, port: number, localPort: number) {
+    const client = clientType === 'sender' ? connection.sender : connection.receiver;
+    client.ws.send(
+        JSON.stringify({
+            type: 'port_added',
+            client_type: clientType,
+            local_port: localPort, // forwarded through the ssh connection the client opened on tunnel_connect
+            forwarded_port: port
+        })
+    );
+}
+
+function sendTunnelConnect(connection: Connection, clientType: ClientType) {
+    const client = clientType === 'sender' ? connection.sender : connection.receiver;
+    client.ws.send(
+        JSON.stringify({
+            type: 'tunnel_connect',
+            client_type: clientType,
+            user: connection.user,
+            sshd_port: connection.sshdPort, // ssh port
+            local_port: connection.localPort, // port that is used to forward between the 2 clients
+            forwarded_port: clientType === 'sender' ? connection.port : 0 // ignored for receiver
+        })
+    );
+}
+
+// whether nothing listens on this port yet
+function portFree(port: number) {
+    return new Promise<boolean>(resolve => {
+        const server = net.createServer();
+        server.once('error', () => resolve(false));
+        server.listen(port, () => server.close(() => resolve(true)));
     });
-});
+}
 
-async function wait(delay: number) {
-    return new Promise(resolve => setTimeout(resolve, delay));
+function usedLocalPorts() {
+    return connections.flatMap(con => [
+        con.localPort,
+        ...(con.localPorts ?? []),
+        ...con.extraForwards.map(forward => forward.localPort)
+    ]);
+}
+
+// the ports the sender binds through sshd, outside of the pool and not used by another tunnel
+async function findLocalPorts(count: number, excluded: number[] = []) {
+    const used = usedLocalPorts();
+    const ports: number[] = [];
+    for (let port = Math.max(...OPENED_PORTS) + 1; port <= 65_535 && ports.length < count; port++) {
+        if (excluded.includes(port) || used.includes(port)) continue;
+        if (await portFree(port)) ports.push(port);
+    }
+    return ports.length === count ? ports : undefined;
+}
+
+// starts sshd on a free port of the pool, moving on to the next one when it dies right away (port taken by another process, ...)
+async function startSshd(
+    sender: Client,
+    receiver: Client
+): Promise<
+    Required<Pick<Connection, 'sshd' | 'user' | 'authorizedKeyFile' | 'sshdPort' | 'localPort' | 'localPorts'>> | ErrorCode
+> {
+    const tried: number[] = [];
+    while (true) {
+        let sshdPort: number | undefined;
+        for (const port of OPENED_PORTS) {
+            if (tried.includes(port) || startingSshdPorts.has(port) || connections.some(con => con.sshdPort === port)) {
+                continue;
+            }
+            tried.push(port);
+            if (await portFree(port)) {
+                sshdPort = port;
+                break;
+            }
+        }
+        const localPorts = await findLocalPorts(1 + TUNNEL_EXTRA_PORTS);
+        if (!sshdPort || !localPorts) {
+            // no port available
+            return 'server_full';
+        }
+
+        // not a connection yet, the cleanup must leave its user and files alone
+        startingSshdPorts.add(sshdPort);
+        try {
+            const user = TUNNEL_USERS === 'per-tunnel' ? createTunnelUser(sshdPort) : FORWARDING_USER!;
+            if (!user) return 'tunnel_failed';
+            const authorizedKeyFile = path.join(AUTHORIZED_KEYS_FOLDER, `authorized_keys_${sshdPort}`);
+            writeAuthorizedKeys(authorizedKeyFile, sender, [receiver], { sshdPort, localPorts });
+            const sshd = spawnSshd(sshdPort, localPorts, user, authorizedKeyFile);
+            const tunnel = { sshd, user, authorizedKeyFile, sshdPort, localPort: localPorts[0]!, localPorts };
+            await wait(1000);
+            if (sshd.exitCode === null && sshd.signalCode === null) return tunnel;
+            console.log(`sshd failed to start on port ${sshdPort}, trying another one`);
+            cleanupCredentials(tunnel);
+        } finally {
+            startingSshdPorts.delete(sshdPort);
+        }
+    }
+}
+
+// the sender's ssh couldn't bind the forwarding port (taken by another process since it was picked),
+// restarts sshd with another one and sends the new port to every client of the tunnel
+async function moveForward(connection: Connection) {
+    const group = [connection, ...sharedTunnels(connection)];
+    connection.forwardRetries = (connection.forwardRetries ?? 0) + 1;
+    const localPorts =
+        connection.forwardRetries <= MAX_FORWARD_RETRIES
+            ? await findLocalPorts(1 + TUNNEL_EXTRA_PORTS, connection.localPorts)
+            : undefined;
+    if (!localPorts) {
+        console.log(`closing tunnel ${connection.sender.uuid} -> ${connection.receiver.uuid}: forwarding failed`);
+        group.forEach(c => closeConnection(c, 'forward_failed'));
+        return;
+    }
+
+    const oldSshd = connection.sshd!;
+    oldSshd.removeAllListeners('exit');
+    if (oldSshd.exitCode === null && oldSshd.signalCode === null) {
+        // sshd has to release its port before the new one can listen on it
+        const exited = new Promise(resolve => oldSshd.once('exit', resolve));
+        killProcessTree(oldSshd);
+        await exited;
+    }
+    const receivers = group.map(c => c.receiver);
+    writeAuthorizedKeys(connection.authorizedKeyFile!, connection.sender, receivers, { ...connection, localPorts });
+    const sshd = spawnSshd(connection.sshdPort, localPorts, connection.user, connection.authorizedKeyFile!);
+    sshd.on('exit', () => cleanupCredentials(connection));
+    for (const c of group) {
+        c.sshd = sshd;
+        c.localPort = localPorts[0]!;
+        c.localPorts = localPorts;
+        // the clients start over with a new ssh connection, ports added to the old one are gone
+        c.extraForwards = [];
+    }
+    await wait(1000);
+    for (const c of group) {
+        sendTunnelConnect(c, 'receiver');
+    }
+    sendTunnelConnect(connection, 'sender');
+}
+
+// the sender's ssh couldn't bind the port an added forward got, gives the forward another port of the
+// tunnel, or refuses it to the receivers which asked for it when none is left
+function moveExtraForward(connection: Connection, port: number) {
+    const group = [connection, ...sharedTunnels(connection)];
+    const holders = group.filter(c => c.extraForwards.some(forward => forward.port === port));
+    const failed = holders[0]?.extraForwards.find(forward => forward.port === port)?.localPort;
+    if (failed === undefined) return;
+    // whatever holds the port on the server will not release it for this tunnel
+    for (const c of group) c.localPorts = c.localPorts?.filter(p => p !== failed);
+    const forwards = group.flatMap(c => [{ port: c.port, localPort: c.localPort }, ...c.extraForwards]);
+    connection.forwardRetries = (connection.forwardRetries ?? 0) + 1;
+    const localPort =
+        connection.forwardRetries <= MAX_FORWARD_RETRIES
+            ? connection.localPorts?.find(p => !forwards.some(forward => forward.localPort === p))
+            : undefined;
+    for (const c of holders) {
+        const forward = c.extraForwards.find(forward => forward.port === port)!;
+        if (localPort) {
+            forward.localPort = localPort;
+            continue;
+        }
+        c.extraForwards = c.extraForwards.filter(f => f !== forward);
+        c.receiver.ws.send(
+            JSON.stringify({
+                type: 'port_refused',
+                port,
+                code: 'tunnel_failed',
+                error: 'The host could not forward this port through the server'
+            })
+        );
+    }
+    if (!localPort) {
+        console.log(`port ${port} of tunnel ${connection.sender.uuid}: forwarding failed`);
+        return;
+    }
+    sendPortAdded(connection, 'sender', port, localPort);
+    holders.forEach(c => sendPortAdded(c, 'receiver', port, localPort));
+}
+
+function spawnSshd(
+    sshdPort: number,
+    localPorts: number[],
+    user: string,
+    authorizedKeyFile: string
+) {
+    const sshdArgs: string[] = [
+        '-f',
+        '/dev/null',
+        '-o',
+        `AllowUsers=${user}`,
+        '-o',
+        'PasswordAuthentication=no',
+        '-o',
+        'PubkeyAuthentication=yes',
+        '-o',
+        'AllowTcpForwarding=yes',
+        '-o',
+        'PermitTunnel=no',
+        '-o',
+        'PermitRootLogin=no',
+        '-o',
+        'X11Forwarding=no',
+        '-o',
+        'PermitUserEnvironment=no',
+        '-o',
+        'AllowAgentForwarding=no',
+        '-o',
+        `Port=${sshdPort}`,
+        '-o',
+        `PermitOpen=${localPorts.map(port => `localhost:${port}`).join(' ')}`,
+        '-o',
+        `PermitListen=${localPorts.join(' ')}`,
+        '-o',
+        'PermitTTY=no',
+        '-o',
+        'PermitUserRC=no',
+        '-o',
+        'AllowStreamLocalForwarding=no',
+        '-o',
+        'GatewayPorts=no',
+        ...(TUNNEL_CHROOT ? ['-o', `ChrootDirectory=${TUNNEL_CHROOT}`] : []),
+        // '-o',
+        // `AuthorizedKeysCommandUser=${FORWARDING_USER}`,
+        '-o',
+        `AuthorizedKeysFile=${authorizedKeyFile}`,
+        '-o',
+        `HostKey=${KEYS[0]}`,
+        '-o',
+        `HostKey=${KEYS[1]}`,
+        '-o',
+        `HostKey=${KEYS[2]}`,
+        '-D',
+        // logs to stderr, where the logins are read from
+        '-e'
+    ];
+
+    const sshd = spawn(SSHD!, sshdArgs, {});
+    trackLogins(sshd);
+    return sshd;
+}
+
+// sshd reads the file on every login, so receivers can be added and removed while it runs
+// each key only gets the forwards of its side of the tunnel: the sender listens on the tunnel's ports,
+// the receivers open them. authorized_keys can restrict a direction but not forbid it, so the other one
+// is restricted to the sshd's own port, which sshd's PermitOpen and PermitListen never allow
+function writeAuthorizedKeys(
+    authorizedKeyFile: string,
+    sender: Client,
+    receivers: Client[],
+    tunnel: Pick<Connection, 'sshdPort' | 'localPorts'>
+) {
+    // restrict turns off the pty, agent and x11 forwarding and whatever future versions of ssh add
+    const authorizedKeyArgs = `restrict,port-forwarding,command="echo 'This account is restricted to port forwarding'"`;
+    const ports = tunnel.localPorts ?? [];
+    const listen = ports.map(port => `permitlisten="${port}"`);
+    const open = ports.map(port => `permitopen="localhost:${port}"`);
+    const senderArgs = [authorizedKeyArgs, ...listen, `permitopen="localhost:${tunnel.sshdPort}"`].join(',');
+    const receiverArgs = [authorizedKeyArgs, ...open, `permitlisten="${tunnel.sshdPort}"`].join(',');
+    const lines = [authorizedKey(sender, senderArgs, receivers)];
+    for (const receiver of receivers) {
+        // the receiver's key is only accepted from the address which passed the allowlist
+        const from = receiverRestricted(sender) && receiver.address ? `from="${receiver.address}",` : '';
+        lines.push(from + authorizedKey(receiver, receiverArgs, [sender]));
+    }
+    const sshKeys = lines.join('\n');
+
+    // fs.writeFileSync(authorizedKeyFile, `#!/bin/sh\n/bin/echo "${sshKeys}"`);
+    fs.writeFileSync(authorizedKeyFile, `${sshKeys}`);
+    // fs.chmodSync(authorizedKeyFile, 700);
+}
+
+// the line of a client's key. with a certificate, its authority is trusted for the certificate's principal
+// instead, so sshd checks the certificate's expiry and restrictions on every login. sshd logs in with the first
+// line matching, a principal on both sides of the tunnel would give one of them the other's forwards:
+// the key is trusted as it is then, and ssh falls back on it
+function authorizedKey(client: Client, args: string, otherSide: Client[]) {
+    const certificate = client.certificate;
+    const principal = certificate?.principal;
+    if (
+        !certificate ||
+        !principal ||
+        !/^[\w.@-]+$/.test(principal) ||
+        otherSide.some(other => other.certificate?.ca === certificate.ca && other.certificate.principal === principal)
+    ) {
+        return `${args} ${client.ssh_key}`;
+    }
+    return `cert-authority,principals="${principal}",${args} ${certificate.ca}`;
+}
+
+function isSshTunnel(connection: Connection) {
+    return !connection.relay && connection.wireguardSubnet === undefined;
+}
+
+// the other receivers using the same ssh forward of the sender
+function sharedTunnels(connection: Connection) {
+    if (!isSshTunnel(connection)) return [];
+    return connections.filter(
+        c => c !== connection && c.sender === connection.sender && c.port === connection.port && isSshTunnel(c)
+    );
+}
+
+function receiverRestricted(sender: Client) {
+    return RECEIVER_ALLOWLIST.length > 0 || (sender.source_allowlist?.length ?? 0) > 0;
+}
+
+// whether a receiver connecting from this address may open a tunnel to the sender
+function receiverAllowed(sender: Client, address?: string) {
+    if (!receiverRestricted(sender)) return true;
+    if (!address) return false;
+    if (RECEIVER_ALLOWLIST.length > 0 && !addressAllowed(address, RECEIVER_ALLOWLIST)) return false;
+    const allowlist = sender.source_allowlist ?? [];
+    return allowlist.length === 0 || addressAllowed(address, allowlist);
+}
+
+// removes the connection and tells both clients (except the one which is already gone) to close their tunnel
+function closeConnection(connection: Connection, reason: string, gone?: Client) {
+    const index = connections.indexOf(connection);
+    if (index === -1) return;
+    connections.splice(index, 1);
+    emitEvent('tunnel_close', {
+        id: connection.id,
+        sender: connection.sender.uuid,
+        receiver: connection.receiver.uuid,
+        reason,
+        traffic: connection.traffic
+    });
+    // measured before the tunnel is closed, for the summary the clients print
+    const usage = receiverUsage(connection);
+    // the sender keeps its forward while other receivers use it, it is only told this receiver left
+    const shared = sharedTunnels(connection).length > 0;
+    if (shared && connection.sender !== gone && supports(connection.sender.capabilities, 'receivers')) {
+        connection.sender.ws.send(JSON.stringify({ type: 'receiver_left', reason, ...usage }));
+    }
+    for (const client of [connection.sender, connection.receiver]) {
+        if (client !== gone && !(shared && client === connection.sender)) {
+            client.ws.send(
+                JSON.stringify({
+                    type: 'tunnel_close',
+                    client_type: client === connection.sender ? 'sender' : 'receiver', // the side closed, for dual clients
+                    reason,
+                    ...usage
+                })
+            );
+        }
+    }
+    closeTunnel(connection);
+}
+
+function tunnelTransport(connection: Connection) {
+    if (connection.wireguardSubnet !== undefined) return 'wireguard';
+    if (connection.p2p) return connection.direct ? 'p2p' : 'p2p_relay';
+    if (connection.relay) return 'relay';
+    return 'ssh';
+}
+
+// finds the tunnel the websocket belongs to, and the client on the other side of it,
+// a client hosting and connecting at once can be in tunnels on both sides so the side can be given
+function tunnelPeer(ws: ClientSocket, side?: ClientType) {
+    const connection = connections.find(
+        c => (side !== 'receiver' && c.sender.ws === ws) || (side !== 'sender' && c.receiver.ws === ws)
+    );
+    if (!connection) return undefined;
+    const isSender = connection.sender.ws === ws;
+    return {
+        connection,
+        client: isSender ? connection.sender : connection.receiver,
+        peer: isSender ? connection.receiver : connection.sender
+    };
+}
+
+// the two addresses of a /30 inside 10.77.0.0/16
+function wireguardAddresses(subnet: number) {
+    const base = subnet * 4;
+    const address = (offset: number) => `10.77.${(base + offset) >> 8}.${(base + offset) & 255}`;
+    return [address(1), address(2)] as const;
 }
 
-function wsSendResponse(ws: ws.WebSocket, success: boolean, error?: string) {
-    ws.send(
+function sendRelayConnect(connection: Connection) {
+    connection.receiver.ws.send(
         JSON.stringify({
-            type: 'response',
-            success,
-            error
+            type: 'relay_connect',
+            client_type: 'receiver',
+            forwarded_port: 0 // ignored for receiver
         })
     );
+    connection.sender.ws.send(
+        JSON.stringify({
+            type: 'relay_connect',
+            client_type: 'sender',
+            forwarded_port: connection.port
+        })
+    );
+}
+
+function relayData(ws: ClientSocket, data: Buffer) {
+    const relay = tunnelPeer(ws);
+    if (!relay?.connection.relay) return;
+    relay.connection.relayedBytes += data.length;
+    relay.peer.ws.send(data, { binary: true });
+}
+
+function closeTunnel(connection: Connection) {
+    if (connection.relay) return;
+    const shared = sharedTunnels(connection);
+    if (shared.length > 0) {
+        if (connection.authorizedKeyFile) {
+            const receivers = shared.map(c => c.receiver);
+            writeAuthorizedKeys(connection.authorizedKeyFile, connection.sender, receivers, connection);
+            // the new file only refuses new logins, the one the receiver has goes with its forwards.
+            // not when another client of the sshd logs in with the same key
+            const key = connection.receiver.ssh_key.trim();
+            if (connection.sshd && ![connection.sender, ...receivers].some(c => c.ssh_key.trim() === key)) {
+                endSessions(connection.sshd, key);
+            }
+        } else if (!shared.some(c => c.receiver.ssh_key === connection.receiver.ssh_key)) {
+            removeReceiver(connection.user, connection.receiver.ssh_key);
+        }
+        return;
+    }
+    if (connection.sshd) {
+        // cleaned up now: once sshd exited, its port and thus its user may already belong to another tunnel
+        connection.sshd.removeAllListeners('exit');
+        killProcessTree(connection.sshd);
+        cleanupCredentials(connection);
+    } else {
+        removeTunnel(connection.user);
+    }
+}
+
+// accounts the traffic of every tunnel, and closes the ones which are idle or over quota
+function monitorTunnels() {
+    const now = Date.now();
+    const chargedSshds = new Set<ChildProcess>();
+    for (const connection of [...connections]) {
+        if (TUNNEL_MAX_LIFETIME > 0) {
+            const remaining = connection.openedAt + TUNNEL_MAX_LIFETIME * 1000 - now;
+            if (remaining <= 0) {
+                console.log(`closing tunnel ${connection.sender.uuid} -> ${connection.receiver.uuid}: max lifetime`);
+                closeConnection(connection, 'max_lifetime');
+                continue;
+            }
+            if (!connection.expiryWarned && remaining <= TUNNEL_LIFETIME_WARNING * 1000) {
+                connection.expiryWarned = true;
+                // a shared forward outlives this receiver, only the receiver needs to know
+                const warned =
+                    sharedTunnels(connection).length > 0 ? [connection.receiver] : [connection.sender, connection.receiver];
+                for (const client of warned) {
+                    client.ws.send(
+                        JSON.stringify({ type: 'tunnel_expiry_warning', seconds_left: Math.ceil(remaining / 1000) })
+                    );
+                }
+            }
+        }
+        if (connection.direct) continue;
+        const traffic = measureTraffic(connection);
+        // the counter of the sshd tree can also go down when a child exits, any change counts as activity
+        if (traffic !== connection.traffic) {
+            const delta = Math.max(0, traffic - connection.traffic);
+            // a shared sshd can't tell its receivers apart, each of them sees the traffic of all,
+            // but the sender must only be charged once
+            if (!connection.sshd || !chargedSshds.has(connection.sshd)) {
+                recordUsage(connection.sender, delta);
+                if (connection.sshd) chargedSshds.add(connection.sshd);
+            }
+            recordUsage(connection.receiver, delta);
+            connection.traffic = traffic;
+            connection.lastActivity = now;
+        }
+
+        const overQuota = [connection.sender, connection.receiver].filter(client => isOverQuota(client));
+        if (overQuota.length > 0) {
+            for (const client of overQuota) {
+                client.ws.send(JSON.stringify(quotaStatus(client)));
+                emitEvent('abuse', { uuid: client.uuid, reason: 'quota_exceeded' });
+            }
+            console.log(`closing tunnel ${connection.sender.uuid} -> ${connection.receiver.uuid}: quota exceeded`);
+            closeConnection(connection, 'quota_exceeded');
+        } else if (TUNNEL_IDLE_TIMEOUT > 0 && now - connection.lastActivity > TUNNEL_IDLE_TIMEOUT * 1000) {
+            console.log(`closing tunnel ${connection.sender.uuid} -> ${connection.receiver.uuid}: idle`);
+            closeConnection(connection, 'idle');
+        }
+    }
+    saveUsage();
+}
+
+// bytes the tunnel carried so far, only meaningful when it goes through the server
+function measureTraffic(connection: Connection) {
+    // sshd reads then writes every forwarded byte, so its counters see everything twice
+    return connection.relay
+        ? connection.relayedBytes
+        : connection.sshd
+          ? Math.floor(processTreeIO(connection.sshd.pid) / 2)
+          : getTunnelTraffic(connection.user, connection.receiver.ssh_key);
+}
+
+// what the host is told about a receiver's use of its tunnel
+function receiverUsage(connection: Connection) {
+    const reported = connection.stats.get(connection.receiver);
+    return {
+        receiver: connection.receiver.uuid,
+        port: connection.port,
+        connected_for: Math.floor((Date.now() - connection.openedAt) / 1000),
+        // a shared sshd can't tell its receivers apart, direct tunnels are only known from the receiver's reports
+        traffic: connection.direct
+            ? (reported?.bytes_sent ?? 0) + (reported?.bytes_received ?? 0) || null
+            : measureTraffic(connection),
+        shared: sharedTunnels(connection).length > 0
+    };
+}
+
+// bytes read and written by a process and all of its descendants
+function processTreeIO(pid?: number): number {
+    if (pid === undefined) return 0;
+    try {
+        const io = fs.readFileSync(`/proc/${pid}/io`).toString();
+        let total = 0;
+        for (const line of io.split('\n')) {
+            const [key, value] = line.split(':');
+            if (key === 'rchar' || key === 'wchar') total += parseInt(value!);
+        }
+        for (const child of childrenOf(pid)) {
+            total += processTreeIO(child);
+        }
+        return total;
+    } catch {
+        return 0;
+    }
+}
+
+function childrenOf(pid: number) {
+    try {
+        const children = fs.readFileSync(`/proc/${pid}/task/${pid}/children`).toString().trim();
+        return children
+            .split(' ')
+            .filter(e => e)
+            .map(child => parseInt(child));
+    } catch {
+        return [];
+    }
+}
+
+function descendantsOf(pid: number): number[] {
+    return childrenOf(pid).flatMap(child => [child, ...descendantsOf(child)]);
+}
+
+// kills the sessions of the logins of a key to the sshd, with the tree under each
+function endSessions(sshd: ChildProcess, sshKey: string) {
+    const sockets = loginSockets(sshd, sshKey);
+    if (sshd.pid === undefined || sockets.size === 0) return;
+    for (const session of childrenOf(sshd.pid)) {
+        if ([session, ...descendantsOf(session)].some(pid => holdsSocket(pid, sockets))) {
+            killProcessTree(session);
+        }
+    }
+}
+
+// sshd forks a process for each login, which keeps the session and its forwards going when only the listening
+// sshd is killed. the tree is listed first, a process whose parent died is no longer found under it.
+// false when the process was already gone
+function killProcessTree(target: ChildProcess | number) {
+    const pid = typeof target === 'number' ? target : target.pid;
+    if (pid === undefined) return false;
+    const descendants = descendantsOf(pid);
+    let killed: boolean;
+    try {
+        // through the ChildProcess when there is one, which then knows it was killed
+        killed = typeof target === 'number' ? process.kill(target) : target.kill();
+    } catch {
+        killed = false;
+    }
+    for (const descendant of descendants) {
+        try {
+            process.kill(descendant);
+        } catch {}
+    }
+    return killed;
+}
+
+function createTunnelUser(sshdPort: number) {
+    const user = TUNNEL_USER_PREFIX + sshdPort;
+    // a previous server could have crashed before removing it
+    spawnSync('userdel', [user]);
+    return addTunnelUser(user) ? user : undefined;
+}
+
+// a system user without a shell, a home or a password, which can only log in with the keys sshd is given
+function addTunnelUser(user: string) {
+    const result = spawnSync('useradd', [
+        '--system',
+        '--no-create-home',
+        '--home-dir',
+        '/nonexistent',
+        '--shell',
+        '/usr/sbin/nologin',
+        // sshd refuses accounts with a locked password ("!"), even for public key authentication
+        '--password',
+        '*',
+        user
+    ]);
+    if (result.status !== 0) {
+        console.error(`failed to create tunnel user "${user}": ${result.stderr.toString().trim()}`);
+        return false;
+    }
+    return true;
+}
+
+// sshd only chroots in folders which root owns and nobody else can write to
+function prepareChroot(folder: string) {
+    fs.mkdirSync(folder, { recursive: true, mode: 0o755 });
+    const stat = fs.statSync(folder);
+    if (stat.uid !== 0 || (stat.mode & 0o022) !== 0) {
+        console.error(
+            `${folder} must be owned by root and only writable by it to lock the tunnel users in, see TUNNEL_CHROOT`
+        );
+        process.exit(1);
+    }
+    if (fs.readdirSync(folder).length > 0) {
+        console.warn(`${folder} is not empty, the tunnel users can see what it holds`);
+    }
+}
+
+// can be called several times for the same connection
+function cleanupCredentials(connection: Pick<Connection, 'user' | 'authorizedKeyFile'>) {
+    if (connection.authorizedKeyFile) {
+        fs.rmSync(connection.authorizedKeyFile, { force: true });
+    }
+    if (connection.user.startsWith(TUNNEL_USER_PREFIX)) {
+        // its sessions were just sent SIGTERM, they may not have exited yet
+        spawnSync('userdel', ['--force', connection.user]);
+    }
+}
+
+// removes the users, authorized_keys files and sshd instances no tunnel owns, left behind by a previous run
+// of the server or by a tunnel which crashed half way, and returns what it removed
+function cleanupStaleCredentials(): CleanupReport {
+    const report: CleanupReport = { authorized_keys: [], users: [], sshd: [], embedded_tunnels: [] };
+    if (SSH_BACKEND !== 'sshd') {
+        const users = new Set(connections.map(c => c.user));
+        for (const user of embeddedTunnelUsers().filter(user => !users.has(user))) {
+            removeTunnel(user);
+            report.embedded_tunnels.push(user);
+        }
+        return report;
+    }
+    const livePorts = new Set([...connections.map(c => c.sshdPort), ...startingSshdPorts]);
+    const owned = (name: string, prefix: string) => livePorts.has(parseInt(name.slice(prefix.length)));
+
+    for (const file of fs.readdirSync(AUTHORIZED_KEYS_FOLDER)) {
+        if (file.startsWith('authorized_keys_') && !owned(file, 'authorized_keys_')) {
+            fs.rmSync(path.join(AUTHORIZED_KEYS_FOLDER, file), { force: true });
+            report.authorized_keys.push(file);
+        }
+    }
+    const passwd = spawnSync('getent', ['passwd']).stdout?.toString() ?? '';
+    for (const line of passwd.split('\n')) {
+        const user = line.split(':')[0];
+        if (user?.startsWith(TUNNEL_USER_PREFIX) && !owned(user, TUNNEL_USER_PREFIX)) {
+            spawnSync('userdel', [user]);
+            report.users.push(user);
+        }
+    }
+    // the instances this server spawns are the ones reading their keys from its folder
+    const livePids = new Set(connections.map(c => c.sshd?.pid));
+    for (const { pid, port } of sshdProcesses()) {
+        if (livePids.has(pid) || startingSshdPorts.has(port)) continue;
+        if (killProcessTree(pid)) report.sshd.push(pid);
+    }
+    return report;
+}
+
+// the sshd instances serving the authorized_keys files of this server, none without /proc.
+// the processes they fork for each login are left out, killProcessTree ends them with their instance
+function sshdProcesses() {
+    const marker = `AuthorizedKeysFile=${path.join(AUTHORIZED_KEYS_FOLDER, 'authorized_keys_')}`;
+    const found: { pid: number; port: number; parent: number }[] = [];
+    let entries: string[];
+    try {
+        entries = fs.readdirSync('/proc').filter(entry => /^\d+$/.test(entry));
+    } catch {
+        return found;
+    }
+    for (const entry of entries) {
+        let args: string[];
+        let stat: string;
+        try {
+            args = fs.readFileSync(`/proc/${entry}/cmdline`, 'utf8').split('\0');
+            stat = fs.readFileSync(`/proc/${entry}/stat`, 'utf8');
+        } catch {
+            continue; // exited since
+        }
+        if (!args.some(arg => arg.startsWith(marker))) continue;
+        const port = args.find(arg => arg.startsWith('Port='))?.slice('Port='.length);
+        // pid (command) state ppid ..., the command can hold spaces and parentheses
+        const parent = parseInt(stat.slice(stat.lastIndexOf(')') + 2).split(' ')[1] ?? '0');
+        found.push({ pid: parseInt(entry), port: parseInt(port ?? '0'), parent });
+    }
+    const pids = new Set(found.map(p => p.pid));
+    return found.filter(p => !pids.has(p.parent));
+}
+
+function readNotice() {
+    try {
+        return fs.readFileSync(NOTICE_FILE).toString().trim();
+    } catch {
+        return '';
+    }
+}
+
+function sendNotice(ws: ClientSocket, level: NoticeLevel, message: string) {
+    ws.send(JSON.stringify({ type: 'server_notice', level, message }));
+}
+
+function broadcastNotice(level: NoticeLevel, message: string) {
+    for (const client of clients) {
+        sendNotice(client.ws, level, message);
+    }
+}
+
+async function wait(delay: number) {
+    return new Promise(resolve => setTimeout(resolve, delay));
 }
@@ -0,0 +1,95 @@
+import { ChildProcess } from 'child_process';
+import { createHash } from 'crypto';
+import fs from 'fs';
+import { createInterface } from 'readline';
+import { addressAllowed } from './allowlist';
+
+// a login sshd reported: the key's fingerprint and where the client connected from
+interface Login {
+    fingerprint: string;
+    address: string;
+    port: number;
+}
+
+// the latest logins of each sshd, enough for the clients sharing one
+const MAX_LOGINS = 256;
+const logins = new WeakMap<ChildProcess, Login[]>();
+
+// reads the logins an sshd started with -e writes to stderr, like
+// "Accepted publickey for kpf-40001 from 203.0.113.7 port 51234 ssh2: ED25519 SHA256:..."
+// the fingerprint is the one of the key, also for a certificate (ED25519-CERT SHA256:... ID ...)
+export function trackLogins(sshd: ChildProcess) {
+    const list: Login[] = [];
+    logins.set(sshd, list);
+    if (!sshd.stderr) return;
+    createInterface({ input: sshd.stderr }).on('line', line => {
+        const match = line.match(/Accepted publickey for \S+ from (\S+) port (\d+) ssh2: \S+ (SHA256:\S+)/);
+        if (!match) return;
+        list.push({ address: match[1]!, port: parseInt(match[2]!), fingerprint: match[3]! });
+        list.splice(0, list.length - MAX_LOGINS);
+    });
+}
+
+// as ssh-keygen -l prints it
+export function fingerprint(sshKey: string) {
+    const blob = Buffer.from(sshKey.trim().split(/\s+/)[1] ?? '', 'base64');
+    return `SHA256:${createHash('sha256').update(blob).digest('base64').replace(/=+$/, '')}`;
+}
+
+// the inodes of the connections the logins of the key came in on, none without /proc
+export function loginSockets(sshd: ChildProcess, sshKey: string) {
+    const keyFingerprint = fingerprint(sshKey);
+    const keyLogins = (logins.get(sshd) ?? []).filter(login => login.fingerprint === keyFingerprint);
+    const sockets = new Set<string>();
+    if (keyLogins.length === 0) return sockets;
+    for (const file of ['/proc/net/tcp', '/proc/net/tcp6']) {
+        let table: string;
+        try {
+            table = fs.readFileSync(file).toString();
+        } catch {
+            continue;
+        }
+        // sl local_address rem_address st ... uid timeout inode
+        for (const line of table.split('\n').slice(1)) {
+            const fields = line.trim().split(/\s+/);
+            const remote = fields[2] && decodeEndpoint(fields[2]);
+            if (!remote || !fields[9]) continue;
+            const loggedIn = keyLogins.some(
+                login => login.port === remote.port && addressAllowed(remote.address, [login.address])
+            );
+            if (loggedIn) sockets.add(fields[9]);
+        }
+    }
+    return sockets;
+}
+
+// whether one of the files the process has open is one of the sockets
+export function holdsSocket(pid: number, sockets: Set<string>) {
+    try {
+        return fs.readdirSync(`/proc/${pid}/fd`).some(fd => {
+            try {
+                const inode = fs.readlinkSync(`/proc/${pid}/fd/${fd}`).match(/^socket:\[(\d+)\]$/)?.[1];
+                return inode !== undefined && sockets.has(inode);
+            } catch {
+                return false;
+            }
+        });
+    } catch {
+        return false;
+    }
+}
+
+// "0100007F:C350" is 127.0.0.1:50000, the address is in 32 bit words of the host's byte order
+function decodeEndpoint(endpoint: string) {
+    const [hex, port] = endpoint.split(':');
+    if (!hex || !port || (hex.length !== 8 && hex.length !== 32)) return undefined;
+    const bytes = Buffer.from(hex, 'hex');
+    for (let word = 0; word < bytes.length; word += 4) {
+        bytes.writeUInt32BE(bytes.readUInt32LE(word), word);
+    }
+    const address =
+        bytes.length === 4
+            ? [...bytes].join('.')
+            : Array.from({ length: 8 }, (_, group) => bytes.readUInt16BE(group * 2).toString(16)).join(':');
+    return { address, port: parseInt(port, 16) };
+}
@@ -0,0 +1,117 @@
+import { EventEmitter } from 'events';
+import type { IncomingMessage, ServerResponse } from 'http';
+import { randomUUID } from 'crypto';
+import type ws from 'ws';
+
+// comments sent on idle sessions, so proxies don't close the stream
+const KEEPALIVE_INTERVAL = 15 * 1000;
+// a message, or a frame of a relayed tunnel
+const MAX_MESSAGE_SIZE = 1024 * 1024;
+
+// what the server uses of a client's socket, a websocket or a session of the fallback below
+export interface ClientSocket {
+    send(data: string | Buffer, options?: { binary?: boolean; compress?: boolean }): void;
+    close(code?: number, reason?: string): void;
+    terminate(): void;
+    on(event: 'message', listener: (data: ws.RawData, isBinary: boolean) => void): this;
+    on(event: 'close', listener: () => void): this;
+    removeListener(event: 'message', listener: (data: ws.RawData, isBinary: boolean) => void): this;
+}
+
+// the fallback of clients behind proxies which kill websockets: the server's messages come as
+// server-sent events of a GET /sse, the client's as one POST /sse/<session> each
+export class SseSocket extends EventEmitter implements ClientSocket {
+    readonly id = randomUUID();
+    private closed = false;
+    private keepalive: NodeJS.Timeout;
+
+    constructor(private res: ServerResponse) {
+        super();
+        res.writeHead(200, {
+            'Content-Type': 'text/event-stream',
+            'Cache-Control': 'no-cache, no-transform',
+            'X-Accel-Buffering': 'no' // nginx would hold the events back otherwise
+        });
+        this.event('session', this.id);
+        this.keepalive = setInterval(() => res.write(': keepalive\n\n'), KEEPALIVE_INTERVAL);
+        res.on('close', () => this.finish());
+    }
+
+    // text is json, which is on one line, binary frames go in base64
+    send(data: string | Buffer) {
+        if (typeof data === 'string') this.event('message', data);
+        else this.event('binary', data.toString('base64'));
+    }
+
+    close(code = 1000, reason = '') {
+        this.event('close', JSON.stringify({ code, reason }));
+        this.res.end();
+        this.finish();
+    }
+
+    terminate() {
+        this.res.destroy();
+        this.finish();
+    }
+
+    private event(name: string, data: string) {
+        if (!this.closed) this.res.write(`event: ${name}\ndata: ${data}\n\n`);
+    }
+
+    private finish() {
+        if (this.closed) return;
+        this.closed = true;
+        clearInterval(this.keepalive);
+        sessions.delete(this.id);
+        this.emit('close');
+    }
+}
+
+const sessions = new Map<string, SseSocket>();
+
+export function sseSessions() {
+    return [...sessions.values()];
+}
+
+// GET /sse opens a session and hands it to onConnection like a new websocket,
+// returns false for the requests which are not for the fallback
+export function sseHandler(onConnection: (socket: SseSocket, req: IncomingMessage) => void) {
+    return (req: IncomingMessage, res: ServerResponse) => {
+        const url = new URL(req.url ?? '/', 'http://localhost');
+        if (req.method === 'GET' && url.pathname === '/sse') {
+            const socket = new SseSocket(res);
+            sessions.set(socket.id, socket);
+            onConnection(socket, req);
+            return true;
+        }
+        const post = url.pathname.match(/^\/sse\/([^/]+)$/);
+        if (req.method !== 'POST' || !post) return false;
+
+        const socket = sessions.get(post[1]!);
+        if (!socket) {
+            res.writeHead(404, { 'Content-Type': 'application/json' });
+            res.end(JSON.stringify({ error: 'no such session' }));
+            return true;
+        }
+        const chunks: Buffer[] = [];
+        let size = 0;
+        req.on('data', (chunk: Buffer) => {
+            size += chunk.length;
+            if (size > MAX_MESSAGE_SIZE) {
+                res.writeHead(413);
+                res.end();
+                req.destroy();
+                return;
+            }
+            chunks.push(chunk);
+        });
+        req.on('end', () => {
+            if (size > MAX_MESSAGE_SIZE) return;
+            // answered first, the client waits for it before sending the next message
+            res.writeHead(204);
+            res.end();
+            socket.emit('message', Buffer.concat(chunks), req.headers['content-type'] === 'application/octet-stream');
+        });
+        return true;
+    };
+}
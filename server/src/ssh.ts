@@ -0,0 +1,177 @@
+import { Server, utils, type Connection as SSHClient, type ParsedKey } from 'ssh2';
+import { timingSafeEqual } from 'crypto';
+import fs from 'fs';
+import { pipeline } from 'stream';
+
+// a tunnel as seen by the embedded ssh server, the user is what the clients log in as
+export interface EmbeddedTunnel {
+    user: string;
+    senderKey: string;
+    localPort: number; // virtual port the sender binds (-R) and the receivers open (-L)
+}
+
+// several receivers can share the forward of a sender
+export interface EmbeddedReceiver {
+    key: string;
+    allowed?: (address: string) => boolean; // checked against the address the receiver's ssh connection comes from
+}
+
+interface TunnelState extends EmbeddedTunnel {
+    sender?: SSHClient;
+    localPorts: Set<number>; // localPort and the ports added to the tunnel afterwards
+    bindAddrs: Map<number, string>; // ports the sender forwards, with the address it bound them on
+    receivers: Map<string, EmbeddedReceiver>;
+    clients: Map<SSHClient, string | undefined>; // key each client logged in with, undefined for the sender
+    traffic: Map<string, number>; // bytes relayed in both directions, by receiver key
+}
+
+const tunnels = new Map<string, TunnelState>();
+
+// writes a host key as ssh-keygen would, the embedded backend does not need openssh installed
+export function generateHostKey(file: string, type: 'rsa' | 'ecdsa' | 'ed25519', bits?: number) {
+    const key = utils.generateKeyPairSync(type, { bits });
+    fs.writeFileSync(file, key.private, { mode: 0o600 });
+    fs.writeFileSync(`${file}.pub`, `${key.public}\n`);
+}
+
+export function startEmbeddedSSH(port: number, hostKeys: string[]) {
+    const server = new Server({ hostKeys: hostKeys.map(key => fs.readFileSync(key)) }, (client, info) => {
+        let tunnel: TunnelState | undefined;
+        let authenticated = false;
+        let receiverKey: string | undefined;
+
+        client.on('authentication', ctx => {
+            tunnel = tunnels.get(ctx.username);
+            if (!tunnel || ctx.method !== 'publickey') {
+                return ctx.reject(['publickey']);
+            }
+            const address = info.ip.replace(/^::ffff:/, '');
+            const candidates: [string, string | undefined][] = [[tunnel.senderKey, undefined]];
+            for (const receiver of tunnel.receivers.values()) {
+                if (!receiver.allowed || receiver.allowed(address)) {
+                    candidates.push([receiver.key, receiver.key]);
+                }
+            }
+            for (const [key, candidateReceiver] of candidates) {
+                const parsed = parseKey(key);
+                if (!parsed || !keyMatches(parsed, ctx.key.data)) continue;
+                // without a signature the client is only asking whether the key would be accepted
+                if (ctx.signature && ctx.blob && parsed.verify(ctx.blob, ctx.signature, ctx.hashAlgo) !== true) {
+                    continue;
+                }
+                authenticated = true;
+                receiverKey = candidateReceiver;
+                return ctx.accept();
+            }
+            ctx.reject(['publickey']);
+        });
+
+        client.on('ready', () => {
+            if (!tunnel || !authenticated) {
+                client.end();
+                return;
+            }
+            const state = tunnel;
+            state.clients.set(client, receiverKey);
+
+            client.on('request', (accept, reject, name, info) => {
+                if (name === 'tcpip-forward' && !receiverKey && state.localPorts.has(info.bindPort)) {
+                    if (state.sender !== client) state.bindAddrs.clear();
+                    state.sender = client;
+                    state.bindAddrs.set(info.bindPort, info.bindAddr);
+                    accept?.();
+                } else if (name === 'cancel-tcpip-forward' && state.sender === client) {
+                    state.bindAddrs.delete(info.bindPort);
+                    if (state.bindAddrs.size === 0) state.sender = undefined;
+                    accept?.();
+                } else {
+                    reject?.();
+                }
+            });
+
+            client.on('tcpip', (accept, reject, info) => {
+                const sender = state.sender;
+                const bindAddr = state.bindAddrs.get(info.destPort);
+                if (!receiverKey || bindAddr === undefined || !sender) {
+                    return reject();
+                }
+                const key = receiverKey;
+                const count = (chunk: Buffer) => state.traffic.set(key, (state.traffic.get(key) ?? 0) + chunk.length);
+                sender.forwardOut(bindAddr, info.destPort, info.srcIP, info.srcPort, (err, upstream) => {
+                    if (err) return reject();
+                    const channel = accept();
+                    channel.on('data', count);
+                    upstream.on('data', count);
+                    // an error or close on either side ends both, instead of leaving a half open channel
+                    pipeline(channel, upstream, channel, () => {});
+                });
+            });
+
+            client.on('session', (_accept, reject) => reject());
+        });
+
+        client.on('close', () => tunnel?.clients.delete(client));
+        client.on('error', () => {});
+    });
+
+    server.listen(port, () => console.log(`Embedded ssh server started on port ${port}`));
+    return server;
+}
+
+export function addTunnel(tunnel: EmbeddedTunnel, receiver: EmbeddedReceiver) {
+    tunnels.set(tunnel.user, {
+        ...tunnel,
+        localPorts: new Set([tunnel.localPort]),
+        bindAddrs: new Map(),
+        receivers: new Map([[receiver.key, receiver]]),
+        clients: new Map(),
+        traffic: new Map()
+    });
+}
+
+// lets the sender forward another port through the tunnel
+export function addLocalPort(user: string, port: number) {
+    tunnels.get(user)?.localPorts.add(port);
+}
+
+export function addReceiver(user: string, receiver: EmbeddedReceiver) {
+    tunnels.get(user)?.receivers.set(receiver.key, receiver);
+}
+
+// disconnects the clients which logged in with this key, the sender's forward stays up
+export function removeReceiver(user: string, key: string) {
+    const tunnel = tunnels.get(user);
+    if (!tunnel) return;
+    tunnel.receivers.delete(key);
+    for (const [client, clientKey] of tunnel.clients) {
+        if (clientKey === key) client.end();
+    }
+}
+
+export function removeTunnel(user: string) {
+    const tunnel = tunnels.get(user);
+    if (!tunnel) return;
+    tunnels.delete(user);
+    for (const client of tunnel.clients.keys()) {
+        client.end();
+    }
+}
+
+export function embeddedTunnelUsers() {
+    return [...tunnels.keys()];
+}
+
+export function getTunnelTraffic(user: string, receiverKey: string) {
+    return tunnels.get(user)?.traffic.get(receiverKey) ?? 0;
+}
+
+function parseKey(key: string): ParsedKey | undefined {
+    const parsed = utils.parseKey(key);
+    if (parsed instanceof Error) return undefined;
+    return Array.isArray(parsed) ? parsed[0] : parsed;
+}
+
+function keyMatches(key: ParsedKey, data: Buffer) {
+    const expected = key.getPublicSSH();
+    return expected.length === data.length && timingSafeEqual(expected, data);
+}
@@ -0,0 +1,24 @@
+import fs from 'fs';
+import { TeamRole, teamsSchema, Team } from './schema';
+
+let teams: Record<string, Team> = {};
+
+export function loadTeams(teamsFile: string) {
+    teams = teamsSchema.parse(JSON.parse(fs.readFileSync(teamsFile).toString()));
+}
+
+// undefined when the account isn't a member of the team, or the client didn't log in
+export function roleOf(team: string, account: string | undefined): TeamRole | undefined {
+    // own properties only, an account named like constructor would otherwise get a member of Object.prototype
+    if (account === undefined || !Object.hasOwn(teams, team)) return undefined;
+    const members = teams[team]!.members;
+    return Object.hasOwn(members, account) ? members[account] : undefined;
+}
+
+export function canHost(team: string, account: string | undefined) {
+    return roleOf(team, account) === 'host';
+}
+
+export function isMember(team: string, account: string | undefined) {
+    return roleOf(team, account) !== undefined;
+}
@@ -0,0 +1,18 @@
+// urls receiving a json POST for every server event
+const WEBHOOK_URLS = (process.env.WEBHOOK_URLS ?? '')
+    .split(',')
+    .map(e => e.trim())
+    .filter(e => e);
+
+export type ServerEvent = 'register' | 'tunnel_open' | 'tunnel_close' | 'abuse';
+
+export function emitEvent(event: ServerEvent, data: Record<string, unknown>) {
+    const body = JSON.stringify({ event, timestamp: new Date().toISOString(), ...data });
+    for (const url of WEBHOOK_URLS) {
+        fetch(url, { method: 'POST', headers: { 'Content-Type': 'application/json' }, body })
+            .then(res => {
+                if (!res.ok) console.log(`webhook ${url} answered ${res.status}`);
+            })
+            .catch(err => console.log(`webhook ${url} failed: ${(err as Error).message}`));
+    }
+}